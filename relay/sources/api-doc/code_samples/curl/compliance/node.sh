@@ -0,0 +1 @@
+curl http://localhost:3030/rudder/relay-api/1/compliance/nodes/4ac35ef0-582d-468d-8c95-cd3f2ee333f9
\ No newline at end of file
@@ -0,0 +1 @@
+curl --request POST http://localhost:3030/rudder/relay-api/1/failed/reports/replay --data 'files=2020-02-18T11:26:02+00:00@4ac35ef0-582d-468d-8c95-cd3f2ee333f9.log.gz'
\ No newline at end of file
@@ -14,11 +14,16 @@ use flate2::read::GzDecoder;
 use openssl::{stack::Stack, x509::X509};
 
 use rudder_relayd::{
-    configuration::{main::DatabaseConfig, Secret},
-    data::{node::NodesList, report::QueryableReport, RunInfo, RunLog},
+    configuration::{
+        main::{DatabaseConfig, RunlogDeduplication},
+        Secret,
+    },
+    data::{node::NodesList, report::QueryableReport, runlog::RunlogDigest, RunInfo, RunLog},
     input::signature,
     output::database::{
-        schema::{reportsexecution::dsl::*, ruddersysevents::dsl::*},
+        schema::{
+            reportsexecution::dsl::*, ruddersysevents::dsl::*, runlogdigests::dsl::runlogdigests,
+        },
         *,
     },
 };
@@ -100,6 +105,8 @@ pub fn db() -> PgPool {
         url: "postgres://rudderreports@127.0.0.1/rudder".to_string(),
         password: Secret::new("PASSWORD".to_string()),
         max_pool_size: 10,
+        deduplication: RunlogDeduplication::Digest,
+        purge: Default::default(),
     };
     pg_pool(&db_config).unwrap()
 }
@@ -110,23 +117,31 @@ fn bench_insert_runlog(c: &mut Criterion) {
 
     diesel::delete(reportsexecution).execute(db).unwrap();
     diesel::delete(ruddersysevents).execute(db).unwrap();
+    diesel::delete(runlogdigests).execute(db).unwrap();
     let results = ruddersysevents
         .limit(1)
         .load::<QueryableReport>(db)
         .unwrap();
     assert_eq!(results.len(), 0);
 
-    let runlog = RunLog::new(
-        "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
-    )
-    .unwrap();
+    let path =
+        "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+    let runlog = RunLog::new(path).unwrap();
+    let runlog_digest = RunlogDigest::new(&runlog.info, &read_to_string(path).unwrap());
 
     // Test inserting the runlog
 
     c.bench_function("insert runlog", move |b| {
         b.iter(|| {
             assert_eq!(
-                insert_runlog(&pool, &runlog).unwrap(),
+                insert_runlog(
+                    &pool,
+                    &runlog,
+                    &runlog_digest,
+                    RunlogDeduplication::Digest,
+                    true
+                )
+                .unwrap(),
                 RunlogInsertion::Inserted
             );
         })
@@ -9,6 +9,8 @@ use warp::{http::StatusCode, path, reject, reject::Reject, reply, Filter, Reject
 
 use crate::JobConfig;
 
+mod compliance;
+mod failed;
 mod metrics;
 mod remote_run;
 mod shared_files;
@@ -99,11 +101,13 @@ pub async fn run(job_config: Arc<JobConfig>) -> Result<(), ()> {
             system::routes_1(job_config.clone())
                 .or(shared_folder::routes_1(job_config.clone()))
                 .or(shared_files::routes_1(job_config.clone()))
-                .or(remote_run::routes_1(job_config.clone())),
+                .or(remote_run::routes_1(job_config.clone()))
+                .or(failed::routes_1(job_config.clone()))
+                .or(compliance::routes_1(job_config.clone())),
             /* special case for /metrics which is the standard URL
              * with no versioning */
         )
-        .or(metrics::routes());
+        .or(metrics::routes(job_config.clone()));
 
     let routes = routes_1
         .recover(customize_error)
@@ -118,7 +122,13 @@ pub async fn run(job_config: Arc<JobConfig>) -> Result<(), ()> {
     })?;
     // Use first resolved address for now
     let socket = addresses.next().unwrap();
-    warp::serve(routes).bind(socket).await;
+    // Stop accepting connections on shutdown, but let ongoing requests
+    // (including remote-run output streams) finish
+    let mut shutdown = job_config.shutdown_signal();
+    let (_, server) = warp::serve(routes)
+        .bind_with_graceful_shutdown(socket, async move { shutdown.requested().await });
+    server.await;
+    info!("API stopped");
     Ok(())
 }
 
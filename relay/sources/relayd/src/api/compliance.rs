@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use warp::{
+    filters::{method, BoxedFilter},
+    http::StatusCode,
+    path, Filter, Reply,
+};
+
+use crate::{api::ApiResponse, data::compliance::ComplianceSummary, JobConfig};
+
+pub fn routes_1(job_config: Arc<JobConfig>) -> BoxedFilter<(impl Reply,)> {
+    let base = path!("compliance" / ..);
+
+    let job_config_nodes = job_config.clone();
+    let nodes = method::get()
+        .and(base)
+        .and(path!("nodes"))
+        .map(move || job_config_nodes.clone())
+        .and_then(handlers::nodes);
+
+    let job_config_node = job_config;
+    let node = method::get()
+        .map(move || job_config_node.clone())
+        .and(base)
+        .and(path!("nodes" / String))
+        .and_then(|j, node_id| handlers::node(node_id, j));
+
+    nodes.or(node).boxed()
+}
+
+pub mod handlers {
+    use warp::{Rejection, Reply};
+
+    use super::*;
+
+    pub async fn nodes(job_config: Arc<JobConfig>) -> Result<impl Reply, Rejection> {
+        Ok(ApiResponse::new::<Error>(
+            "getNodesCompliance",
+            Ok(Some(job_config.compliance.list().await)),
+            None,
+        )
+        .reply())
+    }
+
+    pub async fn node(
+        node_id: String,
+        job_config: Arc<JobConfig>,
+    ) -> Result<impl Reply, Rejection> {
+        Ok(match job_config.compliance.get(&node_id).await {
+            Some(summary) => {
+                ApiResponse::new::<Error>("getNodeCompliance", Ok(Some(summary)), None).reply()
+            }
+            None => ApiResponse::<ComplianceSummary>::new(
+                "getNodeCompliance",
+                Err(anyhow!("no run received from node: {}", node_id)),
+                Some(StatusCode::NOT_FOUND),
+            )
+            .reply(),
+        })
+    }
+}
@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::{collections::HashMap, path::PathBuf, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, Error};
+use warp::{
+    body,
+    filters::{method, BoxedFilter},
+    http::StatusCode,
+    path, Filter, Reply,
+};
+
+use crate::{
+    api::{ApiResponse, RudderReject},
+    processing::failed::{list, replay},
+    JobConfig,
+};
+
+pub fn routes_1(job_config: Arc<JobConfig>) -> BoxedFilter<(impl Reply,)> {
+    let job_config_list = job_config.clone();
+    let list = method::get()
+        .map(move || job_config_list.clone())
+        .and(path!("failed" / FailedKind))
+        .and_then(|j, kind| handlers::list(kind, j));
+
+    let job_config_replay = job_config;
+    let replay = method::post()
+        .map(move || job_config_replay.clone())
+        .and(path!("failed" / FailedKind / "replay"))
+        .and(body::form())
+        .and_then(|j, kind, params| handlers::replay(kind, params, j));
+
+    list.or(replay).boxed()
+}
+
+/// Processing queue the failed files come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailedKind {
+    Reports,
+    Inventories,
+}
+
+impl FromStr for FailedKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reports" => Ok(FailedKind::Reports),
+            "inventories" => Ok(FailedKind::Inventories),
+            _ => Err(anyhow!("unknown failed files kind: {}", s)),
+        }
+    }
+}
+
+impl FailedKind {
+    fn directory(self, job_config: &JobConfig) -> PathBuf {
+        match self {
+            FailedKind::Reports => job_config.cfg.processing.reporting.directory.clone(),
+            FailedKind::Inventories => job_config.cfg.processing.inventory.directory.clone(),
+        }
+    }
+}
+
+pub mod handlers {
+    use warp::{reject, Rejection, Reply};
+
+    use super::*;
+
+    pub async fn list(
+        kind: FailedKind,
+        job_config: Arc<JobConfig>,
+    ) -> Result<impl Reply, Rejection> {
+        Ok(ApiResponse::new::<Error>(
+            "listFailedFiles",
+            super::list(&kind.directory(&job_config)).await.map(Some),
+            None,
+        )
+        .reply())
+    }
+
+    pub async fn replay(
+        kind: FailedKind,
+        params: HashMap<String, String>,
+        job_config: Arc<JobConfig>,
+    ) -> Result<impl Reply, Rejection> {
+        let files: Vec<String> = match params.get("files") {
+            Some(files) => files.split(',').map(|s| s.to_string()).collect(),
+            None => return Err(reject::custom(RudderReject::new("Missing files"))),
+        };
+        let directory = kind.directory(&job_config);
+
+        // Check all files before replaying any of them
+        let known = match super::list(&directory).await {
+            Ok(known) => known,
+            Err(e) => {
+                return Ok(
+                    ApiResponse::<Vec<String>>::new("replayFailedFiles", Err(e), None).reply(),
+                )
+            }
+        };
+        if let Some(unknown) = files.iter().find(|f| !known.iter().any(|k| &&k.file == f)) {
+            return Ok(ApiResponse::<Vec<String>>::new(
+                "replayFailedFiles",
+                Err(anyhow!("unknown failed file: {}", unknown)),
+                Some(StatusCode::NOT_FOUND),
+            )
+            .reply());
+        }
+
+        let mut replayed = Vec::with_capacity(files.len());
+        for file in files {
+            if let Err(e) = super::replay(&directory, &file).await {
+                return Ok(ApiResponse::<Vec<String>>::new(
+                    "replayFailedFiles",
+                    Err(anyhow!("could not replay {}: {}", file, e)),
+                    None,
+                )
+                .reply());
+            }
+            replayed.push(file);
+        }
+        Ok(ApiResponse::new::<Error>("replayFailedFiles", Ok(Some(replayed)), None).reply())
+    }
+}
@@ -1,16 +1,19 @@
 // SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use crate::metrics::REGISTRY;
+use std::sync::Arc;
+
+use crate::{metrics::REGISTRY, JobConfig};
 use warp::{
     filters::{method, BoxedFilter},
     path, Filter, Reply,
 };
 
 /// Special case for /metrics, standard for prometheus
-pub fn routes() -> BoxedFilter<(impl Reply,)> {
+pub fn routes(job_config: Arc<JobConfig>) -> BoxedFilter<(impl Reply,)> {
     method::get()
         .and(path!("metrics"))
+        .map(move || job_config.clone())
         .and_then(handlers::metrics)
         .boxed()
 }
@@ -21,7 +24,11 @@ pub mod handlers {
     use prometheus::proto::MetricFamily;
     use warp::{reject, Rejection, Reply};
 
-    pub async fn metrics() -> Result<impl Reply, Rejection> {
+    pub async fn metrics(job_config: Arc<JobConfig>) -> Result<impl Reply, Rejection> {
+        if job_config.cfg.processing.reporting.compliance {
+            job_config.compliance.update_metrics().await;
+        }
+
         use prometheus::Encoder;
         let encoder = prometheus::TextEncoder::new();
         let mut buffer = Vec::new();
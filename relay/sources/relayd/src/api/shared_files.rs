@@ -159,20 +159,21 @@ async fn put_forward(
     job_config: Arc<JobConfig>,
     body: Bytes,
 ) -> Result<StatusCode, Error> {
-    let client = job_config.upstream_client.read().await.inner().clone();
-    client
-        .put(&format!(
-            "{}/{}/{}",
-            job_config.cfg.upstream_url(),
-            "relay-api/shared-files",
-            file.url(),
-        ))
-        .query(&params)
-        .body(body)
-        .send()
+    job_config
+        .upstreams
+        .send(|client, url| {
+            Ok(client
+                .put(&format!(
+                    "{}/{}/{}",
+                    url,
+                    "relay-api/shared-files",
+                    file.url()
+                ))
+                .query(&params)
+                .body(body.clone()))
+        })
         .await
         .map(|r| r.status())
-        .map_err(|e| e.into())
 }
 
 pub async fn put_local(
@@ -289,20 +290,20 @@ async fn head_forward(
     params: SharedFilesHeadParams,
     job_config: Arc<JobConfig>,
 ) -> Result<StatusCode, Error> {
-    let client = job_config.upstream_client.read().await.inner().clone();
-
-    client
-        .head(&format!(
-            "{}/{}/{}",
-            job_config.cfg.upstream_url(),
-            "relay-api/shared-files",
-            file.url(),
-        ))
-        .query(&params)
-        .send()
+    job_config
+        .upstreams
+        .send(|client, url| {
+            Ok(client
+                .head(&format!(
+                    "{}/{}/{}",
+                    url,
+                    "relay-api/shared-files",
+                    file.url()
+                ))
+                .query(&params))
+        })
         .await
         .map(|r| r.status())
-        .map_err(|e| e.into())
 }
 
 pub async fn head_local(
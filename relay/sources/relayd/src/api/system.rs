@@ -4,11 +4,10 @@
 use crate::{
     api::{ApiResponse, ApiResult},
     configuration::check_configuration,
-    output::database::ping,
     Error, JobConfig, CRATE_VERSION,
 };
 use serde::Serialize;
-use std::sync::Arc;
+use std::{convert::Infallible, sync::Arc};
 use warp::{
     filters::{method, BoxedFilter},
     path, Filter, Reply,
@@ -18,7 +17,9 @@ pub fn routes_1(job_config: Arc<JobConfig>) -> BoxedFilter<(impl Reply,)> {
     let base = path!("system" / ..);
 
     let info = method::get().and(base).and(path!("info")).map(|| {
-        Ok(ApiResponse::new::<Error>("getSystemInfo", Ok(Some(Info::new())), None).reply())
+        Ok::<_, Infallible>(
+            ApiResponse::new::<Error>("getSystemInfo", Ok(Some(Info::new())), None).reply(),
+        )
     });
 
     let job_config_reload = job_config.clone();
@@ -30,12 +31,14 @@ pub fn routes_1(job_config: Arc<JobConfig>) -> BoxedFilter<(impl Reply,)> {
 
     let job_config_status = job_config;
     let status = method::get().and(base).and(path!("status")).map(move || {
-        Ok(ApiResponse::new::<Error>(
-            "getStatus",
-            Ok(Some(Status::poll(job_config_status.clone()))),
-            None,
+        Ok::<_, Infallible>(
+            ApiResponse::new::<Error>(
+                "getStatus",
+                Ok(Some(Status::poll(job_config_status.clone()))),
+                None,
+            )
+            .reply(),
         )
-        .reply())
     });
 
     info.or(reload).or(status).boxed()
@@ -108,7 +111,10 @@ struct Status {
 impl Status {
     pub fn poll(job_config: Arc<JobConfig>) -> Self {
         Self {
-            database: job_config.pool.clone().map(|p| ping(&p).into()),
+            database: job_config
+                .pool
+                .as_ref()
+                .map(|p| p.ping().and_then(|_| job_config.schema_health()).into()),
             configuration: check_configuration(&job_config.cli_cfg.config)
                 .map(|_| ())
                 .into(),
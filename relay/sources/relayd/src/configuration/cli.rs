@@ -1,8 +1,12 @@
 // SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
+use anyhow::{anyhow, Error};
 use gumdrop::Options;
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 #[derive(Debug, Options)]
 // version and description are taken from Cargo.toml
@@ -20,6 +24,107 @@ pub struct CliConfiguration {
     help: bool,
     #[options(help = "print version", short = "V")]
     pub version: bool,
+    #[options(command)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Options)]
+pub enum Command {
+    #[options(help = "copy an inventory export directory into the inventory queues")]
+    ImportInventories(ImportInventoriesOptions),
+    #[options(help = "manage the reporting database schema")]
+    Db(DbOptions),
+    #[options(help = "explain why parts of a runlog file could not be parsed")]
+    Explain(ExplainOptions),
+    #[options(help = "check a received runlog file step by step and display its reports")]
+    Inspect(InspectOptions),
+}
+
+#[derive(Debug, Options)]
+pub struct ImportInventoriesOptions {
+    #[options(free, required, help = "export directory, containing a manifest")]
+    pub directory: PathBuf,
+    #[options(help = "print help message")]
+    help: bool,
+}
+
+#[derive(Debug, Options)]
+pub struct ExplainOptions {
+    #[options(
+        free,
+        required,
+        help = "unsigned runlog, possibly compressed, named like received runlogs"
+    )]
+    pub file: PathBuf,
+    #[options(help = "print help message")]
+    help: bool,
+}
+
+#[derive(Debug, Options)]
+pub struct InspectOptions {
+    #[options(
+        free,
+        required,
+        help = "received runlog, signed and possibly compressed"
+    )]
+    pub file: PathBuf,
+    #[options(
+        help = "nodes list file",
+        default = "/var/rudder/lib/relay/nodeslist.json"
+    )]
+    pub nodes_list: PathBuf,
+    #[options(
+        help = "nodes certificates file",
+        default = "/var/rudder/lib/ssl/allnodescerts.pem"
+    )]
+    pub certificates: PathBuf,
+    #[options(help = "reports output format: reports or json", default = "reports")]
+    pub format: InspectFormat,
+    #[options(help = "print help message")]
+    help: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InspectFormat {
+    /// Agent output format
+    Reports,
+    Json,
+}
+
+impl FromStr for InspectFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reports" => Ok(Self::Reports),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("unknown format '{}', expected reports or json", s)),
+        }
+    }
+}
+
+#[derive(Debug, Options)]
+pub struct DbOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(command, required)]
+    pub command: Option<DbCommand>,
+}
+
+#[derive(Debug, Options)]
+pub enum DbCommand {
+    #[options(help = "create missing tables and indexes, and record applied migrations")]
+    Init(DbCommandOptions),
+    #[options(help = "compare the database schema with the expected one")]
+    Check(DbCommandOptions),
+    #[options(help = "apply pending migrations to an initialized database")]
+    Migrate(DbCommandOptions),
+}
+
+#[derive(Debug, Options)]
+pub struct DbCommandOptions {
+    #[options(help = "print help message")]
+    help: bool,
 }
 
 impl CliConfiguration {
@@ -30,6 +135,7 @@ impl CliConfiguration {
             test,
             help: false,
             version: false,
+            command: None,
         }
     }
 }
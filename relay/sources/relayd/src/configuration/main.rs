@@ -2,7 +2,7 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     convert::TryFrom,
     fmt,
     fs::read_to_string,
@@ -12,6 +12,7 @@ use std::{
 };
 
 use anyhow::{anyhow, Context, Error};
+use regex::Regex;
 use serde::{
     de::{Deserializer, Error as SerdeError, Unexpected, Visitor},
     Deserialize,
@@ -59,6 +60,42 @@ where
     d.deserialize_str(V)
 }
 
+/// Regular expression, compiled when loading the configuration
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn regex(&self) -> &Regex {
+        &self.0
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(Regex::new(s)?))
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(SerdeError::custom)
+    }
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 // Default can be implemented in serde using the Default trait
 pub struct Configuration {
@@ -172,6 +209,21 @@ impl Configuration {
             ),
         }
     }
+
+    /// Upstream urls in order of preference, with their certificate file
+    pub fn upstream_servers(&self) -> Vec<(String, Option<&Path>)> {
+        let mut servers = vec![(
+            self.upstream_url(),
+            Some(self.output.upstream.server_certificate_file.as_path()),
+        )];
+        servers.extend(self.output.upstream.failover.iter().map(|f| {
+            (
+                format!("https://{}:{}/", f.host, self.general.https_port),
+                f.server_certificate_file.as_deref(),
+            )
+        }));
+        servers
+    }
 }
 
 impl FromStr for Configuration {
@@ -216,6 +268,11 @@ pub struct GeneralConfig {
     /// Which certificate validation model to use
     #[serde(default = "GeneralConfig::default_peer_authentication")]
     peer_authentication: PeerAuthentication,
+    /// Maximum time to wait for in-flight processing and API requests
+    /// before exiting on SIGTERM/SIGINT
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "GeneralConfig::default_shutdown_timeout")]
+    pub shutdown_timeout: Duration,
 }
 
 impl GeneralConfig {
@@ -248,6 +305,10 @@ impl GeneralConfig {
         // For compatibility
         PeerAuthentication::SystemRootCerts
     }
+
+    fn default_shutdown_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
 }
 
 impl Default for GeneralConfig {
@@ -263,6 +324,7 @@ impl Default for GeneralConfig {
             https_port: Self::default_https_port(),
             https_idle_timeout: Self::default_https_idle_timeout(),
             peer_authentication: Self::default_peer_authentication(),
+            shutdown_timeout: Self::default_shutdown_timeout(),
         }
     }
 }
@@ -334,6 +396,114 @@ impl Default for CleanupConfig {
     }
 }
 
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct RetryConfig {
+    /// Delay before the first new attempt, doubled after each failure
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "RetryConfig::default_initial_delay")]
+    pub initial_delay: Duration,
+    /// Upper bound for the delay between two attempts
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "RetryConfig::default_max_delay")]
+    pub max_delay: Duration,
+    /// Number of failed attempts after which the file is moved to `failed/`
+    #[serde(default = "RetryConfig::default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+impl RetryConfig {
+    /// 30 seconds
+    fn default_initial_delay() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    /// 1 hour
+    fn default_max_delay() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn default_max_attempts() -> u32 {
+        20
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: Self::default_initial_delay(),
+            max_delay: Self::default_max_delay(),
+            max_attempts: Self::default_max_attempts(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct BatchConfig {
+    /// Maximum number of runlogs inserted or sent together,
+    /// 1 means each runlog is processed separately
+    #[serde(default = "BatchConfig::default_size")]
+    pub size: usize,
+    /// Maximum time a runlog can wait for the batch to be full
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "BatchConfig::default_delay")]
+    pub delay: Duration,
+}
+
+impl BatchConfig {
+    fn default_size() -> usize {
+        1
+    }
+
+    /// 1 second
+    fn default_delay() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.size > 1
+    }
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            size: Self::default_size(),
+            delay: Self::default_delay(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct OrderingConfig {
+    /// Time a runlog waits for older runlogs of the same node before being processed
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "OrderingConfig::default_delay")]
+    pub delay: Duration,
+    /// Insert runlogs older than the last processed one of their node
+    /// into `reportsexecution`, where they can be used as the latest run
+    #[serde(default = "OrderingConfig::default_record_older_executions")]
+    pub record_older_executions: bool,
+}
+
+impl OrderingConfig {
+    fn default_delay() -> Duration {
+        Duration::from_secs(0)
+    }
+
+    fn default_record_older_executions() -> bool {
+        true
+    }
+}
+
+impl Default for OrderingConfig {
+    fn default() -> Self {
+        Self {
+            delay: Self::default_delay(),
+            record_older_executions: Self::default_record_older_executions(),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone, Default)]
 pub struct ProcessingConfig {
     #[serde(default)]
@@ -352,12 +522,41 @@ pub struct InventoryConfig {
     pub catchup: CatchupConfig,
     #[serde(default)]
     pub cleanup: CleanupConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Inventories from different nodes processed concurrently, for each queue
+    #[serde(default = "InventoryConfig::default_workers")]
+    pub workers: usize,
+    #[serde(default)]
+    pub validation: InventoryValidationConfig,
+    /// Maximum time to wait for the other file of an inventory and signature pair
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "InventoryConfig::default_pairing_timeout")]
+    pub pairing_timeout: Duration,
+    /// Forward inventories without signature after the pairing timeout,
+    /// instead of moving them to failed. Signature validation still
+    /// requires a signature when enabled.
+    #[serde(default = "InventoryConfig::default_allow_unsigned")]
+    pub allow_unsigned: bool,
 }
 
 impl InventoryConfig {
     fn default_directory() -> PathBuf {
         PathBuf::from("/var/rudder/inventories/")
     }
+
+    fn default_workers() -> usize {
+        1
+    }
+
+    fn default_pairing_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    /// Agents and relays which don't send signatures keep working
+    fn default_allow_unsigned() -> bool {
+        true
+    }
 }
 
 impl Default for InventoryConfig {
@@ -367,14 +566,39 @@ impl Default for InventoryConfig {
             output: InventoryOutputSelect::default(),
             catchup: Default::default(),
             cleanup: Default::default(),
+            retry: Default::default(),
+            workers: Self::default_workers(),
+            validation: Default::default(),
+            pairing_timeout: Self::default_pairing_timeout(),
+            allow_unsigned: Self::default_allow_unsigned(),
         }
     }
 }
 
+/// Checks done on the relay before sending inventories further
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub struct InventoryValidationConfig {
+    /// Require a `.sign` file with a valid signature for each inventory, made with the
+    /// known node key for updates
+    #[serde(default)]
+    pub signature: bool,
+    /// Require a well-formed XML inventory
+    #[serde(default)]
+    pub xml: bool,
+}
+
+impl InventoryValidationConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.signature || self.xml
+    }
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum InventoryOutputSelect {
     Upstream,
+    /// Export directory with a manifest, see `output.directory`
+    Directory,
     Disabled,
 }
 
@@ -395,13 +619,36 @@ pub struct ReportingConfig {
     #[serde(default)]
     pub cleanup: CleanupConfig,
     #[serde(default)]
+    pub retry: RetryConfig,
+    /// Reports from different nodes processed concurrently
+    #[serde(default = "ReportingConfig::default_workers")]
+    pub workers: usize,
+    /// Only used with database output
+    #[serde(default)]
+    pub batch: BatchConfig,
+    #[serde(default)]
+    pub ordering: OrderingConfig,
+    #[serde(default)]
     pub skip_event_types: HashSet<String>,
+    /// Compute a summary of the results of each runlog, available
+    /// through the API and as relay-wide metrics
+    #[serde(default)]
+    pub compliance: bool,
+    /// Applied in order to parsed reports, after `skip_event_types`
+    #[serde(default)]
+    pub rules: Vec<ReportRule>,
+    #[serde(default)]
+    pub redaction: RedactionConfig,
 }
 
 impl ReportingConfig {
     fn default_directory() -> PathBuf {
         PathBuf::from("/var/rudder/reports/")
     }
+
+    fn default_workers() -> usize {
+        1
+    }
 }
 
 impl Default for ReportingConfig {
@@ -411,19 +658,151 @@ impl Default for ReportingConfig {
             output: ReportingOutputSelect::default(),
             catchup: Default::default(),
             cleanup: Default::default(),
+            retry: Default::default(),
+            workers: Self::default_workers(),
+            batch: Default::default(),
+            ordering: Default::default(),
             skip_event_types: Default::default(),
+            compliance: false,
+            rules: Default::default(),
+            redaction: Default::default(),
+        }
+    }
+}
+
+/// Removes sensitive data from report messages before any output
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct RedactionConfig {
+    /// Applied in order to the messages of parsed reports
+    #[serde(default)]
+    pub patterns: Vec<RedactionPattern>,
+    /// Forward a redacted copy of the runlog, signed with the key of this relay,
+    /// instead of the original file
+    #[serde(default)]
+    pub forward_redacted: bool,
+    /// Used to sign forwarded redacted runlogs
+    #[serde(default = "RedactionConfig::default_key_file")]
+    pub key_file: PathBuf,
+    #[serde(default = "RedactionConfig::default_certificate_file")]
+    pub certificate_file: PathBuf,
+    /// Accept runlogs signed by a relay between the node and us,
+    /// needed to receive redacted runlogs
+    #[serde(default)]
+    pub accept_relay_signatures: bool,
+}
+
+impl RedactionConfig {
+    fn default_key_file() -> PathBuf {
+        PathBuf::from("/var/rudder/cfengine-community/ppkeys/localhost.priv")
+    }
+
+    fn default_certificate_file() -> PathBuf {
+        PathBuf::from("/opt/rudder/etc/ssl/agent.cert")
+    }
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        Self {
+            patterns: Default::default(),
+            forward_redacted: false,
+            key_file: Self::default_key_file(),
+            certificate_file: Self::default_certificate_file(),
+            accept_relay_signatures: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct RedactionPattern {
+    pub regex: Pattern,
+    /// Can reference capture groups, like `$1`
+    #[serde(default = "RedactionPattern::default_replacement")]
+    pub replacement: String,
+}
+
+impl RedactionPattern {
+    fn default_replacement() -> String {
+        "[REDACTED]".to_string()
+    }
+}
+
+/// Drops or modifies the reports matching all the given criteria
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ReportRule {
+    #[serde(default)]
+    pub rule_id: Option<String>,
+    #[serde(default)]
+    pub directive_id: Option<String>,
+    #[serde(default)]
+    pub component: Option<String>,
+    #[serde(default)]
+    pub key_value: Option<String>,
+    #[serde(default)]
+    pub event_type: Option<String>,
+    /// Searched in the message
+    #[serde(default)]
+    pub msg: Option<Pattern>,
+    #[serde(flatten)]
+    pub action: ReportAction,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum ReportAction {
+    Drop,
+    /// Keep at most `max_bytes` of the message
+    Truncate {
+        max_bytes: usize,
+    },
+    /// Replace the values of the given fields
+    Rewrite {
+        set: HashMap<ReportField, String>,
+    },
+}
+
+impl ReportAction {
+    /// Used as metrics label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportAction::Drop => "drop",
+            ReportAction::Truncate { .. } => "truncate",
+            ReportAction::Rewrite { .. } => "rewrite",
         }
     }
 }
 
+#[derive(Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportField {
+    RuleId,
+    DirectiveId,
+    Component,
+    KeyValue,
+    EventType,
+    Msg,
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum ReportingOutputSelect {
     Database,
     Upstream,
+    /// JSON lines, see `output.file`
+    File,
+    /// JSON over HTTP, see `output.webhook`
+    Webhook,
+    /// Insert into the database and forward the original file upstream
+    Tee,
     Disabled,
 }
 
+impl ReportingOutputSelect {
+    pub fn uses_database(&self) -> bool {
+        matches!(self, Self::Database | Self::Tee)
+    }
+}
+
 impl Default for ReportingOutputSelect {
     fn default() -> Self {
         Self::Disabled
@@ -552,17 +931,135 @@ pub struct OutputConfig {
     pub database: DatabaseConfig,
     #[serde(default)]
     pub upstream: UpstreamConfig,
+    #[serde(default)]
+    pub file: FileOutputConfig,
+    #[serde(default)]
+    pub webhook: WebhookConfig,
+    #[serde(default)]
+    pub directory: DirectoryOutputConfig,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct DirectoryOutputConfig {
+    /// Where inventories are exported, to be imported on another server
+    #[serde(default = "DirectoryOutputConfig::default_path")]
+    pub path: PathBuf,
+}
+
+impl DirectoryOutputConfig {
+    fn default_path() -> PathBuf {
+        PathBuf::from("/var/rudder/inventories/export/")
+    }
+}
+
+impl Default for DirectoryOutputConfig {
+    fn default() -> Self {
+        Self {
+            path: Self::default_path(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct FileOutputConfig {
+    #[serde(default = "FileOutputConfig::default_directory")]
+    pub directory: PathBuf,
+    /// Rotate the file before it exceeds this size, in bytes
+    #[serde(default = "FileOutputConfig::default_max_size")]
+    pub max_size: u64,
+    /// Rotate the file when it was created longer ago than this, checked periodically
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "FileOutputConfig::default_max_age")]
+    pub max_age: Duration,
+}
+
+impl FileOutputConfig {
+    fn default_directory() -> PathBuf {
+        PathBuf::from("/var/rudder/reports/output/")
+    }
+
+    /// 100 MiB
+    fn default_max_size() -> u64 {
+        100 * 1024 * 1024
+    }
+
+    /// 1 day
+    fn default_max_age() -> Duration {
+        Duration::from_secs(24 * 3600)
+    }
+}
+
+impl Default for FileOutputConfig {
+    fn default() -> Self {
+        Self {
+            directory: Self::default_directory(),
+            max_size: Self::default_max_size(),
+            max_age: Self::default_max_age(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct WebhookConfig {
+    /// Runlogs are sent with a POST request to this URL, must use HTTPS
+    #[serde(default)]
+    pub url: String,
+    /// Sent as a bearer token in the `Authorization` header
+    #[serde(default)]
+    pub token: Option<Secret>,
+    /// Added to every request
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default = "WebhookConfig::default_peer_authentication")]
+    pub peer_authentication: PeerAuthentication,
+    /// Only used with `cert_pinning`
+    #[serde(default)]
+    pub server_certificate_file: Option<PathBuf>,
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "WebhookConfig::default_timeout")]
+    pub timeout: Duration,
+}
+
+impl WebhookConfig {
+    fn default_peer_authentication() -> PeerAuthentication {
+        PeerAuthentication::SystemRootCerts
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: Default::default(),
+            token: Default::default(),
+            headers: Default::default(),
+            peer_authentication: Self::default_peer_authentication(),
+            server_certificate_file: Default::default(),
+            timeout: Self::default_timeout(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct DatabaseConfig {
     /// URL without the password
+    ///
+    /// `sqlite://<path>` selects the embedded SQLite database
     #[serde(default = "DatabaseConfig::default_url")]
     pub url: String,
     /// When the section is there, password is mandatory
+    ///
+    /// Not used by SQLite
     pub password: Secret,
     #[serde(default = "DatabaseConfig::default_max_pool_size")]
     pub max_pool_size: u32,
+    #[serde(default)]
+    pub deduplication: RunlogDeduplication,
+    #[serde(default)]
+    pub purge: DatabasePurgeConfig,
 }
 
 impl DatabaseConfig {
@@ -581,10 +1078,66 @@ impl Default for DatabaseConfig {
             url: Self::default_url(),
             password: Default::default(),
             max_pool_size: Self::default_max_pool_size(),
+            deduplication: Default::default(),
+            purge: Default::default(),
         }
     }
 }
 
+/// Removal of old reports, for relays storing reports in their own database
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct DatabasePurgeConfig {
+    /// Requires delete privileges for the database account
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "CleanupConfig::default_cleanup_frequency")]
+    pub frequency: Duration,
+    /// Based on the run timestamp
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "CleanupConfig::default_cleanup_retention")]
+    pub retention: Duration,
+    /// Move reports to `archivedruddersysevents` instead of deleting them
+    #[serde(default)]
+    pub archive: bool,
+    /// Maximum number of rows removed in a single transaction
+    #[serde(default = "DatabasePurgeConfig::default_batch_size")]
+    pub batch_size: u32,
+}
+
+impl DatabasePurgeConfig {
+    fn default_batch_size() -> u32 {
+        10_000
+    }
+}
+
+impl Default for DatabasePurgeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            frequency: CleanupConfig::default_cleanup_frequency(),
+            retention: CleanupConfig::default_cleanup_retention(),
+            archive: false,
+            batch_size: Self::default_batch_size(),
+        }
+    }
+}
+
+/// How already inserted runlogs are detected
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RunlogDeduplication {
+    /// Look for the (node id, run timestamp, content digest) key in `runlogdigests`,
+    /// falls back to `FirstReport` until the table exists
+    #[default]
+    Digest,
+    /// Also look for the first report in `ruddersysevents`, to detect runlogs
+    /// inserted before the digests table was created. As slow as `FirstReport`.
+    DigestAndFirstReport,
+    /// Only look for the first report in `ruddersysevents`, slow on large tables
+    FirstReport,
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct UpstreamConfig {
     /// DEPRECATED: use host and global.https_port
@@ -613,6 +1166,15 @@ pub struct UpstreamConfig {
     /// Not used if verification model is not `Rudder`.
     #[serde(default = "UpstreamConfig::default_server_certificate_file")]
     pub server_certificate_file: PathBuf,
+    /// Compress uncompressed runlogs and inventories with gzip while sending them,
+    /// with a `.gz` suffix added to the file name
+    #[serde(default)]
+    pub compress: bool,
+    /// Other upstream servers, used in order when the previous ones are unavailable
+    #[serde(default)]
+    pub failover: Vec<FailoverUpstreamConfig>,
+    #[serde(default)]
+    pub health_check: HealthCheckConfig,
     // TODO timeout?
 }
 
@@ -644,6 +1206,48 @@ impl Default for UpstreamConfig {
             default_password: Default::default(),
             verify_certificates: Self::default_verify_certificates(),
             server_certificate_file: Self::default_server_certificate_file(),
+            compress: false,
+            failover: Default::default(),
+            health_check: Default::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct FailoverUpstreamConfig {
+    /// Port comes from global https_port setting
+    pub host: String,
+    /// Mandatory with cert_pinning
+    #[serde(default)]
+    pub server_certificate_file: Option<PathBuf>,
+}
+
+/// Upstream servers availability checks, only done with failover servers
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct HealthCheckConfig {
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "HealthCheckConfig::default_frequency")]
+    pub frequency: Duration,
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "HealthCheckConfig::default_timeout")]
+    pub timeout: Duration,
+}
+
+impl HealthCheckConfig {
+    fn default_frequency() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            frequency: Self::default_frequency(),
+            timeout: Self::default_timeout(),
         }
     }
 }
@@ -699,6 +1303,7 @@ mod tests {
                 https_port: 443,
                 https_idle_timeout: Duration::from_secs(2),
                 peer_authentication: PeerAuthentication::SystemRootCerts,
+                shutdown_timeout: Duration::from_secs(30),
             },
             processing: ProcessingConfig {
                 inventory: InventoryConfig {
@@ -712,6 +1317,18 @@ mod tests {
                         frequency: Duration::from_secs(3600),
                         retention: Duration::from_secs(3600 * 24 * 7),
                     },
+                    retry: RetryConfig {
+                        initial_delay: Duration::from_secs(30),
+                        max_delay: Duration::from_secs(3600),
+                        max_attempts: 20,
+                    },
+                    workers: 1,
+                    validation: InventoryValidationConfig {
+                        signature: false,
+                        xml: false,
+                    },
+                    pairing_timeout: Duration::from_secs(60),
+                    allow_unsigned: true,
                 },
                 reporting: ReportingConfig {
                     directory: PathBuf::from("/var/rudder/reports/"),
@@ -724,7 +1341,32 @@ mod tests {
                         frequency: Duration::from_secs(3600),
                         retention: Duration::from_secs(3600 * 24 * 7),
                     },
+                    retry: RetryConfig {
+                        initial_delay: Duration::from_secs(30),
+                        max_delay: Duration::from_secs(3600),
+                        max_attempts: 20,
+                    },
+                    workers: 1,
+                    batch: BatchConfig {
+                        size: 1,
+                        delay: Duration::from_secs(1),
+                    },
+                    ordering: OrderingConfig {
+                        delay: Duration::from_secs(0),
+                        record_older_executions: true,
+                    },
                     skip_event_types: HashSet::new(),
+                    compliance: false,
+                    rules: vec![],
+                    redaction: RedactionConfig {
+                        patterns: vec![],
+                        forward_redacted: false,
+                        key_file: PathBuf::from(
+                            "/var/rudder/cfengine-community/ppkeys/localhost.priv",
+                        ),
+                        certificate_file: PathBuf::from("/opt/rudder/etc/ssl/agent.cert"),
+                        accept_relay_signatures: false,
+                    },
                 },
             },
             output: OutputConfig {
@@ -736,11 +1378,41 @@ mod tests {
                     default_password: Secret::new("".to_string()),
                     verify_certificates: true,
                     server_certificate_file: PathBuf::from("/var/rudder/lib/ssl/policy_server.pem"),
+                    compress: false,
+                    failover: vec![],
+                    health_check: HealthCheckConfig {
+                        frequency: Duration::from_secs(30),
+                        timeout: Duration::from_secs(10),
+                    },
                 },
                 database: DatabaseConfig {
                     url: "postgres://rudder@127.0.0.1/rudder".to_string(),
                     password: Secret::new("".to_string()),
                     max_pool_size: 10,
+                    deduplication: RunlogDeduplication::Digest,
+                    purge: DatabasePurgeConfig {
+                        enabled: false,
+                        frequency: Duration::from_secs(3600),
+                        retention: Duration::from_secs(3600 * 24 * 7),
+                        archive: false,
+                        batch_size: 10_000,
+                    },
+                },
+                file: FileOutputConfig {
+                    directory: PathBuf::from("/var/rudder/reports/output/"),
+                    max_size: 104_857_600,
+                    max_age: Duration::from_secs(86_400),
+                },
+                webhook: WebhookConfig {
+                    url: "".to_string(),
+                    token: None,
+                    headers: HashMap::new(),
+                    peer_authentication: PeerAuthentication::SystemRootCerts,
+                    server_certificate_file: None,
+                    timeout: Duration::from_secs(30),
+                },
+                directory: DirectoryOutputConfig {
+                    path: PathBuf::from("/var/rudder/inventories/export/"),
                 },
             },
             remote_run: RemoteRun {
@@ -798,6 +1470,7 @@ mod tests {
                 https_port: 4443,
                 https_idle_timeout: Duration::from_secs(42),
                 peer_authentication: PeerAuthentication::CertPinning,
+                shutdown_timeout: Duration::from_secs(10),
             },
             processing: ProcessingConfig {
                 inventory: InventoryConfig {
@@ -811,6 +1484,18 @@ mod tests {
                         frequency: Duration::from_secs(10),
                         retention: Duration::from_secs(10),
                     },
+                    retry: RetryConfig {
+                        initial_delay: Duration::from_secs(30),
+                        max_delay: Duration::from_secs(3600),
+                        max_attempts: 20,
+                    },
+                    workers: 2,
+                    validation: InventoryValidationConfig {
+                        signature: false,
+                        xml: true,
+                    },
+                    pairing_timeout: Duration::from_secs(5),
+                    allow_unsigned: true,
                 },
                 reporting: ReportingConfig {
                     directory: PathBuf::from("target/tmp/reporting/"),
@@ -823,7 +1508,75 @@ mod tests {
                         frequency: Duration::from_secs(30),
                         retention: Duration::from_secs(30 * 60 + 20),
                     },
+                    retry: RetryConfig {
+                        initial_delay: Duration::from_secs(5),
+                        max_delay: Duration::from_secs(600),
+                        max_attempts: 3,
+                    },
+                    workers: 4,
+                    batch: BatchConfig {
+                        size: 50,
+                        delay: Duration::from_millis(500),
+                    },
+                    ordering: OrderingConfig {
+                        delay: Duration::from_millis(200),
+                        record_older_executions: false,
+                    },
                     skip_event_types: HashSet::new(),
+                    compliance: true,
+                    rules: vec![
+                        ReportRule {
+                            rule_id: None,
+                            directive_id: None,
+                            component: None,
+                            key_value: None,
+                            event_type: Some("log_info".to_string()),
+                            msg: Some("^diff ".parse().unwrap()),
+                            action: ReportAction::Drop,
+                        },
+                        ReportRule {
+                            rule_id: None,
+                            directive_id: Some("common-root".to_string()),
+                            component: None,
+                            key_value: None,
+                            event_type: None,
+                            msg: None,
+                            action: ReportAction::Truncate { max_bytes: 4096 },
+                        },
+                        ReportRule {
+                            rule_id: None,
+                            directive_id: None,
+                            component: Some("Command execution".to_string()),
+                            key_value: None,
+                            event_type: None,
+                            msg: None,
+                            action: ReportAction::Rewrite {
+                                set: vec![(ReportField::Msg, "Command output removed".to_string())]
+                                    .into_iter()
+                                    .collect(),
+                            },
+                        },
+                    ],
+                    redaction: RedactionConfig {
+                        patterns: vec![
+                            RedactionPattern {
+                                regex: "password=\\S+".parse().unwrap(),
+                                replacement: "password=***".to_string(),
+                            },
+                            RedactionPattern {
+                                regex: "-----BEGIN [A-Z ]*PRIVATE KEY-----".parse().unwrap(),
+                                replacement: "[REDACTED]".to_string(),
+                            },
+                        ],
+                        forward_redacted: false,
+                        key_file: PathBuf::from(
+                            "tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.nopass.priv",
+                        ),
+                        certificate_file: PathBuf::from(
+                            "tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert",
+                        ),
+                        accept_relay_signatures: true,
+                    },
                 },
             },
             output: OutputConfig {
@@ -837,11 +1590,50 @@ mod tests {
                     server_certificate_file: PathBuf::from(
                         "tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert",
                     ),
+                    compress: true,
+                    failover: vec![FailoverUpstreamConfig {
+                        host: "rudder2.example.com".to_string(),
+                        server_certificate_file: Some(PathBuf::from(
+                            "tests/files/keys/37817c4d-fbf7-4850-a985-50021f4e8f41.cert",
+                        )),
+                    }],
+                    health_check: HealthCheckConfig {
+                        frequency: Duration::from_secs(10),
+                        timeout: Duration::from_secs(5),
+                    },
                 },
                 database: DatabaseConfig {
                     url: "postgres://rudderreports@postgres/rudder".to_string(),
                     password: Secret::new("PASSWORD".to_string()),
                     max_pool_size: 5,
+                    deduplication: RunlogDeduplication::DigestAndFirstReport,
+                    purge: DatabasePurgeConfig {
+                        enabled: false,
+                        frequency: Duration::from_secs(600),
+                        retention: Duration::from_secs(3600 * 24 * 30),
+                        archive: true,
+                        batch_size: 500,
+                    },
+                },
+                file: FileOutputConfig {
+                    directory: PathBuf::from("target/tmp/output"),
+                    max_size: 1_048_576,
+                    max_age: Duration::from_secs(3600),
+                },
+                webhook: WebhookConfig {
+                    url: "https://localhost:4443/webhook".to_string(),
+                    token: Some(Secret::new("TOKEN".to_string())),
+                    headers: [("X-Source".to_string(), "relay".to_string())]
+                        .into_iter()
+                        .collect(),
+                    peer_authentication: PeerAuthentication::CertPinning,
+                    server_certificate_file: Some(PathBuf::from(
+                        "tests/files/keys/37817c4d-fbf7-4850-a985-50021f4e8f41.cert",
+                    )),
+                    timeout: Duration::from_secs(5),
+                },
+                directory: DirectoryOutputConfig {
+                    path: PathBuf::from("target/tmp/inventories/export"),
                 },
             },
             remote_run: RemoteRun {
@@ -865,6 +1657,23 @@ mod tests {
             config.upstream_url(),
             "https://rudder.example.com:4443/".to_string()
         );
+        assert_eq!(
+            config.upstream_servers(),
+            vec![
+                (
+                    "https://rudder.example.com:4443/".to_string(),
+                    Some(Path::new(
+                        "tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert"
+                    ))
+                ),
+                (
+                    "https://rudder2.example.com:4443/".to_string(),
+                    Some(Path::new(
+                        "tests/files/keys/37817c4d-fbf7-4850-a985-50021f4e8f41.cert"
+                    ))
+                ),
+            ]
+        );
         assert!(config.validate().is_ok());
     }
 }
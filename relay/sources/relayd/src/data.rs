@@ -1,8 +1,10 @@
 // SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
+pub mod compliance;
 pub mod node;
 pub mod report;
+pub mod rules;
 pub mod runinfo;
 pub mod runlog;
 pub mod shared_file;
@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::collections::BTreeMap;
+
+use chrono::prelude::*;
+use serde::Serialize;
+
+use crate::data::{
+    node::NodeId,
+    runlog::{RunLog, RunLogType},
+    Report, RunInfo,
+};
+
+/// Number of reports by result
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ComplianceCounts {
+    pub success: u64,
+    pub repaired: u64,
+    pub error: u64,
+    pub na: u64,
+    pub audit_compliant: u64,
+    pub audit_noncompliant: u64,
+    pub audit_error: u64,
+    pub audit_na: u64,
+}
+
+impl ComplianceCounts {
+    /// Labels used in metrics, in field order
+    pub const STATUSES: [&'static str; 8] = [
+        "success",
+        "repaired",
+        "error",
+        "na",
+        "audit_compliant",
+        "audit_noncompliant",
+        "audit_error",
+        "audit_na",
+    ];
+
+    /// Returns `false` for event types which are not results, like logs or control
+    fn add(&mut self, event_type: &str) -> bool {
+        let counter = match event_type {
+            "result_success" => &mut self.success,
+            "result_repaired" => &mut self.repaired,
+            "result_error" => &mut self.error,
+            "result_na" => &mut self.na,
+            "audit_compliant" => &mut self.audit_compliant,
+            "audit_noncompliant" => &mut self.audit_noncompliant,
+            "audit_error" => &mut self.audit_error,
+            "audit_na" => &mut self.audit_na,
+            _ => return false,
+        };
+        *counter += 1;
+        true
+    }
+
+    /// Values in `STATUSES` order
+    pub fn values(&self) -> [u64; 8] {
+        [
+            self.success,
+            self.repaired,
+            self.error,
+            self.na,
+            self.audit_compliant,
+            self.audit_noncompliant,
+            self.audit_error,
+            self.audit_na,
+        ]
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DirectiveCompliance {
+    pub rule_id: String,
+    pub directive_id: String,
+    #[serde(flatten)]
+    pub counts: ComplianceCounts,
+}
+
+/// Results of a runlog, computed on the relay so that the state of the nodes
+/// is known locally even when the root server is unreachable
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ComplianceSummary {
+    pub node_id: NodeId,
+    /// Run timestamp, from the runlog file name
+    pub date: DateTime<FixedOffset>,
+    pub config_id: Option<String>,
+    pub run_type: RunLogType,
+    /// Between the start and end control reports, missing if one of them is absent
+    pub duration_seconds: Option<i64>,
+    pub total: ComplianceCounts,
+    pub directives: Vec<DirectiveCompliance>,
+}
+
+/// Computes a `ComplianceSummary` from the reports of a runlog, which can come
+/// by batches
+#[derive(Debug, Clone)]
+pub struct ComplianceBuilder {
+    info: RunInfo,
+    config_id: Option<String>,
+    start: Option<DateTime<FixedOffset>>,
+    end: Option<DateTime<FixedOffset>>,
+    total: ComplianceCounts,
+    directives: BTreeMap<(String, String), ComplianceCounts>,
+}
+
+impl ComplianceBuilder {
+    pub fn new(info: RunInfo) -> Self {
+        Self {
+            info,
+            config_id: None,
+            start: None,
+            end: None,
+            total: ComplianceCounts::default(),
+            directives: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, report: &Report) {
+        if report.event_type == "control" {
+            match report.component.as_str() {
+                "start" => self.start = Some(report.execution_datetime),
+                "end" => self.end = Some(report.execution_datetime),
+                _ => return,
+            }
+            if self.config_id.is_none() {
+                self.config_id = Some(report.key_value.clone());
+            }
+            return;
+        }
+        if self.total.add(&report.event_type) {
+            self.directives
+                .entry((report.rule_id.clone(), report.directive_id.clone()))
+                .or_default()
+                .add(&report.event_type);
+        }
+    }
+
+    pub fn build(self) -> ComplianceSummary {
+        ComplianceSummary {
+            node_id: self.info.node_id,
+            date: self.info.timestamp,
+            config_id: self.config_id,
+            run_type: if self.start.is_some() {
+                RunLogType::Complete
+            } else {
+                RunLogType::Partial
+            },
+            duration_seconds: match (self.start, self.end) {
+                (Some(start), Some(end)) => Some((end - start).num_seconds()),
+                _ => None,
+            },
+            total: self.total,
+            directives: self
+                .directives
+                .into_iter()
+                .map(|((rule_id, directive_id), counts)| DirectiveCompliance {
+                    rule_id,
+                    directive_id,
+                    counts,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&RunLog> for ComplianceSummary {
+    fn from(runlog: &RunLog) -> Self {
+        let mut builder = ComplianceBuilder::new(runlog.info.clone());
+        for report in &runlog.reports {
+            builder.add(report);
+        }
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryFrom, str::FromStr};
+
+    use super::*;
+
+    #[test]
+    fn it_computes_compliance_summary() {
+        let runlog = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        let summary = ComplianceSummary::from(&runlog);
+
+        assert_eq!(summary.node_id, "e745a140-40bc-4b86-b6dc-084488fc906b");
+        assert_eq!(summary.run_type, RunLogType::Complete);
+        assert_eq!(summary.config_id, runlog.config_id);
+
+        let results = runlog
+            .reports
+            .iter()
+            .filter(|r| r.event_type.starts_with("result_") || r.event_type.starts_with("audit_"))
+            .count() as u64;
+        assert!(results > 0);
+        assert_eq!(summary.total.values().iter().sum::<u64>(), results);
+        assert_eq!(
+            summary
+                .directives
+                .iter()
+                .map(|d| d.counts.values().iter().sum::<u64>())
+                .sum::<u64>(),
+            results
+        );
+    }
+
+    #[test]
+    fn it_computes_run_duration() {
+        let info =
+            RunInfo::from_str("2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log")
+                .unwrap();
+        let runlog = RunLog::try_from((
+            info,
+            "2018-08-24T15:55:01+00:00 R: @@Common@@control@@rudder@@run@@0@@start@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#Start execution\n\
+             2018-08-24T15:55:03+00:00 R: @@Common@@result_success@@rule1@@directive1@@0@@Component@@None@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#Ok\n\
+             2018-08-24T15:55:04+00:00 R: @@Common@@result_repaired@@rule1@@directive1@@0@@Component@@None@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#Repaired\n\
+             2018-08-24T15:55:05+00:00 R: @@Common@@log_info@@rule1@@directive2@@0@@Component@@None@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#Info\n\
+             2018-08-24T15:55:43+00:00 R: @@Common@@control@@rudder@@run@@0@@end@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#End execution\n",
+        ))
+        .unwrap();
+        let summary = ComplianceSummary::from(&runlog);
+
+        assert_eq!(summary.duration_seconds, Some(42));
+        assert_eq!(
+            summary.config_id,
+            Some("20180824-130007-3ad37587".to_string())
+        );
+        assert_eq!(
+            summary.directives,
+            vec![DirectiveCompliance {
+                rule_id: "rule1".to_string(),
+                directive_id: "directive1".to_string(),
+                counts: ComplianceCounts {
+                    success: 1,
+                    repaired: 1,
+                    ..Default::default()
+                },
+            }]
+        );
+    }
+}
@@ -209,6 +209,29 @@ impl NodesList {
         })
     }
 
+    /// Certificates of the relays between the node and us, closest to the node first
+    pub fn relays_certs(&self, id: &NodeIdRef) -> Vec<&Stack<X509>> {
+        let mut certs = vec![];
+        let mut current = id;
+        // Bounded in case of a loop in the list
+        for _ in 0..self.list.data.len() {
+            let relay = match self.list.data.get(current) {
+                Some(node) if node.policy_server != self.my_id => node.policy_server.as_str(),
+                _ => break,
+            };
+            match self.list.data.get(relay) {
+                Some(node) => {
+                    if let Some(ref c) = node.certificates {
+                        certs.push(c);
+                    }
+                }
+                None => break,
+            }
+            current = relay;
+        }
+        certs
+    }
+
     fn id_from_cert(cert: &X509) -> Result<NodeId, Error> {
         Ok(cert
             .subject_name()
@@ -446,6 +469,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_gets_relays_certificates() {
+        let nodeslist = NodesList::new(
+            "root".to_string(),
+            "tests/files/nodeslist.json",
+            Some("tests/files/keys/nodescerts.pem"),
+        )
+        .unwrap();
+        // a745a140 has no known certificate
+        let certs = nodeslist.relays_certs("b745a140-40bc-4b86-b6dc-084488fc906b");
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0].len(), 2);
+        assert_eq!(
+            nodeslist
+                .relays_certs("c745a140-40bc-4b86-b6dc-084488fc906b")
+                .len(),
+            1
+        );
+        assert!(nodeslist
+            .relays_certs("e745a140-40bc-4b86-b6dc-084488fc906b")
+            .is_empty());
+        assert!(nodeslist.relays_certs("unknown").is_empty());
+    }
+
     #[test]
     fn if_gets_subrelays() {
         assert!(
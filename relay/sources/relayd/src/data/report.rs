@@ -1,9 +1,6 @@
 // SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-// Prevent warning with diesel::Insertable
-#![allow(clippy::extra_unused_lifetimes)]
-
 use std::fmt::{self, Display};
 
 use chrono::prelude::*;
@@ -16,7 +13,7 @@ use nom::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::{data::node::NodeId, output::database::schema::ruddersysevents};
+use crate::data::node::NodeId;
 
 type AgentLogLevel = &'static str;
 
@@ -153,7 +150,7 @@ fn log_entries(i: &str) -> IResult<&str, Vec<LogEntry>> {
     many0(log_entry)(i)
 }
 
-pub fn report(i: &str) -> IResult<&str, ParsedReport> {
+pub fn report(i: &str) -> IResult<&str, RawReport> {
     let (i, logs) = log_entries(i)?;
     let (i, execution_datetime) =
         map_res(take_until(" "), |d| DateTime::parse_from_str(d, "%+"))(i)?;
@@ -190,7 +187,7 @@ pub fn report(i: &str) -> IResult<&str, ParsedReport> {
 
     Ok((
         i,
-        Ok(RawReport {
+        RawReport {
             report: Report {
                 // We could skip parsing it but it would prevent consistency check that cannot
                 // be done once inserted.
@@ -212,42 +209,256 @@ pub fn report(i: &str) -> IResult<&str, ParsedReport> {
                 policy: policy.to_string(),
             },
             logs,
-        }),
+        },
     ))
 }
 
+/// Rejected input, as a slice of the runlog
+type Chunk<'a> = Result<RawReport, &'a str>;
+
 /// Skip garbage before a report, useful in case there are
 /// very broken (not timestamped) lines for some reason.
-fn garbage(i: &str) -> IResult<&str, ParsedReport> {
-    let (i, _) = not(line_timestamp)(i)?;
-    let (i, res) = simpleline(i)?;
-    Ok((i, Err(res.to_string())))
+fn garbage(i: &str) -> IResult<&str, Chunk<'_>> {
+    let (rest, _) = not(line_timestamp)(i)?;
+    let (rest, _) = simpleline(rest)?;
+    Ok((rest, Err(&i[..i.len() - rest.len()])))
 }
 
 // Handle errors: eat the broken report and continue
-fn until_next(i: &str) -> IResult<&str, ParsedReport> {
+fn until_next(i: &str) -> IResult<&str, Chunk<'_>> {
     // The line looking like a report
-    let (i, first) = take_until("R: @@")(i)?;
-    let (i, tag) = tag("R: @@")(i)?;
+    let (rest, _) = take_until("R: @@")(i)?;
+    let (rest, _) = tag("R: @@")(rest)?;
     // The end of the broken report
-    let (i, multi) = multilines(i)?;
-    let mut lines = first.to_string();
-    lines.push_str(tag);
-    for line in multi {
-        lines.push_str(line);
-    }
-    Ok((i, Err(lines)))
+    let (rest, _) = multilines(rest)?;
+    Ok((rest, Err(&i[..i.len() - rest.len()])))
 }
 
-fn maybe_report(i: &str) -> IResult<&str, ParsedReport> {
-    alt((report, garbage, until_next))(i)
+fn maybe_report(i: &str) -> IResult<&str, Chunk<'_>> {
+    alt((map(report, Ok), garbage, until_next))(i)
 }
 
 pub fn runlog(i: &str) -> IResult<&str, Vec<ParsedReport>> {
-    many1(maybe_report)(i)
+    let (rest, chunks) = many1(maybe_report)(i)?;
+    Ok((
+        rest,
+        chunks
+            .into_iter()
+            .map(|c| c.map_err(|rejected| ParseDiagnostic::rejected(i, rejected)))
+            .collect(),
+    ))
 }
 
-pub type ParsedReport = Result<RawReport, String>;
+/// Incremental version of `runlog`, parsing reports one at a time instead of
+/// building the whole list.
+///
+/// Like `runlog`, it fails if no report can be parsed at all, and otherwise stops
+/// at the first unparsable content.
+pub struct RawReports<'a> {
+    content: &'a str,
+    input: &'a str,
+    started: bool,
+    done: bool,
+}
+
+impl<'a> RawReports<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self {
+            content: input,
+            input,
+            started: false,
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for RawReports<'a> {
+    /// The error is the content that stopped the parsing
+    type Item = Result<ParsedReport, ParseDiagnostic>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match maybe_report(self.input) {
+            // Always make progress
+            Ok((rest, report)) if rest.len() < self.input.len() => {
+                self.input = rest;
+                self.started = true;
+                Some(Ok(report.map_err(|rejected| {
+                    ParseDiagnostic::rejected(self.content, rejected)
+                })))
+            }
+            _ if self.started => {
+                self.done = true;
+                None
+            }
+            Ok((rest, _)) => {
+                self.done = true;
+                Some(Err(ParseDiagnostic::new(
+                    self.content,
+                    ParseErrorKind::Unparsable,
+                    rest,
+                    nom::error::ErrorKind::Many1,
+                )))
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                self.done = true;
+                Some(Err(ParseDiagnostic::new(
+                    self.content,
+                    ParseErrorKind::Unparsable,
+                    e.input,
+                    e.code,
+                )))
+            }
+            Err(nom::Err::Incomplete(_)) => {
+                self.done = true;
+                Some(Err(ParseDiagnostic::new(
+                    self.content,
+                    ParseErrorKind::Unparsable,
+                    self.input,
+                    nom::error::ErrorKind::Complete,
+                )))
+            }
+        }
+    }
+}
+
+pub type ParsedReport = Result<RawReport, ParseDiagnostic>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParseErrorKind {
+    /// Line without timestamp outside of a report
+    Garbage,
+    /// Unparsable execution or run timestamp
+    InvalidTimestamp,
+    /// Missing field separator, usually a truncated report
+    MissingSeparator,
+    /// Other broken content, like an unterminated multiline message
+    Malformed,
+    /// Content that stopped the parsing of the runlog
+    Unparsable,
+}
+
+impl ParseErrorKind {
+    pub const ALL: [Self; 5] = [
+        Self::Garbage,
+        Self::InvalidTimestamp,
+        Self::MissingSeparator,
+        Self::Malformed,
+        Self::Unparsable,
+    ];
+
+    /// Used as metrics label
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Garbage => "garbage",
+            Self::InvalidTimestamp => "invalid_timestamp",
+            Self::MissingSeparator => "missing_separator",
+            Self::Malformed => "malformed",
+            Self::Unparsable => "unparsable",
+        }
+    }
+}
+
+impl Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str().replace('_', " "))
+    }
+}
+
+/// Maximum length of the content displayed in diagnostics
+const SNIPPET_LENGTH: usize = 80;
+
+/// Why and where a part of a runlog could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParseDiagnostic {
+    pub kind: ParseErrorKind,
+    /// Byte offset of the failure in the runlog
+    pub offset: usize,
+    /// Line of the failure, starting at 1
+    pub line: usize,
+    /// nom combinator that failed
+    pub combinator: String,
+    /// Content at the failure, until the end of the line
+    pub snippet: String,
+}
+
+impl ParseDiagnostic {
+    /// `failed` must be a slice of `content`
+    fn new(
+        content: &str,
+        kind: ParseErrorKind,
+        failed: &str,
+        combinator: nom::error::ErrorKind,
+    ) -> Self {
+        let offset = failed.as_ptr() as usize - content.as_ptr() as usize;
+        let snippet: String = failed
+            .chars()
+            .take_while(|c| *c != '\n' && *c != '\r')
+            .take(SNIPPET_LENGTH)
+            .collect();
+        Self {
+            kind,
+            offset,
+            line: content[..offset].matches('\n').count() + 1,
+            combinator: format!("{:?}", combinator),
+            snippet,
+        }
+    }
+
+    /// Parses the rejected chunk again, alone, to find out where it failed.
+    ///
+    /// `rejected` must be a slice of `content`.
+    fn rejected(content: &str, rejected: &str) -> Self {
+        let (kind, error) = match line_timestamp(rejected) {
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => (ParseErrorKind::Garbage, e),
+            _ => match report(rejected) {
+                Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => (
+                    match e.code {
+                        nom::error::ErrorKind::MapRes => ParseErrorKind::InvalidTimestamp,
+                        nom::error::ErrorKind::TakeUntil | nom::error::ErrorKind::Tag => {
+                            ParseErrorKind::MissingSeparator
+                        }
+                        _ => ParseErrorKind::Malformed,
+                    },
+                    e,
+                ),
+                _ => (
+                    ParseErrorKind::Malformed,
+                    nom::error::Error::new(rejected, nom::error::ErrorKind::Complete),
+                ),
+            },
+        };
+        Self::new(content, kind, error.input, error.code)
+    }
+}
+
+impl Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {} (byte {}): {} in {}: '{}'",
+            self.line, self.offset, self.kind, self.combinator, self.snippet
+        )
+    }
+}
+
+/// Diagnostics of a runlog that could not be processed, attached as context
+/// to the error so that they are kept with the failed file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseDiagnostics(pub Vec<ParseDiagnostic>);
+
+impl Display for ParseDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} parts of the runlog could not be parsed",
+            self.0.len()
+        )
+    }
+}
 
 // We could make RawReport insertable to avoid copying context to simple logs
 #[derive(Debug, PartialEq, Eq)]
@@ -297,30 +508,19 @@ pub struct QueryableReport {
     pub report_id: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Insertable)]
-#[diesel(table_name = ruddersysevents)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Report {
-    #[diesel(column_name = "executiontimestamp")]
     pub start_datetime: DateTime<FixedOffset>,
-    #[diesel(column_name = "ruleid")]
     pub rule_id: String,
-    #[diesel(column_name = "directiveid")]
     pub directive_id: String,
     pub component: String,
-    #[diesel(column_name = "keyvalue")]
     pub key_value: String,
     // Not parsed as we do not use it and do not want to prevent future changes
-    #[diesel(column_name = "eventtype")]
     pub event_type: String,
-    #[diesel(column_name = "msg")]
     pub msg: String,
-    #[diesel(column_name = "policy")]
     pub policy: String,
-    #[diesel(column_name = "nodeid")]
     pub node_id: NodeId,
-    #[diesel(column_name = "executiondate")]
     pub execution_datetime: DateTime<FixedOffset>,
-    #[diesel(column_name = "reportid")]
     pub report_id: String,
 }
 
@@ -698,7 +898,7 @@ mod tests {
         let report = "2018-08-24T15:55:01+00:00 R: @@Common@@broken\n";
         assert_eq!(
             maybe_report(report).unwrap().1,
-            Err("2018-08-24T15:55:01+00:00 R: @@Common@@broken".to_string())
+            Err("2018-08-24T15:55:01+00:00 R: @@Common@@broken\n")
         );
         let report = "garbage\n2018-08-24T15:55:01+00:00 R: @@Common@@result_repaired@@hasPolicyServer-root@@common-root@@@@CRON Daemon@@multi\r\n2018-08-24T15:55:01+00:00 line@@2018-08-24 15:55:01 +00:00##root@#Cron daemon status was repaired\r\n";
         let (i, e) = maybe_report(report).unwrap();
@@ -763,7 +963,7 @@ mod tests {
         let report = "2018-08-24T15:55:01+00:00 R: @@Common@@broken\n";
         assert_eq!(
             maybe_report(report).unwrap().1,
-            Err("2018-08-24T15:55:01+00:00 R: @@Common@@broken".to_string())
+            Err("2018-08-24T15:55:01+00:00 R: @@Common@@broken\n")
         );
     }
 
@@ -772,12 +972,54 @@ mod tests {
         let report = "test\n2018-08-24T15:55:01+00:00 R: @@Common@@broken\n";
         assert_eq!(
             until_next(report).unwrap().1,
-            Err("test\n2018-08-24T15:55:01+00:00 R: @@Common@@broken".to_string())
+            Err("test\n2018-08-24T15:55:01+00:00 R: @@Common@@broken\n")
         );
         let report = "2018-08-24T15:55:01+00:00 R: @@Common@@broken\r\n2018-08-24T15:55:01+00:00 R: @@Common@@result_repaired@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01 +00:00##root@#Cron daemon status was repaired\r\n";
         assert_eq!(
             until_next(report).unwrap().1,
-            Err("2018-08-24T15:55:01+00:00 R: @@Common@@broken".to_string())
+            Err("2018-08-24T15:55:01+00:00 R: @@Common@@broken\r\n")
+        );
+    }
+
+    #[test]
+    fn it_diagnoses_rejected_content() {
+        let runlog_content = "garbage\n2018-08-24T15:55:01+00:00 R: @@Common@@result_repaired@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01 +00:00##root@#Cron daemon status was repaired\n2018-08-24T15:55:01+00:00 R: @@Common@@broken\n2018-08-24T15:55:01+00:00 R: @@Common@@result_repaired@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@yesterday##root@#Cron daemon status was repaired\n";
+        let diagnostics: Vec<ParseDiagnostic> = RawReports::new(runlog_content)
+            .filter_map(|r| r.unwrap().err())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                ParseDiagnostic {
+                    kind: ParseErrorKind::Garbage,
+                    offset: 0,
+                    line: 1,
+                    combinator: "TakeUntil".to_string(),
+                    snippet: "garbage".to_string(),
+                },
+                ParseDiagnostic {
+                    kind: ParseErrorKind::MissingSeparator,
+                    offset: 226,
+                    line: 3,
+                    combinator: "TakeUntil".to_string(),
+                    snippet: "broken".to_string(),
+                },
+                ParseDiagnostic {
+                    kind: ParseErrorKind::InvalidTimestamp,
+                    offset: 346,
+                    line: 4,
+                    combinator: "MapRes".to_string(),
+                    snippet: "yesterday##root@#Cron daemon status was repaired".to_string(),
+                },
+            ]
         );
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "line 3 (byte 226): missing separator in TakeUntil: 'broken'"
+        );
+
+        let diagnostic = RawReports::new("garbage").next().unwrap().unwrap_err();
+        assert_eq!(diagnostic.kind, ParseErrorKind::Unparsable);
+        assert_eq!(diagnostic.line, 1);
     }
 }
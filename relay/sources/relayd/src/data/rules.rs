@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::borrow::Cow;
+
+use crate::{
+    configuration::main::{RedactionPattern, ReportAction, ReportField, ReportRule},
+    data::Report,
+    metrics::{REPORTS_REDACTIONS, REPORTS_RULES},
+};
+
+impl ReportRule {
+    pub fn matches(&self, report: &Report) -> bool {
+        let eq = |expected: &Option<String>, value: &str| {
+            expected.as_ref().map(|e| e == value).unwrap_or(true)
+        };
+        eq(&self.rule_id, &report.rule_id)
+            && eq(&self.directive_id, &report.directive_id)
+            && eq(&self.component, &report.component)
+            && eq(&self.key_value, &report.key_value)
+            && eq(&self.event_type, &report.event_type)
+            && self
+                .msg
+                .as_ref()
+                .map(|p| p.regex().is_match(&report.msg))
+                .unwrap_or(true)
+    }
+}
+
+/// Applies all matching rules in order, returns `None` if the report is dropped
+pub fn apply(rules: &[ReportRule], mut report: Report) -> Option<Report> {
+    for rule in rules {
+        // Checked against the report as modified by previous rules
+        if !rule.matches(&report) {
+            continue;
+        }
+        match &rule.action {
+            ReportAction::Drop => {
+                REPORTS_RULES
+                    .with_label_values(&[rule.action.as_str()])
+                    .inc();
+                return None;
+            }
+            ReportAction::Truncate { max_bytes } => {
+                if report.msg.len() > *max_bytes {
+                    // Don't cut a multi-byte character
+                    let mut end = *max_bytes;
+                    while !report.msg.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    report.msg.truncate(end);
+                    REPORTS_RULES
+                        .with_label_values(&[rule.action.as_str()])
+                        .inc();
+                }
+            }
+            ReportAction::Rewrite { set } => {
+                for (field, value) in set {
+                    let target = match field {
+                        ReportField::RuleId => &mut report.rule_id,
+                        ReportField::DirectiveId => &mut report.directive_id,
+                        ReportField::Component => &mut report.component,
+                        ReportField::KeyValue => &mut report.key_value,
+                        ReportField::EventType => &mut report.event_type,
+                        ReportField::Msg => &mut report.msg,
+                    };
+                    target.clone_from(value);
+                }
+                REPORTS_RULES
+                    .with_label_values(&[rule.action.as_str()])
+                    .inc();
+            }
+        }
+    }
+    Some(report)
+}
+
+/// Replaces sensitive data in the message, with each pattern in order
+pub fn redact(patterns: &[RedactionPattern], report: &mut Report) {
+    for pattern in patterns {
+        let redacted = match pattern
+            .regex
+            .regex()
+            .replace_all(&report.msg, pattern.replacement.as_str())
+        {
+            Cow::Owned(redacted) => Some(redacted),
+            // Nothing matched
+            Cow::Borrowed(_) => None,
+        };
+        if let Some(redacted) = redacted {
+            report.msg = redacted;
+            REPORTS_REDACTIONS.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+
+    fn report(event_type: &str, msg: &str) -> Report {
+        let date =
+            DateTime::parse_from_str("2018-08-24 15:55:01+00:00", "%Y-%m-%d %H:%M:%S%z").unwrap();
+        Report {
+            start_datetime: date,
+            rule_id: "hasPolicyServer-root".into(),
+            directive_id: "common-root".into(),
+            component: "CRON Daemon".into(),
+            key_value: "None".into(),
+            event_type: event_type.into(),
+            msg: msg.into(),
+            policy: "Common".into(),
+            node_id: "root".into(),
+            report_id: "0".into(),
+            execution_datetime: date,
+        }
+    }
+
+    fn rule(event_type: Option<&str>, msg: Option<&str>, action: ReportAction) -> ReportRule {
+        ReportRule {
+            rule_id: None,
+            directive_id: None,
+            component: None,
+            key_value: None,
+            event_type: event_type.map(|e| e.to_string()),
+            msg: msg.map(|m| m.parse().unwrap()),
+            action,
+        }
+    }
+
+    #[test]
+    fn it_drops_matching_reports() {
+        let rules = vec![rule(Some("log_info"), Some("^diff "), ReportAction::Drop)];
+
+        assert_eq!(apply(&rules, report("log_info", "diff -u a b")), None);
+        let kept = report("log_info", "no diff");
+        assert_eq!(apply(&rules, kept.clone()), Some(kept));
+        let kept = report("result_success", "diff -u a b");
+        assert_eq!(apply(&rules, kept.clone()), Some(kept));
+    }
+
+    #[test]
+    fn it_truncates_and_rewrites_reports() {
+        let rules = vec![
+            rule(None, None, ReportAction::Truncate { max_bytes: 4 }),
+            rule(
+                Some("log_info"),
+                None,
+                ReportAction::Rewrite {
+                    set: vec![(ReportField::EventType, "log_debug".to_string())]
+                        .into_iter()
+                        .collect(),
+                },
+            ),
+        ];
+
+        assert_eq!(
+            apply(&rules, report("result_success", "abcdef")),
+            Some(report("result_success", "abcd"))
+        );
+        // "é" is two bytes long
+        assert_eq!(
+            apply(&rules, report("log_info", "abcé")),
+            Some(report("log_debug", "abc"))
+        );
+    }
+
+    #[test]
+    fn it_redacts_messages() {
+        let patterns = vec![
+            RedactionPattern {
+                regex: "(password|token)=\\S+".parse().unwrap(),
+                replacement: "$1=***".to_string(),
+            },
+            RedactionPattern {
+                regex: "secret".parse().unwrap(),
+                replacement: "[REDACTED]".to_string(),
+            },
+        ];
+
+        let mut redacted = report("log_info", "curl token=abc password=def secret");
+        redact(&patterns, &mut redacted);
+        assert_eq!(
+            redacted,
+            report("log_info", "curl token=*** password=*** [REDACTED]")
+        );
+
+        let mut unchanged = report("log_info", "nothing to hide");
+        redact(&patterns, &mut unchanged);
+        assert_eq!(unchanged, report("log_info", "nothing to hide"));
+    }
+}
@@ -5,8 +5,9 @@ use crate::{data::node::NodeId, error::RudderError};
 use anyhow::Error;
 use chrono::prelude::*;
 use nom::{
+    branch::alt,
     bytes::complete::{tag, take_until},
-    combinator::{map_res, opt},
+    combinator::{eof, map_res, opt},
     IResult,
 };
 use serde::{Deserialize, Serialize};
@@ -41,7 +42,9 @@ fn parse_runinfo(i: &str) -> IResult<&str, RunInfo> {
     let (i, _) = tag("@")(i)?;
     let (i, node_id) = take_until(".")(i)?;
     let (i, _) = tag(".log")(i)?;
-    let (i, _) = opt(tag(".gz"))(i)?;
+    // Supported compression formats, see `input::read_compressed_file`
+    let (i, _) = opt(alt((tag(".gz"), tag(".zst"), tag(".xz"))))(i)?;
+    let (i, _) = eof(i)?;
 
     if node_id.is_empty() {
         Err(nom::Err::Failure(nom::error::Error::new(
@@ -138,6 +141,36 @@ mod tests {
         )
         .is_err());
         assert!(RunInfo::from_str("2018-08-24T15:55:01+00:00@.log.gz").is_err());
+        assert!(RunInfo::from_str(
+            "2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log.bz2"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn it_rejects_trailing_suffixes() {
+        for name in &[
+            "2018-08-24T15:55:01+00:00@root.log.zip",
+            "2018-08-24T15:55:01+00:00@root.log.gz.tmp",
+            "2018-08-24T15:55:01+00:00@root.log~",
+        ] {
+            assert!(RunInfo::from_str(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn it_parses_compressed_runinfo() {
+        let reference = RunInfo {
+            timestamp: DateTime::parse_from_str("2018-08-24T15:55:01+00:00", "%+").unwrap(),
+            node_id: "root".into(),
+        };
+        for extension in &["gz", "zst", "xz"] {
+            assert_eq!(
+                RunInfo::from_str(&format!("2018-08-24T15:55:01+00:00@root.log.{}", extension))
+                    .unwrap(),
+                reference
+            );
+        }
     }
 
     #[test]
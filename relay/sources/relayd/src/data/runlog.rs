@@ -1,9 +1,6 @@
 // SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-// Prevent warning with diesel::Insertable
-#![allow(clippy::extra_unused_lifetimes)]
-
 use std::{
     collections::HashSet,
     convert::TryFrom,
@@ -19,29 +16,25 @@ use serde::{Deserialize, Serialize};
 use tracing::{debug, error, warn};
 
 use crate::{
+    configuration::main::{RedactionPattern, ReportRule},
     data::{
-        report::{runlog, RawReport},
-        Report, RunInfo,
+        compliance::{ComplianceBuilder, ComplianceSummary},
+        report::{ParseDiagnostic, ParseDiagnostics, RawReports},
+        rules, Report, RunInfo,
     },
     error::RudderError,
-    output::database::schema::reportsexecution,
+    hashing::HashType,
+    metrics::REPORTS_PARSE_ERRORS,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Insertable)]
-#[diesel(table_name = reportsexecution)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 /// Represents a runlog in the database
 pub struct InsertedRunlog {
-    #[diesel(column_name = "nodeid")]
     pub node_id: String,
-    #[diesel(column_name = "date")]
     pub date: DateTime<FixedOffset>,
-    #[diesel(column_name = "nodeconfigid")]
     pub node_config_id: Option<String>,
-    #[diesel(column_name = "insertionid")]
     pub insertion_id: i64,
-    #[diesel(column_name = "insertiondate")]
     pub insertion_date: Option<DateTime<FixedOffset>>,
-    #[diesel(column_name = "compliancecomputationdate")]
     pub compliance_computation_date: Option<DateTime<FixedOffset>>,
 }
 
@@ -60,8 +53,27 @@ impl InsertedRunlog {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Identifies a received runlog, to detect duplicates
+pub struct RunlogDigest {
+    pub node_id: String,
+    pub date: DateTime<FixedOffset>,
+    /// sha256 of the signed content
+    pub digest: String,
+}
+
+impl RunlogDigest {
+    pub fn new(info: &RunInfo, content: &str) -> Self {
+        Self {
+            node_id: info.node_id.clone(),
+            date: info.timestamp,
+            digest: HashType::Sha256.hash(content.as_bytes()).hex(),
+        }
+    }
+}
+
 /// Type of agent log
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum RunLogType {
     /// Complete policy run, which means we can use this result
     /// as compliance source
@@ -80,7 +92,7 @@ pub enum RunLogType {
 /// We want to allow invalid runlogs as much as possible
 /// to let the webapp give meaningful feedback to the user.
 /// The only constraint is that the runlog contains at least one proper report.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct RunLog {
     pub info: RunInfo,
     pub config_id: Option<String>,
@@ -88,10 +100,25 @@ pub struct RunLog {
     pub reports: Vec<Report>,
 }
 
+/// The alternate form (`{:#}`) is the agent output format, with timestamped lines,
+/// which can be parsed again
 impl Display for RunLog {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for report in &self.reports {
-            writeln!(f, "R: {:}", report)?
+            if f.alternate() {
+                let timestamp = report.execution_datetime.to_rfc3339();
+                // Multi-line values continue on timestamped lines
+                writeln!(
+                    f,
+                    "{} R: {}",
+                    timestamp,
+                    report
+                        .to_string()
+                        .replace('\n', &format!("\n{} ", timestamp))
+                )?
+            } else {
+                writeln!(f, "R: {:}", report)?
+            }
         }
         Ok(())
     }
@@ -125,6 +152,27 @@ impl RunLog {
         }
     }
 
+    /// Drops or modifies reports according to the rules
+    pub fn with_rules(self, rules: &[ReportRule]) -> Self {
+        Self {
+            info: self.info,
+            config_id: self.config_id,
+            reports: self
+                .reports
+                .into_iter()
+                .filter_map(|r| rules::apply(rules, r))
+                .collect(),
+        }
+    }
+
+    /// Replaces sensitive data in report messages
+    pub fn redacted(mut self, patterns: &[RedactionPattern]) -> Self {
+        for report in &mut self.reports {
+            rules::redact(patterns, report);
+        }
+        self
+    }
+
     /// Is the `RunLog` an actual agent run, with a start and (hopefully) an end,
     /// or a partial policy run, like happens with `rudder agent inventory`.
     pub fn log_type(&self) -> RunLogType {
@@ -144,72 +192,234 @@ impl TryFrom<(RunInfo, &str)> for RunLog {
     type Error = Error;
 
     fn try_from(raw_reports: (RunInfo, &str)) -> Result<Self, Self::Error> {
-        match runlog(raw_reports.1) {
-            Ok(raw_runlog) => {
-                debug!("Parsed runlog {:#?}", raw_runlog.1);
-                let (reports, failed): (Vec<_>, Vec<_>) =
-                    raw_runlog.1.into_iter().partition(Result::is_ok);
-                for invalid_report in failed.into_iter().map(Result::unwrap_err) {
-                    warn!("Invalid report: {}", invalid_report);
-                }
-
-                let reports: Vec<RawReport> = reports.into_iter().map(Result::unwrap).collect();
-                RunLog::try_from((raw_reports.0, reports))
-            }
-            Err(e) => {
-                warn!("{:?}: could not parse '{}'", e, raw_reports.0);
-                Err(RudderError::InvalidRunLog(format!("{:?}", e)).into())
-            }
+        let mut reader = RunLogReader::new(raw_reports.0, raw_reports.1, usize::MAX);
+        let mut reports = vec![];
+        for batch in &mut reader {
+            reports.extend(batch?);
         }
+        if reports.is_empty() {
+            return Err(reader.with_diagnostics(RudderError::InconsistentRunlog.into()));
+        }
+        Ok(Self {
+            info: reader.info,
+            config_id: reader.config_id,
+            reports,
+        })
     }
 }
 
-impl TryFrom<(RunInfo, Vec<RawReport>)> for RunLog {
-    type Error = Error;
+/// Parses and checks a runlog incrementally, yielding reports by batches of
+/// at most `batch_size` to avoid materializing all the reports of big runlogs.
+/// The raw content is borrowed, so it is still fully loaded in memory.
+///
+/// Checks are the same as for `RunLog` and a wrong node id in any report
+/// makes the whole runlog invalid, so the consumer must be able to roll back.
+/// Runlog-level information (`config_id`, `log_type`) is only complete once
+/// all batches have been consumed.
+pub struct RunLogReader<'a> {
+    pub info: RunInfo,
+    /// Extracted from start or end control reports
+    pub config_id: Option<String>,
+    /// Parts of the runlog that could not be parsed, complete once all
+    /// batches have been consumed
+    pub diagnostics: Vec<ParseDiagnostic>,
+    raw_reports: RawReports<'a>,
+    batch_size: usize,
+    skip_event_types: HashSet<String>,
+    rules: Vec<ReportRule>,
+    redaction: Vec<RedactionPattern>,
+    /// Includes skipped event types
+    compliance: ComplianceBuilder,
+    /// Execution timestamp of the first report
+    timestamp: Option<DateTime<FixedOffset>>,
+    has_start: bool,
+    done: bool,
+}
 
-    fn try_from(raw_reports: (RunInfo, Vec<RawReport>)) -> Result<Self, Self::Error> {
-        let reports: Vec<Report> = raw_reports
-            .1
-            .into_iter()
-            .flat_map(RawReport::into_reports)
-            .collect();
-        let info = raw_reports.0;
-        let timestamp = reports
-            .first()
-            .ok_or(RudderError::InconsistentRunlog)?
-            .start_datetime;
-
-        // Try to extract a configId from start or end control reports
-        let config_id = reports
-            .iter()
-            .find(|r| r.event_type == "control" && (r.component == "start" || r.component == "end"))
-            .map(|r| r.key_value.clone());
+impl<'a> RunLogReader<'a> {
+    pub fn new(info: RunInfo, content: &'a str, batch_size: usize) -> Self {
+        Self {
+            compliance: ComplianceBuilder::new(info.clone()),
+            info,
+            config_id: None,
+            diagnostics: vec![],
+            raw_reports: RawReports::new(content),
+            batch_size: batch_size.max(1),
+            skip_event_types: HashSet::new(),
+            rules: vec![],
+            redaction: vec![],
+            timestamp: None,
+            has_start: false,
+            done: false,
+        }
+    }
+
+    /// Do not yield reports of the given types
+    pub fn without_types(mut self, types: &HashSet<String>) -> Self {
+        self.skip_event_types = types.clone();
+        self
+    }
+
+    /// Drop or modify reports according to the rules, after skipped types
+    pub fn with_rules(mut self, rules: &[ReportRule]) -> Self {
+        self.rules = rules.to_vec();
+        self
+    }
 
-        if config_id.is_none() {
-            warn!("Missing start/end control reports in runlog, no config id available");
+    /// Replace sensitive data in report messages, before any other processing
+    pub fn with_redaction(mut self, patterns: &[RedactionPattern]) -> Self {
+        self.redaction = patterns.to_vec();
+        self
+    }
+
+    /// Execution timestamp of the first yielded report
+    pub fn start_datetime(&self) -> Option<DateTime<FixedOffset>> {
+        self.timestamp
+    }
+
+    /// Only meaningful after consuming all reports
+    pub fn log_type(&self) -> RunLogType {
+        if self.has_start {
+            RunLogType::Complete
+        } else {
+            RunLogType::Partial
         }
+    }
 
-        for report in &reports {
-            if info.node_id != report.node_id {
-                error!(
-                    "Wrong node id in report {:#?}, got {} but should be {}",
-                    report, report.node_id, info.node_id
-                );
-                let e: Error = RudderError::InconsistentRunlog.into();
-                return Err(e);
-            }
-            if timestamp != report.start_datetime {
+    /// Only meaningful after consuming all reports
+    pub fn inserted_runlog(&self, insertion_id: i64) -> Option<InsertedRunlog> {
+        self.timestamp.map(|date| InsertedRunlog {
+            node_id: self.info.node_id.clone(),
+            date,
+            node_config_id: self.config_id.clone(),
+            insertion_id,
+            // None means default value will be inserted, here current_timestamp
+            insertion_date: None,
+            compliance_computation_date: None,
+        })
+    }
+
+    /// Only meaningful after consuming all reports
+    pub fn compliance_summary(&self) -> ComplianceSummary {
+        self.compliance.clone().build()
+    }
+
+    /// Attaches the diagnostics collected so far to the error
+    fn with_diagnostics(&self, error: Error) -> Error {
+        if self.diagnostics.is_empty() {
+            error
+        } else {
+            error.context(ParseDiagnostics(self.diagnostics.clone()))
+        }
+    }
+
+    fn diagnose(&mut self, diagnostic: ParseDiagnostic) {
+        REPORTS_PARSE_ERRORS
+            .with_label_values(&[diagnostic.kind.as_str()])
+            .inc();
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn check(&mut self, report: &Report) -> Result<(), Error> {
+        if self.info.node_id != report.node_id {
+            error!(
+                "Wrong node id in report {:#?}, got {} but should be {}",
+                report, report.node_id, self.info.node_id
+            );
+            return Err(RudderError::InconsistentRunlog.into());
+        }
+        match self.timestamp {
+            None => self.timestamp = Some(report.start_datetime),
+            Some(timestamp) if timestamp != report.start_datetime => {
                 warn!(
                     "Wrong execution timestamp in report {:#?}, got {} but should be {}",
                     report, report.start_datetime, timestamp
                 );
             }
+            _ => (),
+        }
+        if report.event_type == "control" {
+            // Try to extract a configId from start or end control reports
+            if self.config_id.is_none()
+                && (report.component == "start" || report.component == "end")
+            {
+                self.config_id = Some(report.key_value.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for RunLogReader<'a> {
+    type Item = Result<Vec<Report>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut batch = vec![];
+        while batch.len() < self.batch_size {
+            let raw_report = match self.raw_reports.next() {
+                Some(Ok(Ok(r))) => r,
+                Some(Ok(Err(diagnostic))) => {
+                    debug!("Invalid report in '{}': {}", self.info, diagnostic);
+                    self.diagnose(diagnostic);
+                    continue;
+                }
+                Some(Err(diagnostic)) => {
+                    warn!("{}: could not parse '{}'", diagnostic, self.info);
+                    self.done = true;
+                    let error = RudderError::InvalidRunLog(diagnostic.to_string());
+                    self.diagnose(diagnostic);
+                    return Some(Err(self.with_diagnostics(error.into())));
+                }
+                None => {
+                    self.done = true;
+                    if !self.diagnostics.is_empty() {
+                        // One record for the whole file
+                        warn!(
+                            "{} invalid reports in '{}': {}",
+                            self.diagnostics.len(),
+                            self.info,
+                            serde_json::to_string(&self.diagnostics)
+                                .unwrap_or_else(|e| e.to_string())
+                        );
+                    }
+                    if self.config_id.is_none() {
+                        warn!(
+                            "Missing start/end control reports in runlog, no config id available"
+                        );
+                    }
+                    break;
+                }
+            };
+            for mut report in raw_report.into_reports() {
+                if let Err(e) = self.check(&report) {
+                    self.done = true;
+                    return Some(Err(self.with_diagnostics(e)));
+                }
+                rules::redact(&self.redaction, &mut report);
+                self.compliance.add(&report);
+                if self.skip_event_types.contains(&report.event_type) {
+                    continue;
+                }
+                let report = match rules::apply(&self.rules, report) {
+                    Some(r) => r,
+                    None => continue,
+                };
+                if report.event_type == "control" && report.component == "start" {
+                    self.has_start = true;
+                }
+                batch.push(report);
+            }
+        }
+
+        if batch.is_empty() {
+            None
+        } else {
+            Some(Ok(batch))
         }
-        Ok(Self {
-            info,
-            reports,
-            config_id,
-        })
     }
 }
 
@@ -241,6 +451,71 @@ mod tests {
         assert!(test_done > 1);
     }
 
+    #[test]
+    fn it_reads_runlog_by_batches() {
+        let path = "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let reference = RunLog::new(path).unwrap();
+
+        let content = read_to_string(path).unwrap();
+        let mut reader = RunLogReader::new(reference.info.clone(), &content, 10);
+        let mut batches = 0;
+        let mut reports = vec![];
+        for batch in &mut reader {
+            reports.extend(batch.unwrap());
+            batches += 1;
+        }
+        assert!(batches > 1);
+        assert_eq!(reports, reference.reports);
+        assert_eq!(reader.config_id, reference.config_id);
+        assert_eq!(reader.log_type(), RunLogType::Complete);
+        assert_eq!(
+            reader.compliance_summary(),
+            ComplianceSummary::from(&reference)
+        );
+        assert_eq!(
+            reader.inserted_runlog(42).unwrap(),
+            InsertedRunlog::new(&reference, 42)
+        );
+
+        // Wrong node id in a later report
+        let info =
+            RunInfo::from_str("2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906c.log")
+                .unwrap();
+        assert!(RunLogReader::new(info, &content, 10).any(|b| b.is_err()));
+    }
+
+    #[test]
+    fn it_serializes_runlog_in_agent_format() {
+        let runlog = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        assert_eq!(
+            RunLog::try_from((runlog.info.clone(), format!("{:#}", runlog).as_ref())).unwrap(),
+            runlog
+        );
+    }
+
+    #[test]
+    fn it_serializes_multiline_reports_in_agent_format() {
+        let info =
+            RunInfo::from_str("2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log")
+                .unwrap();
+        let runlog = RunLog::try_from((
+            info.clone(),
+            "2018-08-24T15:55:01+00:00 R: @@Common@@control@@rudder@@run@@0@@start@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#Start execution\n\
+             2018-08-24T15:55:02+00:00 R: @@Common@@log_info@@rule1@@directive1@@0@@Component@@None@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#first line\n\
+             2018-08-24T15:55:02+00:00 second line\n\
+             third line\n",
+        ))
+        .unwrap();
+        assert_eq!(runlog.reports[1].msg, "first line\nsecond line\nthird line");
+
+        let output = format!("{:#}", runlog);
+        assert!(output.contains("@#first line\n2018-08-24T15:55:02+00:00 second line\n"));
+        assert_eq!(RunLog::try_from((info, output.as_ref())).unwrap(), runlog);
+    }
+
     #[test]
     fn it_detect_invalid_node_in_runlog() {
         assert!(
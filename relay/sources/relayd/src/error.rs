@@ -56,4 +56,18 @@ pub enum RudderError {
     MissingHeader(String),
     #[error("invalid shared file: {0}")]
     InvalidSharedFile(String),
+    #[error("could not write output file: {0}")]
+    OutputFile(String),
+    #[error("request refused by webhook with status {0}")]
+    WebhookRefused(u16),
+    #[error("invalid inventory: {0}")]
+    InvalidInventory(String),
+    #[error("invalid inventory signature: {0}")]
+    InvalidInventorySignature(String),
+    #[error("missing inventory signature: {0:?}")]
+    MissingInventorySignature(PathBuf),
+    #[error("invalid database schema: {0}")]
+    InvalidDatabaseSchema(String),
+    #[error("database schema is not initialized, run 'relayd db init' first")]
+    UninitializedDatabaseSchema,
 }
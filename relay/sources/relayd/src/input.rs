@@ -1,6 +1,7 @@
 // SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
+pub mod inventory;
 pub mod watch;
 
 use crate::error::RudderError;
@@ -8,41 +9,75 @@ use anyhow::Error;
 use flate2::read::GzDecoder;
 use openssl::{
     pkcs7::{Pkcs7, Pkcs7Flags},
+    pkey::{PKey, Private},
     stack::Stack,
     x509::{store::X509StoreBuilder, X509},
 };
 use std::{
     ffi::OsStr,
-    io::{Cursor, Read},
-    path::Path,
+    fs::{read, File},
+    io::{BufReader, Cursor, Read, Seek},
+    path::{Path, PathBuf},
 };
-use tokio::fs::read;
+use tokio::task::spawn_blocking;
 use tracing::debug;
+use xz2::read::XzDecoder;
 use zip::read::ZipArchive;
 
+/// Decompresses the file while reading it, so that the compressed content is
+/// not kept in memory next to the uncompressed one.
+///
+/// The whole uncompressed content is returned, as signatures can only be
+/// verified on complete messages (see `SignedMessage`).
 pub async fn read_compressed_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
-    let path = path.as_ref();
+    let path = path.as_ref().to_path_buf();
+    // Decompression is CPU-bound and uses blocking io
+    spawn_blocking(move || read_compressed_file_sync(&path)).await?
+}
 
+fn read_compressed_file_sync(path: &PathBuf) -> Result<Vec<u8>, Error> {
     debug!("Reading {:#?} content", path);
-    let data = read(path).await?;
+    let file = File::open(path)?;
+    // Only a hint, the compressed size is a lower bound
+    let size_hint = file.metadata().map(|m| m.len() as usize).unwrap_or(0);
+    decompress_from(BufReader::new(file), path, size_hint)
+}
+
+/// Decompresses an already read file content, depending on the file extension
+pub fn decompress(content: &[u8], path: &Path) -> Result<Vec<u8>, Error> {
+    decompress_from(Cursor::new(content), path, content.len())
+}
 
-    Ok(match path.extension().and_then(OsStr::to_str) {
+fn decompress_from<R: Read + Seek>(
+    mut reader: R,
+    path: &Path,
+    size_hint: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut uncompressed_data = Vec::with_capacity(size_hint);
+
+    match path.extension().and_then(OsStr::to_str) {
         Some("gz") => {
             debug!("{:?} has .gz extension, extracting", path);
-            let mut gz = GzDecoder::new(data.as_slice());
-            let mut uncompressed_data = vec![];
+            let mut gz = GzDecoder::new(reader);
             gz.read_to_end(&mut uncompressed_data)?;
-            uncompressed_data
+        }
+        Some("zst") => {
+            debug!("{:?} has .zst extension, extracting", path);
+            let mut zst = zstd::Decoder::new(reader)?;
+            zst.read_to_end(&mut uncompressed_data)?;
+        }
+        Some("xz") => {
+            debug!("{:?} has .xz extension, extracting", path);
+            let mut xz = XzDecoder::new(reader);
+            xz.read_to_end(&mut uncompressed_data)?;
         }
         Some("zip") => {
             debug!("{:?} has .zip extension, extracting", path);
-            let mut zip = ZipArchive::new(Cursor::new(data))?;
+            let mut zip = ZipArchive::new(reader)?;
             // Considering only the first file in the zip
             // There should be only one anyway
             let mut first_file = zip.by_index(0)?;
-            let mut uncompressed_data: Vec<u8> = Vec::new();
             let _ = first_file.read_to_end(&mut uncompressed_data);
-            uncompressed_data
         }
         // Let's assume everything else is a text file
         _ => {
@@ -50,9 +85,10 @@ pub async fn read_compressed_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Er
                 "{:?} has no compressed file extension, no extraction needed",
                 path
             );
-            data
+            reader.read_to_end(&mut uncompressed_data)?;
         }
-    })
+    }
+    Ok(uncompressed_data)
 }
 
 /// Parses an S/MIME message as an UTF-8 string and validates the signature with the given
@@ -70,36 +106,94 @@ pub async fn read_compressed_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Er
 /// * `-nocerts` to avoid including certs in the signature, as we use the known
 ///   certificate on the server to validate signature and embedded certs are ignored.
 pub fn signature(input: &[u8], certs: &Stack<X509>) -> Result<String, Error> {
-    let (signature, content) = Pkcs7::from_smime(input)?;
-
-    // An empty content is possible in S/MIME, but is it an
-    // error in the Rudder context.
-    let content = content.ok_or(RudderError::EmptyRunlog)?;
-
-    let mut flags = Pkcs7Flags::empty();
-    // To remove text header
-    flags.set(Pkcs7Flags::TEXT, true);
-    // Ignore certificates contained in the message, we only rely on the one we know
-    // Our messages should not contain certs anyway
-    flags.set(Pkcs7Flags::NOINTERN, true);
-    // Do not verify chain (as we have no meaningful chaining)
-    // Only verify that the provided cert has signed the message
-    flags.set(Pkcs7Flags::NOVERIFY, true);
-    // No chaining so no need for a CA store
-    let store = X509StoreBuilder::new()?.build();
-
-    let mut message = vec![];
-    signature.verify(certs, &store, Some(&content), Some(&mut message), flags)?;
-
-    // We have validated the presence of a plain text MIME type, let's parse
-    // content as a string.
-    Ok(String::from_utf8(message)?)
+    SignedMessage::from_smime(input)?.verify(certs)
+}
+
+/// A parsed S/MIME message, which can be checked against several sets of certificates.
+///
+/// openssl only verifies complete messages held in memory, so the signed content is
+/// not streamed. Parsing it first allows dropping the raw message before
+/// verification, and avoids parsing it again for each set of certificates.
+pub struct SignedMessage {
+    signature: Pkcs7,
+    content: Vec<u8>,
+}
+
+impl SignedMessage {
+    pub fn from_smime(input: &[u8]) -> Result<Self, Error> {
+        let (signature, content) = Pkcs7::from_smime(input)?;
+
+        // An empty content is possible in S/MIME, but is it an
+        // error in the Rudder context.
+        let content = content.ok_or(RudderError::EmptyRunlog)?;
+        Ok(Self { signature, content })
+    }
+
+    /// Validates the signature, see `signature` for the expected format
+    pub fn verify(&self, certs: &Stack<X509>) -> Result<String, Error> {
+        let mut flags = Pkcs7Flags::empty();
+        // To remove text header
+        flags.set(Pkcs7Flags::TEXT, true);
+        // Ignore certificates contained in the message, we only rely on the one we know
+        // Our messages should not contain certs anyway
+        flags.set(Pkcs7Flags::NOINTERN, true);
+        // Do not verify chain (as we have no meaningful chaining)
+        // Only verify that the provided cert has signed the message
+        flags.set(Pkcs7Flags::NOVERIFY, true);
+        // No chaining so no need for a CA store
+        let store = X509StoreBuilder::new()?.build();
+
+        let mut message = vec![];
+        self.signature.verify(
+            certs,
+            &store,
+            Some(&self.content),
+            Some(&mut message),
+            flags,
+        )?;
+
+        // We have validated the presence of a plain text MIME type, let's parse
+        // content as a string.
+        Ok(String::from_utf8(message)?)
+    }
+}
+
+/// Signs content with the key of this relay, in the same format as the agent,
+/// to forward modified runlogs
+pub struct RunlogSigner {
+    key: PKey<Private>,
+    certificate: X509,
+}
+
+impl RunlogSigner {
+    pub fn new(key_file: &Path, certificate_file: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            key: PKey::private_key_from_pem(&read(key_file)?)?,
+            certificate: X509::from_pem(&read(certificate_file)?)?,
+        })
+    }
+
+    /// Equivalent of `openssl smime -sign -text -nocerts -md sha256`, which
+    /// can be checked with `signature`
+    pub fn sign(&self, content: &str) -> Result<Vec<u8>, Error> {
+        let flags = Pkcs7Flags::TEXT | Pkcs7Flags::NOCERTS | Pkcs7Flags::DETACHED;
+        let certs: Stack<X509> = Stack::new()?;
+        let signed = Pkcs7::sign(
+            &self.certificate,
+            &self.key,
+            &certs,
+            content.as_bytes(),
+            flags,
+        )?;
+        Ok(signed.to_smime(content.as_bytes(), flags)?)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs::read_to_string;
+    use tokio::fs::read;
 
     #[tokio::test]
     async fn it_reads_gzipped_files() {
@@ -123,6 +217,28 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn it_reads_zstd_files() {
+        let reference = read("tests/files/gz/normal.log").await.unwrap();
+        assert_eq!(
+            read_compressed_file("tests/files/gz/normal.log.zst")
+                .await
+                .unwrap(),
+            reference
+        );
+    }
+
+    #[tokio::test]
+    async fn it_reads_xz_files() {
+        let reference = read("tests/files/gz/normal.log").await.unwrap();
+        assert_eq!(
+            read_compressed_file("tests/files/gz/normal.log.xz")
+                .await
+                .unwrap(),
+            reference
+        );
+    }
+
     #[tokio::test]
     async fn it_reads_plain_files() {
         let reference = read("tests/files/gz/normal.log").await.unwrap();
@@ -193,4 +309,62 @@ mod tests {
         )
         .is_err());
     }
+
+    #[test]
+    fn it_verifies_parsed_message_with_several_certificates() {
+        let reference = read_to_string("tests/files/smime/normal.log").unwrap();
+        let message =
+            SignedMessage::from_smime(&std::fs::read("tests/files/smime/normal.signed").unwrap())
+                .unwrap();
+
+        let mut other_certs = Stack::new().unwrap();
+        other_certs
+            .push(
+                X509::from_pem(
+                    &std::fs::read(
+                        "tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b-other.cert",
+                    )
+                    .unwrap(),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let mut certs = Stack::new().unwrap();
+        certs
+            .push(
+                X509::from_pem(
+                    &std::fs::read("tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert")
+                        .unwrap(),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+
+        assert!(message.verify(&other_certs).is_err());
+        assert_eq!(message.verify(&certs).unwrap(), reference);
+    }
+
+    #[test]
+    fn it_signs_content() {
+        let signer = RunlogSigner::new(
+            Path::new("tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.nopass.priv"),
+            Path::new("tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert"),
+        )
+        .unwrap();
+        let content = read_to_string("tests/files/gz/normal.log").unwrap();
+        let signed = signer.sign(&content).unwrap();
+
+        let x509 = X509::from_pem(
+            &std::fs::read("tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert").unwrap(),
+        )
+        .unwrap();
+        let mut certs = Stack::new().unwrap();
+        certs.push(x509).unwrap();
+
+        // Line endings are converted to CRLF
+        assert_eq!(
+            signature(&signed, &certs).unwrap().replace("\r\n", "\n"),
+            content
+        );
+    }
 }
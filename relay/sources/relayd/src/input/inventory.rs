@@ -0,0 +1,235 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::{
+    ffi::OsStr,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Error;
+use quick_xml::{events::Event, Reader};
+use tokio::fs::read_to_string;
+use tracing::debug;
+
+use crate::{
+    configuration::main::InventoryValidationConfig, data::shared_file::Metadata,
+    error::RudderError, hashing::Hash, input::read_compressed_file,
+};
+
+const SIGNATURE_EXTENSION: &str = "sign";
+/// Root element of agent inventories
+const ROOT_ELEMENT: &[u8] = b"REQUEST";
+
+pub fn is_signature(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new(SIGNATURE_EXTENSION))
+}
+
+/// Signature file expected next to the inventory
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut sign = path.as_os_str().to_owned();
+    sign.push(".");
+    sign.push(SIGNATURE_EXTENSION);
+    sign.into()
+}
+
+/// Inventory signed by the signature file
+pub fn signed_path(signature: &Path) -> PathBuf {
+    signature.with_extension("")
+}
+
+/// Checks that the content is a well-formed XML document with the inventory root element
+pub fn check_xml(content: &[u8]) -> Result<(), RudderError> {
+    let mut reader = Reader::from_reader(content);
+    let mut buf = vec![];
+    let mut depth = 0;
+    let mut has_root = false;
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Start(e)) => {
+                if depth == 0 {
+                    if has_root || e.name().as_ref() != ROOT_ELEMENT {
+                        return Err(RudderError::InvalidInventory(format!(
+                            "unexpected root element {}",
+                            String::from_utf8_lossy(e.name().as_ref())
+                        )));
+                    }
+                    has_root = true;
+                }
+                depth += 1;
+            }
+            Ok(Event::End(_)) => depth -= 1,
+            Ok(Event::Empty(_)) if depth == 0 => {
+                return Err(RudderError::InvalidInventory(
+                    "empty root element".to_string(),
+                ))
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => (),
+            Err(e) => {
+                return Err(RudderError::InvalidInventory(format!(
+                    "invalid XML at position {}: {}",
+                    reader.buffer_position(),
+                    e
+                )))
+            }
+        }
+        buf.clear();
+    }
+    if !has_root || depth != 0 {
+        return Err(RudderError::InvalidInventory(
+            "incomplete XML document".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the signature of the (uncompressed) inventory content.
+///
+/// The key hash is only known for accepted nodes, for new inventories
+/// we can only check the signature is consistent with the key it contains.
+pub fn check_signature(
+    content: &[u8],
+    signature: &str,
+    known_key_hash: Option<&Hash>,
+) -> Result<(), Error> {
+    let meta = Metadata::from_str(signature)?;
+
+    if let Some(known) = known_key_hash {
+        let key_hash = known.hash_type.hash(&meta.pubkey()?.public_key_to_der()?);
+        if &key_hash != known {
+            return Err(RudderError::InvalidInventorySignature(format!(
+                "hash of public key ({}) does not match known hash ({})",
+                key_hash, known
+            ))
+            .into());
+        }
+    }
+    if meta.hash.hash_type.hash(content) != meta.hash {
+        return Err(RudderError::InvalidInventorySignature(
+            "hash does not match inventory content".to_string(),
+        )
+        .into());
+    }
+    let digest = hex::decode(&meta.digest)?;
+    if !meta.validate_signature(content, meta.hash.hash_type, &digest)? {
+        return Err(RudderError::InvalidInventorySignature("invalid signature".to_string()).into());
+    }
+    Ok(())
+}
+
+/// Checks an inventory, and its signature file, before sending them further
+pub async fn validate(
+    path: &Path,
+    cfg: InventoryValidationConfig,
+    known_key_hash: Option<&Hash>,
+) -> Result<(), Error> {
+    let content = read_compressed_file(path)
+        .await
+        .map_err(|e| RudderError::InvalidInventory(format!("could not read content: {}", e)))?;
+    if cfg.xml {
+        check_xml(&content)?;
+    }
+    if cfg.signature {
+        let sign_path = signature_path(path);
+        let signature = match read_to_string(&sign_path).await {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(RudderError::MissingInventorySignature(sign_path).into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        check_signature(&content, &signature, known_key_hash)?;
+    }
+    debug!("{:?} is a valid inventory", path);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{fs::write, io::Write};
+
+    use flate2::{write::GzEncoder, Compression};
+    use openssl::{pkey::PKey, rsa::Rsa, sign::Signer};
+
+    use super::*;
+    use crate::hashing::HashType;
+
+    #[test]
+    fn it_checks_inventory_xml() {
+        assert!(check_xml(
+            b"<?xml version=\"1.0\"?>\n<REQUEST><CONTENT><A>1</A></CONTENT></REQUEST>"
+        )
+        .is_ok());
+        assert!(check_xml(b"<REQUEST><CONTENT></REQUEST>").is_err());
+        assert!(check_xml(b"<REQUEST><CONTENT>").is_err());
+        assert!(check_xml(b"<OTHER></OTHER>").is_err());
+        assert!(check_xml(b"<REQUEST/>").is_err());
+        assert!(check_xml(b"").is_err());
+    }
+
+    pub(crate) fn sign(key: &Rsa<openssl::pkey::Private>, content: &[u8]) -> String {
+        let pkey = PKey::from_rsa(key.clone()).unwrap();
+        let mut signer = Signer::new(HashType::Sha256.to_openssl_hash(), &pkey).unwrap();
+        signer.update(content).unwrap();
+        let pem = String::from_utf8(key.public_key_to_pem_pkcs1().unwrap()).unwrap();
+        let short_pubkey: String = pem.lines().filter(|l| !l.starts_with("-----")).collect();
+        format!(
+            "header=rudder-signature-v1\nalgorithm=sha256\ndigest={}\nhash_value={}\nshort_pubkey={}\nhostname=node1\nkeydate=2020-01-01\nkeyid=B29D02BB\n",
+            hex::encode(signer.sign_to_vec().unwrap()),
+            HashType::Sha256.hash(content).hex(),
+            short_pubkey
+        )
+    }
+
+    #[test]
+    fn it_checks_inventory_signature() {
+        let key = Rsa::generate(2048).unwrap();
+        let content = b"<REQUEST></REQUEST>";
+        let signature = sign(&key, content);
+        let key_hash = HashType::Sha256.hash(
+            &PKey::from_rsa(key.clone())
+                .unwrap()
+                .public_key_to_der()
+                .unwrap(),
+        );
+
+        assert!(check_signature(content, &signature, None).is_ok());
+        assert!(check_signature(content, &signature, Some(&key_hash)).is_ok());
+        assert!(check_signature(b"<REQUEST>forged</REQUEST>", &signature, None).is_err());
+
+        let other_hash = HashType::Sha256.hash(b"other key");
+        assert!(check_signature(content, &signature, Some(&other_hash)).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_validates_compressed_inventories() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = Rsa::generate(2048).unwrap();
+        let content = b"<REQUEST><CONTENT></CONTENT></REQUEST>";
+        let inventory = dir.path().join("node1-root.ocs.gz");
+        let cfg = InventoryValidationConfig {
+            signature: true,
+            xml: true,
+        };
+
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(content).unwrap();
+        write(&inventory, gz.finish().unwrap()).unwrap();
+        assert!(matches!(
+            validate(&inventory, cfg, None)
+                .await
+                .unwrap_err()
+                .downcast_ref::<RudderError>(),
+            Some(RudderError::MissingInventorySignature(_))
+        ));
+
+        // The signature is made on the uncompressed content
+        write(signature_path(&inventory), sign(&key, content)).unwrap();
+        assert!(validate(&inventory, cfg, None).await.is_ok());
+        let compressed = std::fs::read(&inventory).unwrap();
+        write(signature_path(&inventory), sign(&key, &compressed)).unwrap();
+        assert!(validate(&inventory, cfg, None).await.is_err());
+    }
+}
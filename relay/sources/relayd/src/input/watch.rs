@@ -19,6 +19,7 @@ use tracing::{debug, error, info, instrument};
 use crate::{
     configuration::main::{CatchupConfig, CleanupConfig, WatchedDirectory},
     processing::ReceivedFile,
+    shutdown::Shutdown,
 };
 
 pub async fn cleanup(path: WatchedDirectory, cfg: CleanupConfig) -> Result<(), Error> {
@@ -71,11 +72,21 @@ pub async fn cleanup(path: WatchedDirectory, cfg: CleanupConfig) -> Result<(), E
     }
 }
 
-#[instrument(name = "watcher", level = "debug", skip(tx))]
-pub fn watch(path: WatchedDirectory, cfg: CatchupConfig, tx: mpsc::Sender<ReceivedFile>) {
+/// Both watchers stop when shutdown is requested, closing the channel
+#[instrument(name = "watcher", level = "debug", skip(tx, shutdown))]
+pub fn watch(
+    path: WatchedDirectory,
+    cfg: CatchupConfig,
+    tx: mpsc::Sender<ReceivedFile>,
+    shutdown: Shutdown,
+) {
     info!("Starting file watcher on {:#?}", &path);
-    tokio::spawn(list_files(path.clone(), cfg, tx.clone()));
-    tokio::spawn(watch_files(path, tx));
+    tokio::spawn(
+        shutdown
+            .clone()
+            .cancel(list_files(path.clone(), cfg, tx.clone())),
+    );
+    tokio::spawn(shutdown.cancel(watch_files(path, tx)));
 }
 
 async fn list_files(
@@ -5,15 +5,22 @@
 extern crate diesel;
 
 use std::{
-    collections::HashMap, fs, fs::create_dir_all, process::exit, string::ToString, sync::Arc,
+    collections::HashMap,
+    fs::create_dir_all,
+    process::exit,
+    string::ToString,
+    sync::{Arc, Mutex},
 };
 
+use futures::future::join_all;
+
 use anyhow::Error;
 use tokio::{
     signal::unix::{signal, SignalKind},
     sync::RwLock,
+    time::timeout,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{
     filter::EnvFilter,
     fmt::{
@@ -29,14 +36,24 @@ use crate::{
         logging::LogConfig,
         main::{
             Configuration, InventoryOutputSelect, OutputSelect, PeerAuthentication,
-            ReportingOutputSelect,
+            ReportingOutputSelect, RunlogDeduplication,
         },
     },
     data::node::{NodeId, NodesList},
     http_client::HttpClient,
+    input::RunlogSigner,
     metrics::{MANAGED_NODES, SUB_NODES},
-    output::database::{pg_pool, PgPool},
-    processing::{inventory, reporting, shared_files},
+    output::{
+        database::{self, migrations::SchemaCheck, DbPool},
+        directory::InventoryExport,
+        file::{self, ReportsFile},
+        upstream::{self, Upstreams},
+        webhook,
+    },
+    processing::{
+        compliance::ComplianceStore, inventory, reporting, shared_files, sinks::SinkState,
+    },
+    shutdown::{Shutdown, ShutdownTrigger},
 };
 
 pub mod api;
@@ -49,6 +66,7 @@ pub mod input;
 pub mod metrics;
 pub mod output;
 pub mod processing;
+pub mod shutdown;
 
 pub const CRATE_NAME: &str = env!("CARGO_PKG_NAME");
 pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -143,14 +161,17 @@ pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(),
         let job_config_reload = job_config.clone();
         signal_handlers(job_config_reload);
 
+        // Tasks to wait for on shutdown
+        let mut tasks = vec![];
+
         // Spawn report and inventory processing
         if job_config.cfg.processing.reporting.output.is_enabled() {
-            reporting::start(&job_config);
+            tasks.extend(reporting::start(&job_config));
         } else {
             info!("Skipping reporting as it is disabled");
         }
         if job_config.cfg.processing.inventory.output.is_enabled() {
-            inventory::start(&job_config);
+            tasks.extend(inventory::start(&job_config));
         } else {
             info!("Skipping inventory as it is disabled");
         }
@@ -158,35 +179,65 @@ pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(),
         // Spawn shared-files cleaner
         shared_files::start(&job_config);
 
+        // Go back to the preferred upstream server once available
+        upstream::start_health_check(&job_config);
+
+        // Remove old reports from our own database
+        database::purge::start(&job_config);
+        // Rotate the reports file when no runlog is received
+        file::start(&job_config);
+
         // Initialize metrics
         job_config.reload_metrics().await;
 
-        // API should never return
-        api::run(job_config.clone())
-            .await
-            .expect("could not start api");
+        // API should only return after a shutdown request
+        let mut api = tokio::spawn(api::run(job_config.clone()));
+        let mut shutdown = job_config.shutdown_signal();
+        tokio::select! {
+            res = &mut api => {
+                res.expect("api crashed").expect("could not start api");
+                panic!("Server halted unexpectedly");
+            }
+            _ = shutdown.requested() => {}
+        }
+        tasks.push(api);
+
+        let deadline = job_config.cfg.general.shutdown_timeout;
+        info!(
+            "Waiting up to {:?} for in-flight processing and API requests",
+            deadline
+        );
+        match timeout(deadline, join_all(tasks)).await {
+            Ok(_) => info!("Shutdown complete"),
+            Err(_) => warn!(
+                "Shutdown timeout reached after {:?}, remaining files will be processed after restart",
+                deadline
+            ),
+        }
     });
-    panic!("Server halted unexpectedly");
+    // Don't wait for blocking tasks still running after the deadline
+    runtime.shutdown_background();
+    Ok(())
 }
 
 fn signal_handlers(job_config: Arc<JobConfig>) {
     // SIGHUP: reload logging configuration + nodes list
+    let job_config_reload = job_config.clone();
     tokio::spawn(async move {
         debug!("Setup configuration reload signal handler");
 
         let mut hangup = signal(SignalKind::hangup()).expect("Error setting up interrupt signal");
         loop {
             hangup.recv().await;
-            let _ = job_config
+            let _ = job_config_reload
                 .reload()
                 .await
                 .map_err(|e| error!("reload error {}", e));
         }
     });
 
-    // SIGINT or SIGTERM: immediate shutdown
-    // TODO: graceful shutdown
-    tokio::spawn(async {
+    // SIGINT or SIGTERM: graceful shutdown, a second signal forces immediate exit
+    tokio::spawn(async move {
         debug!("Setup shutdown signal handler");
 
         let mut terminate =
@@ -195,12 +246,19 @@ fn signal_handlers(job_config: Arc<JobConfig>) {
             signal(SignalKind::interrupt()).expect("Error setting up interrupt signal");
         tokio::select! {
             _ = terminate.recv() => {
-                info!("SIGINT received: shutdown requested");
+                info!("SIGTERM received: shutdown requested");
             },
             _ = interrupt.recv() => {
-                info!("SIGTERM received: shutdown requested");
+                info!("SIGINT received: shutdown requested");
             }
         }
+        job_config.shutdown.trigger();
+
+        tokio::select! {
+            _ = terminate.recv() => {},
+            _ = interrupt.recv() => {}
+        }
+        warn!("Second signal received: immediate shutdown");
         exit(ExitStatus::Shutdown.code());
     });
 }
@@ -210,13 +268,31 @@ pub struct JobConfig {
     pub cli_cfg: CliConfiguration,
     pub cfg: Configuration,
     pub nodes: RwLock<NodesList>,
-    pub pool: Option<PgPool>,
-    /// Parent policy server
-    pub upstream_client: RwLock<HttpClient>,
+    pub pool: Option<DbPool>,
+    /// Configured duplicate runlog detection, if usable with the database schema
+    pub deduplication: RunlogDeduplication,
+    /// Successful database schema check, the schema only changes with
+    /// `relayd db migrate` which requires a restart
+    schema_check: Mutex<Option<SchemaCheck>>,
+    /// Only used with tee output
+    pub sinks: Option<SinkState>,
+    /// Only used with file output
+    pub reports_file: Option<Mutex<ReportsFile>>,
+    /// Only used with webhook output
+    pub webhook_client: Option<HttpClient>,
+    /// Only used with directory output for inventories
+    pub inventory_export: Option<InventoryExport>,
+    /// Only used when forwarding redacted runlogs
+    pub runlog_signer: Option<RunlogSigner>,
+    /// Last run of each node, only filled when compliance is enabled
+    pub compliance: ComplianceStore,
+    /// Parent policy servers
+    pub upstreams: Upstreams,
     /// Sub relays
     // TODO could be lazily created
     pub downstream_clients: RwLock<HashMap<NodeId, HttpClient>>,
     handle: LogHandle,
+    shutdown: ShutdownTrigger,
 }
 
 impl JobConfig {
@@ -235,6 +311,9 @@ impl JobConfig {
         if cfg.processing.reporting.output != ReportingOutputSelect::Disabled {
             create_dir_all(cfg.processing.reporting.directory.join("incoming"))?;
             create_dir_all(cfg.processing.reporting.directory.join("failed"))?;
+            if cfg.processing.reporting.redaction.forward_redacted {
+                create_dir_all(cfg.processing.reporting.directory.join("redacted"))?;
+            }
         }
 
         Ok(())
@@ -247,12 +326,56 @@ impl JobConfig {
     ) -> Result<Arc<Self>, Error> {
         Self::create_dirs(&cfg)?;
 
-        let pool = if cfg.processing.reporting.output == ReportingOutputSelect::Database {
-            Some(pg_pool(&cfg.output.database)?)
+        let pool = if cfg.processing.reporting.output.uses_database() {
+            Some(DbPool::new(&cfg.output.database)?)
+        } else {
+            None
+        };
+        let deduplication = match pool {
+            Some(ref pool) => pool.deduplication(cfg.output.database.deduplication)?,
+            None => cfg.output.database.deduplication,
+        };
+        let schema_check = pool.as_ref().and_then(|pool| {
+            pool.check_schema(deduplication)
+                .map_err(|e| warn!("Could not check the database schema: {}", e))
+                .ok()
+        });
+        let sinks = if cfg.processing.reporting.output == ReportingOutputSelect::Tee {
+            Some(SinkState::new(&cfg.processing.reporting.directory)?)
+        } else {
+            None
+        };
+        let reports_file = if cfg.processing.reporting.output == ReportingOutputSelect::File {
+            Some(Mutex::new(ReportsFile::new(&cfg.output.file)?))
+        } else {
+            None
+        };
+        let webhook_client = if cfg.processing.reporting.output == ReportingOutputSelect::Webhook {
+            debug!("Creating HTTP client for webhook");
+            Some(webhook::client(
+                &cfg.output.webhook,
+                cfg.general.https_idle_timeout,
+            )?)
+        } else {
+            None
+        };
+
+        let runlog_signer = if cfg.processing.reporting.redaction.forward_redacted {
+            Some(RunlogSigner::new(
+                &cfg.processing.reporting.redaction.key_file,
+                &cfg.processing.reporting.redaction.certificate_file,
+            )?)
         } else {
             None
         };
 
+        let inventory_export =
+            if cfg.processing.inventory.output == InventoryOutputSelect::Directory {
+                Some(InventoryExport::new(&cfg.output.directory.path))
+            } else {
+                None
+            };
+
         let nodes = NodesList::new(
             cfg.node_id()?,
             &cfg.general.nodes_list_file,
@@ -263,19 +386,7 @@ impl JobConfig {
         //
         let model = cfg.peer_authentication();
 
-        debug!("Creating HTTP client for upstream");
-        let upstream_client = match model {
-            PeerAuthentication::CertPinning => {
-                let cert = fs::read(&cfg.output.upstream.server_certificate_file)?;
-                HttpClient::builder(cfg.general.https_idle_timeout).pinned(vec![cert])
-            }
-            PeerAuthentication::SystemRootCerts => {
-                HttpClient::builder(cfg.general.https_idle_timeout).system()
-            }
-            PeerAuthentication::DangerousNone => {
-                HttpClient::builder(cfg.general.https_idle_timeout).no_verify()
-            }
-        }?;
+        let upstreams = Upstreams::new(&cfg)?;
 
         let mut downstream_clients = HashMap::new();
 
@@ -310,12 +421,43 @@ impl JobConfig {
             cfg,
             nodes,
             pool,
+            deduplication,
+            schema_check: Mutex::new(schema_check),
+            sinks,
+            reports_file,
+            webhook_client,
+            inventory_export,
+            runlog_signer,
+            compliance: ComplianceStore::new(),
             handle,
-            upstream_client: RwLock::new(upstream_client),
+            upstreams,
             downstream_clients: RwLock::new(downstream_clients),
+            shutdown: ShutdownTrigger::new(),
         }))
     }
 
+    /// Database schema health, the check is only done again if it could
+    /// not be done before
+    pub fn schema_health(&self) -> Result<(), Error> {
+        let pool = match self.pool {
+            Some(ref pool) => pool,
+            None => return Ok(()),
+        };
+        let mut cached = self
+            .schema_check
+            .lock()
+            .expect("schema check lock poisoned");
+        if cached.is_none() {
+            *cached = Some(pool.check_schema(self.deduplication)?);
+        }
+        cached.as_ref().map(|c| c.health()).unwrap_or(Ok(()))
+    }
+
+    /// Allows tasks to stop cleanly when the service is stopping
+    pub fn shutdown_signal(&self) -> Shutdown {
+        self.shutdown.subscribe()
+    }
+
     fn reload_logging(&self) -> Result<(), Error> {
         LogConfig::new(&self.cli_cfg.config).and_then(|log_cfg| {
             self.handle
@@ -331,6 +473,7 @@ impl JobConfig {
             &self.cfg.general.nodes_list_file,
             Some(&self.cfg.general.nodes_certs_file),
         )?;
+        self.compliance.retain(&nodes).await;
 
         Ok(())
     }
@@ -353,22 +496,10 @@ impl JobConfig {
         // possible as when using the clients to make requests, we don't lock for the request
         // duration but only for the time necessary to clone the client (=very short).
         if self.cfg.peer_authentication() == PeerAuthentication::CertPinning {
-            // upstream client
-            let cert = fs::read(&self.cfg.output.upstream.server_certificate_file)?;
-            let certs = vec![cert];
-
-            let needs_reload = self.upstream_client.read().await.outdated(&certs);
-            if needs_reload {
-                debug!(
-                    "Upstream HTTP client has outdated certificate, updating from {}",
-                    &self.cfg.output.upstream.server_certificate_file.display()
-                );
-                let mut upstream_client = self.upstream_client.write().await;
-                *upstream_client =
-                    HttpClient::builder(self.cfg.general.https_idle_timeout).pinned(certs)?;
-            } else {
-                debug!("Upstream HTTP client has up-to-date certificate");
-            }
+            // upstream clients
+            self.upstreams
+                .reload(self.cfg.general.https_idle_timeout)
+                .await?;
 
             // sub-relay clients
             // recreate up to date map, keep existing clients if possible
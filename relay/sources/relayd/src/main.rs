@@ -1,14 +1,29 @@
 // SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use std::{env, process::exit};
+use std::{convert::TryFrom, env, path::Path, process::exit, str::FromStr};
 
+use anyhow::{anyhow, Error};
 use gumdrop::Options;
 use tracing::error;
 
 use rudder_relayd::{
-    configuration::{check_configuration, cli::CliConfiguration},
-    init_logger, start, ExitStatus, CRATE_NAME, CRATE_VERSION,
+    configuration::{
+        check_configuration,
+        cli::{CliConfiguration, Command, DbCommand, InspectFormat, InspectOptions},
+        main::Configuration,
+    },
+    data::{
+        node::NodesList,
+        report::ParseDiagnostic,
+        runlog::{RunLogReader, RunLogType},
+        RunInfo, RunLog,
+    },
+    error::RudderError,
+    init_logger,
+    input::{read_compressed_file, signature},
+    output::{database::DbPool, directory::import},
+    start, ExitStatus, CRATE_NAME, CRATE_VERSION,
 };
 
 /// Everything in a lib to allow extensive testing
@@ -39,6 +54,37 @@ fn main() {
                 println!("Syntax: OK");
             }
         }
+    } else if let Some(Command::ImportInventories(ref opts)) = cli_cfg.command {
+        match Configuration::new(&cli_cfg.config)
+            .and_then(|cfg| import(&opts.directory, &cfg.processing.inventory.directory))
+        {
+            Err(e) => {
+                println!("{:#}", e);
+                exit(ExitStatus::StartError(e).code());
+            }
+            Ok(files) => {
+                for f in &files {
+                    println!("imported: {}", f.display());
+                }
+                println!("{} files imported", files.len());
+            }
+        }
+    } else if let Some(Command::Db(ref opts)) = cli_cfg.command {
+        let command = opts.command.as_ref().expect("db subcommand is required");
+        if let Err(e) = database(&cli_cfg.config, command) {
+            println!("{:#}", e);
+            exit(ExitStatus::StartError(e).code());
+        }
+    } else if let Some(Command::Explain(ref opts)) = cli_cfg.command {
+        if let Err(e) = explain(&opts.file) {
+            println!("{:#}", e);
+            exit(ExitStatus::StartError(e).code());
+        }
+    } else if let Some(Command::Inspect(ref opts)) = cli_cfg.command {
+        if let Err(e) = inspect(opts) {
+            println!("{:#}", e);
+            exit(ExitStatus::StartError(e).code());
+        }
     } else {
         let reload_handle = match init_logger() {
             Ok(handle) => handle,
@@ -55,3 +101,185 @@ fn main() {
         }
     }
 }
+
+fn database(cfg_dir: &Path, command: &DbCommand) -> Result<(), Error> {
+    let cfg = Configuration::new(cfg_dir)?;
+    let pool = DbPool::new(&cfg.output.database)?;
+
+    let applied = match command {
+        DbCommand::Init(_) => pool.init_schema()?,
+        DbCommand::Migrate(_) => pool.migrate_schema()?,
+        DbCommand::Check(_) => {
+            let check = pool.check_schema(cfg.output.database.deduplication)?;
+            if !check.tracked {
+                println!("warning: migrations are not recorded in the database, 'relayd db init' records them");
+            }
+            for difference in &check.differences {
+                println!("difference: {}", difference);
+            }
+            check.health()?;
+            println!("Schema: OK");
+            return Ok(());
+        }
+    };
+    for migration in &applied {
+        println!("applied: migration {}", migration);
+    }
+    println!("{} migrations applied", applied.len());
+    Ok(())
+}
+
+/// Reads a possibly compressed file, like received files
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    // Decompression runs on the blocking threadpool
+    tokio::runtime::Builder::new_current_thread()
+        .build()?
+        .block_on(read_compressed_file(path))
+}
+
+fn explain(path: &Path) -> Result<(), Error> {
+    let info = RunInfo::from_str(
+        path.file_name()
+            .and_then(|r| r.to_str())
+            .ok_or_else(|| RudderError::InvalidRunInfo(path.display().to_string()))?,
+    )?;
+    let content = String::from_utf8(read_file(path)?)?;
+
+    let mut reader = RunLogReader::new(info, &content, usize::MAX);
+    let mut reports = 0;
+    let mut result = Ok(());
+    for batch in &mut reader {
+        match batch {
+            Ok(batch) => reports += batch.len(),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+
+    for diagnostic in &reader.diagnostics {
+        println!("{}", explain_diagnostic(&content, diagnostic));
+    }
+    result?;
+    println!(
+        "{} reports parsed, {} parts rejected, {} run",
+        reports,
+        reader.diagnostics.len(),
+        match reader.log_type() {
+            RunLogType::Complete => "complete",
+            RunLogType::Partial => "partial",
+        }
+    );
+    Ok(())
+}
+
+/// Runs the checks done on received runlogs, printing a verdict for each step.
+///
+/// Stops at the first blocking failure.
+fn inspect(opts: &InspectOptions) -> Result<(), Error> {
+    let info = RunInfo::try_from(opts.file.as_path())?;
+    println!("run: {}", info);
+
+    let content = verdict("decompression", read_file(&opts.file))?;
+    println!("  {} bytes", content.len());
+
+    // Our own id is only used for relay relationships, which are not checked here
+    let nodes = NodesList::new(
+        "root".to_string(),
+        &opts.nodes_list,
+        Some(&opts.certificates),
+    )?;
+    verdict(
+        "node known",
+        if nodes.is_subnode(&info.node_id) {
+            Ok(())
+        } else {
+            Err(RudderError::UnknownNode(info.node_id.clone()).into())
+        },
+    )?;
+    let signed_runlog = verdict(
+        "signature",
+        nodes
+            .certs(&info.node_id)
+            .and_then(|certs| signature(&content, certs)),
+    )?;
+
+    let mut reader = RunLogReader::new(info.clone(), &signed_runlog, usize::MAX);
+    let mut reports = 0;
+    let mut result = Ok(());
+    for batch in &mut reader {
+        match batch {
+            Ok(batch) => reports += batch.len(),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    let parse = verdict(
+        "parsing",
+        result.and_then(|_| {
+            if reader.diagnostics.is_empty() {
+                Ok(())
+            } else {
+                Err(anyhow!("{} parts rejected", reader.diagnostics.len()))
+            }
+        }),
+    );
+    for diagnostic in &reader.diagnostics {
+        println!("{}", explain_diagnostic(&signed_runlog, diagnostic));
+    }
+    // Rejected parts are skipped, the remaining reports are still usable
+    if parse.is_err() && reports == 0 {
+        return parse;
+    }
+    println!("  {} reports parsed", reports);
+    println!(
+        "run type: {}",
+        match reader.log_type() {
+            RunLogType::Complete => "complete",
+            RunLogType::Partial => "partial",
+        }
+    );
+
+    let runlog = RunLog::try_from((info, signed_runlog.as_str()))?;
+    println!();
+    match opts.format {
+        InspectFormat::Reports => print!("{}", runlog),
+        InspectFormat::Json => println!("{}", serde_json::to_string_pretty(&runlog)?),
+    }
+    parse
+}
+
+/// Prints the result of an inspection step
+fn verdict<T>(step: &str, result: Result<T, Error>) -> Result<T, Error> {
+    match result {
+        Ok(_) => println!("{}: OK", step),
+        // The error itself is displayed on exit
+        Err(_) => println!("{}: FAILED", step),
+    }
+    result
+}
+
+/// Displays the line containing the failure, pointing at the failing character
+fn explain_diagnostic(content: &str, diagnostic: &ParseDiagnostic) -> String {
+    let line_start = content[..diagnostic.offset]
+        .rfind('\n')
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let line = content[line_start..]
+        .split(['\n', '\r'])
+        .next()
+        .unwrap_or("");
+    let column = content[line_start..diagnostic.offset].chars().count();
+    format!(
+        "line {}, byte {}: {} (in {})\n  | {}\n  | {}^",
+        diagnostic.line,
+        diagnostic.offset,
+        diagnostic.kind,
+        diagnostic.combinator,
+        line,
+        " ".repeat(column)
+    )
+}
@@ -2,7 +2,11 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use lazy_static::lazy_static;
-use prometheus::{Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry};
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
+
+use crate::data::{compliance::ComplianceCounts, report::ParseErrorKind};
 
 lazy_static! {
     pub static ref REGISTRY: Registry = Registry::new();
@@ -26,10 +30,62 @@ lazy_static! {
     // default buckets for now
         Histogram::with_opts(HistogramOpts::new("reports_processing_duration_seconds", "Reports processing")
             .namespace("rudder").subsystem("relayd")).unwrap();
+    pub static ref REPORTS_BATCH_SIZE: Histogram =
+        Histogram::with_opts(HistogramOpts::new("reports_batch_size", "Runlogs processed in a batch")
+            .namespace("rudder").subsystem("relayd")
+            .buckets(prometheus::exponential_buckets(1.0, 2.0, 10).unwrap())).unwrap();
     pub static ref REPORTS_SIZE_BYTES: Histogram =
     // FIXME: useful buckets
         Histogram::with_opts(HistogramOpts::new("reports_size_bytes", "Uncompressed reports size")
             .namespace("rudder").subsystem("relayd")).unwrap();
+    pub static ref REPORTS_PARSE_ERRORS: IntCounterVec =
+        IntCounterVec::new(Opts::new("reports_parse_errors_total", "Runlog parts that could not be parsed")
+            .namespace("rudder").subsystem("relayd"), &["kind"]).unwrap();
+    pub static ref REPORTS_RULES: IntCounterVec =
+        IntCounterVec::new(Opts::new("reports_rules_total", "Reports dropped or modified by filtering rules")
+            .namespace("rudder").subsystem("relayd"), &["action"]).unwrap();
+    pub static ref REPORTS_REDACTIONS: IntCounter =
+        IntCounter::with_opts(Opts::new("reports_redactions_total", "Report messages modified by a redaction pattern")
+            .namespace("rudder").subsystem("relayd")).unwrap();
+    pub static ref REPORTS_OUT_OF_ORDER: IntCounter =
+        IntCounter::with_opts(Opts::new("reports_out_of_order_total", "Runlogs older than the last processed one of their node")
+            .namespace("rudder").subsystem("relayd")).unwrap();
+    // Compliance of the last run of the nodes, summed over all nodes to keep
+    // a bounded number of series
+    pub static ref COMPLIANCE_REPORTS: IntGaugeVec =
+        IntGaugeVec::new(Opts::new("compliance_reports", "Results in the last run of each node, summed over nodes")
+            .namespace("rudder").subsystem("relayd"), &["status"]).unwrap();
+    pub static ref COMPLIANCE_NODES: IntGauge =
+        IntGauge::with_opts(Opts::new("compliance_nodes", "Nodes with a known last run")
+            .namespace("rudder").subsystem("relayd")).unwrap();
+    pub static ref COMPLIANCE_RECENT_NODES: IntGauge =
+        IntGauge::with_opts(Opts::new("compliance_recent_nodes", "Nodes with a run in the last hour")
+            .namespace("rudder").subsystem("relayd")).unwrap();
+    // Retries after transient errors
+    pub static ref RETRY_BACKLOG: IntGaugeVec =
+        IntGaugeVec::new(Opts::new("retry_backlog_files", "Files waiting for a new attempt after a transient error")
+            .namespace("rudder").subsystem("relayd"), &["queue"]).unwrap();
+    pub static ref RETRIES: IntCounterVec =
+        IntCounterVec::new(Opts::new("retries_total", "Attempts scheduled after a transient error")
+            .namespace("rudder").subsystem("relayd"), &["queue"]).unwrap();
+    // Processing workers
+    pub static ref ACTIVE_WORKERS: IntGaugeVec =
+        IntGaugeVec::new(Opts::new("active_workers", "Workers currently processing a file")
+            .namespace("rudder").subsystem("relayd"), &["queue"]).unwrap();
+    // Upstream servers
+    pub static ref UPSTREAM_ACTIVE: IntGaugeVec =
+        IntGaugeVec::new(Opts::new("upstream_active", "Upstream server currently used for forwarding")
+            .namespace("rudder").subsystem("relayd"), &["url"]).unwrap();
+    pub static ref UPSTREAM_FAILOVERS: IntCounter =
+        IntCounter::with_opts(Opts::new("upstream_failovers_total", "Changes of the active upstream server")
+            .namespace("rudder").subsystem("relayd")).unwrap();
+    // Database purge
+    pub static ref DATABASE_PURGED_ROWS: IntCounterVec =
+        IntCounterVec::new(Opts::new("database_purged_rows_total", "Rows removed from the database by the purge")
+            .namespace("rudder").subsystem("relayd"), &["table"]).unwrap();
+    pub static ref DATABASE_PURGE_DURATION: Histogram =
+        Histogram::with_opts(HistogramOpts::new("database_purge_duration_seconds", "Database purge")
+            .namespace("rudder").subsystem("relayd")).unwrap();
     // TODO add:
     //
     // * API: status & endpoint counters
@@ -57,4 +113,65 @@ pub fn register() {
     REGISTRY
         .register(Box::new(REPORTS_PROCESSING_DURATION.clone()))
         .unwrap();
+    REGISTRY
+        .register(Box::new(REPORTS_BATCH_SIZE.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(REPORTS_OUT_OF_ORDER.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(REPORTS_PARSE_ERRORS.clone()))
+        .unwrap();
+    for kind in &ParseErrorKind::ALL {
+        REPORTS_PARSE_ERRORS.with_label_values(&[kind.as_str()]);
+    }
+    REGISTRY.register(Box::new(REPORTS_RULES.clone())).unwrap();
+    REGISTRY
+        .register(Box::new(REPORTS_REDACTIONS.clone()))
+        .unwrap();
+    for action in &["drop", "truncate", "rewrite"] {
+        REPORTS_RULES.with_label_values(&[action]);
+    }
+    //
+    REGISTRY
+        .register(Box::new(COMPLIANCE_REPORTS.clone()))
+        .unwrap();
+    for status in &ComplianceCounts::STATUSES {
+        COMPLIANCE_REPORTS.with_label_values(&[status]);
+    }
+    REGISTRY
+        .register(Box::new(COMPLIANCE_NODES.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(COMPLIANCE_RECENT_NODES.clone()))
+        .unwrap();
+    //
+    REGISTRY.register(Box::new(RETRY_BACKLOG.clone())).unwrap();
+    REGISTRY.register(Box::new(RETRIES.clone())).unwrap();
+    for queue in &["reports", "inventories"] {
+        RETRY_BACKLOG.with_label_values(&[queue]);
+        RETRIES.with_label_values(&[queue]);
+    }
+    //
+    REGISTRY.register(Box::new(ACTIVE_WORKERS.clone())).unwrap();
+    for queue in &["reports", "inventories"] {
+        ACTIVE_WORKERS.with_label_values(&[queue]);
+    }
+    //
+    REGISTRY
+        .register(Box::new(UPSTREAM_ACTIVE.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(UPSTREAM_FAILOVERS.clone()))
+        .unwrap();
+    //
+    REGISTRY
+        .register(Box::new(DATABASE_PURGED_ROWS.clone()))
+        .unwrap();
+    for table in &["ruddersysevents", "reportsexecution", "runlogdigests"] {
+        DATABASE_PURGED_ROWS.with_label_values(&[table]);
+    }
+    REGISTRY
+        .register(Box::new(DATABASE_PURGE_DURATION.clone()))
+        .unwrap();
 }
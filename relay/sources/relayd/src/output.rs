@@ -2,4 +2,7 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 pub mod database;
+pub mod directory;
+pub mod file;
 pub mod upstream;
+pub mod webhook;
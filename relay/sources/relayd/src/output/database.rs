@@ -1,54 +1,176 @@
 // SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
+pub mod migrations;
+pub mod purge;
+pub mod sqlite;
+
+use std::iter::once;
+
+use chrono::{DateTime, FixedOffset, Utc};
 use diesel::{
     insert_into,
-    pg::PgConnection,
+    pg::{Pg, PgConnection},
     prelude::*,
-    r2d2::{ConnectionManager, Pool},
+    r2d2::{ConnectionManager, Pool, R2D2Connection},
+    sql_query,
+    sql_types::{BigInt, Text, Timestamptz},
 };
-use tracing::{debug, error, instrument, trace};
+use tracing::{debug, error, instrument, trace, warn};
 
 use crate::{
-    configuration::main::DatabaseConfig,
+    configuration::main::{DatabaseConfig, DatabasePurgeConfig, RunlogDeduplication},
     data::{
-        report::QueryableReport,
-        runlog::{InsertedRunlog, RunLogType},
+        report::{QueryableReport, Report},
+        runlog::{InsertedRunlog, RunLogReader, RunLogType, RunlogDigest},
         RunLog,
     },
+    error::RudderError,
+    output::database::{
+        migrations::{
+            check_schema, init_schema, migrate_schema, ExpectedTable, Migration, MigrationVersion,
+            SchemaCheck, TableColumn, MIGRATIONS_TABLE, POSTGRES_MIGRATIONS,
+        },
+        purge::{purge, PurgedRows, REPORT_COLUMNS},
+        sqlite::{sqlite_pool, SqlitePool},
+    },
     Error,
 };
 
+/// Tables of the reports database, parametrized by the timestamp SQL type
+/// as PostgreSQL and SQLite use different ones
+macro_rules! reports_schema {
+    ($timestamp:ident) => {
+        table! {
+            use diesel::sql_types::*;
+
+            // Needs to be kept in sync with the database schema
+            ruddersysevents {
+                id -> BigInt,
+                executiondate -> $timestamp,
+                ruleid -> Text,
+                directiveid -> Text,
+                component -> Text,
+                keyvalue -> Nullable<Text>,
+                eventtype -> Nullable<Text>,
+                msg -> Nullable<Text>,
+                policy -> Nullable<Text>,
+                nodeid -> Text,
+                executiontimestamp -> Nullable<$timestamp>,
+                reportid -> Text,
+            }
+        }
+
+        table! {
+            use diesel::sql_types::*;
+
+            // (nodeid, date) is the primary key
+            reportsexecution(nodeid, date) {
+                nodeid -> Text,
+                date -> $timestamp,
+                nodeconfigid -> Nullable<Text>,
+                insertionid -> Nullable<BigInt>,
+                insertiondate -> Nullable<$timestamp>,
+                compliancecomputationdate -> Nullable<$timestamp>,
+            }
+        }
+
+        table! {
+            use diesel::sql_types::*;
+
+            runlogdigests(nodeid, date, digest) {
+                nodeid -> Text,
+                date -> $timestamp,
+                digest -> Text,
+                insertiondate -> Nullable<$timestamp>,
+            }
+        }
+    };
+}
+pub(crate) use reports_schema;
+
 pub mod schema {
-    table! {
-        use diesel::sql_types::*;
+    reports_schema!(Timestamptz);
+}
+
+/// Insertable views of the domain types, shared by both backends so that
+/// `data` does not depend on them
+#[derive(Insertable)]
+#[diesel(table_name = schema::ruddersysevents)]
+#[diesel(table_name = sqlite::schema::ruddersysevents)]
+pub struct NewReport<'a> {
+    executiontimestamp: &'a DateTime<FixedOffset>,
+    ruleid: &'a str,
+    directiveid: &'a str,
+    component: &'a str,
+    keyvalue: &'a str,
+    eventtype: &'a str,
+    msg: &'a str,
+    policy: &'a str,
+    nodeid: &'a str,
+    executiondate: &'a DateTime<FixedOffset>,
+    reportid: &'a str,
+}
+
+impl<'a> From<&'a Report> for NewReport<'a> {
+    fn from(report: &'a Report) -> Self {
+        Self {
+            executiontimestamp: &report.start_datetime,
+            ruleid: &report.rule_id,
+            directiveid: &report.directive_id,
+            component: &report.component,
+            keyvalue: &report.key_value,
+            eventtype: &report.event_type,
+            msg: &report.msg,
+            policy: &report.policy,
+            nodeid: &report.node_id,
+            executiondate: &report.execution_datetime,
+            reportid: &report.report_id,
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::reportsexecution)]
+#[diesel(table_name = sqlite::schema::reportsexecution)]
+pub struct NewExecution<'a> {
+    nodeid: &'a str,
+    date: &'a DateTime<FixedOffset>,
+    nodeconfigid: Option<&'a str>,
+    insertionid: &'a i64,
+    /// `None` inserts the default value, the current timestamp
+    insertiondate: Option<&'a DateTime<FixedOffset>>,
+    compliancecomputationdate: Option<&'a DateTime<FixedOffset>>,
+}
 
-        // Needs to be kept in sync with the database schema
-        ruddersysevents {
-            id -> BigInt,
-            executiondate -> Timestamptz,
-            ruleid -> Text,
-            directiveid -> Text,
-            component -> Text,
-            keyvalue -> Nullable<Text>,
-            eventtype -> Nullable<Text>,
-            msg -> Nullable<Text>,
-            policy -> Nullable<Text>,
-            nodeid -> Text,
-            executiontimestamp -> Nullable<Timestamptz>,
-            reportid -> Text,
+impl<'a> From<&'a InsertedRunlog> for NewExecution<'a> {
+    fn from(runlog: &'a InsertedRunlog) -> Self {
+        Self {
+            nodeid: &runlog.node_id,
+            date: &runlog.date,
+            nodeconfigid: runlog.node_config_id.as_deref(),
+            insertionid: &runlog.insertion_id,
+            insertiondate: runlog.insertion_date.as_ref(),
+            compliancecomputationdate: runlog.compliance_computation_date.as_ref(),
         }
     }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::runlogdigests)]
+#[diesel(table_name = sqlite::schema::runlogdigests)]
+pub struct NewRunlogDigest<'a> {
+    nodeid: &'a str,
+    date: &'a DateTime<FixedOffset>,
+    digest: &'a str,
+}
 
-    table! {
-        // (nodeid, date) is the primary key
-        reportsexecution(nodeid, date) {
-            nodeid -> Text,
-            date -> Timestamptz,
-            nodeconfigid -> Nullable<Text>,
-            insertionid -> Nullable<BigInt>,
-            insertiondate -> Nullable<Timestamptz>,
-            compliancecomputationdate -> Nullable<Timestamptz>,
+impl<'a> From<&'a RunlogDigest> for NewRunlogDigest<'a> {
+    fn from(runlog_digest: &'a RunlogDigest) -> Self {
+        Self {
+            nodeid: &runlog_digest.node_id,
+            date: &runlog_digest.date,
+            digest: &runlog_digest.digest,
         }
     }
 }
@@ -72,29 +194,439 @@ pub fn pg_pool(configuration: &DatabaseConfig) -> Result<PgPool, Error> {
         .build(manager)?)
 }
 
+/// URL prefix selecting the embedded SQLite database, followed by the database file path
+pub const SQLITE_URL_PREFIX: &str = "sqlite://";
+
+/// Connections to the reports database, the backend is selected by the URL
+#[derive(Clone)]
+pub enum DbPool {
+    Postgres(PgPool),
+    /// Embedded database, for small installations and tests
+    Sqlite(SqlitePool),
+}
+
+impl DbPool {
+    pub fn new(configuration: &DatabaseConfig) -> Result<Self, Error> {
+        Ok(match configuration.url.strip_prefix(SQLITE_URL_PREFIX) {
+            Some(path) => DbPool::Sqlite(sqlite_pool(path, configuration.max_pool_size)?),
+            None => DbPool::Postgres(pg_pool(configuration)?),
+        })
+    }
+
+    pub fn ping(&self) -> Result<(), Error> {
+        match self {
+            DbPool::Postgres(pool) => ping(pool),
+            DbPool::Sqlite(pool) => ping(pool),
+        }
+    }
+
+    pub fn check_schema(&self, deduplication: RunlogDeduplication) -> Result<SchemaCheck, Error> {
+        match self {
+            DbPool::Postgres(pool) => check_schema(&mut *pool.get()?, deduplication),
+            DbPool::Sqlite(pool) => check_schema(&mut *pool.get()?, deduplication),
+        }
+    }
+
+    pub fn init_schema(&self) -> Result<Vec<&'static Migration>, Error> {
+        match self {
+            DbPool::Postgres(pool) => init_schema(&mut *pool.get()?),
+            DbPool::Sqlite(pool) => init_schema(&mut *pool.get()?),
+        }
+    }
+
+    pub fn migrate_schema(&self) -> Result<Vec<&'static Migration>, Error> {
+        match self {
+            DbPool::Postgres(pool) => migrate_schema(&mut *pool.get()?),
+            DbPool::Sqlite(pool) => migrate_schema(&mut *pool.get()?),
+        }
+    }
+
+    /// Runlog digests can only be used once the `runlogdigests` table has been
+    /// created, until then duplicates are detected with the first report
+    pub fn deduplication(
+        &self,
+        configured: RunlogDeduplication,
+    ) -> Result<RunlogDeduplication, Error> {
+        if configured == RunlogDeduplication::FirstReport {
+            return Ok(configured);
+        }
+        let has_digests = match self {
+            DbPool::Postgres(pool) => !pool.get()?.table_columns("runlogdigests")?.is_empty(),
+            DbPool::Sqlite(pool) => !pool.get()?.table_columns("runlogdigests")?.is_empty(),
+        };
+        Ok(if has_digests {
+            configured
+        } else {
+            warn!("The 'runlogdigests' table is missing, duplicate runlogs are detected with their first report until 'relayd db migrate' is run and relayd restarted");
+            RunlogDeduplication::FirstReport
+        })
+    }
+
+    pub fn purge(
+        &self,
+        before: DateTime<Utc>,
+        cfg: DatabasePurgeConfig,
+    ) -> Result<PurgedRows, Error> {
+        match self {
+            DbPool::Postgres(pool) => purge(&mut *pool.get()?, before, cfg),
+            DbPool::Sqlite(pool) => purge(&mut *pool.get()?, before, cfg),
+        }
+    }
+
+    pub fn insert_runlog_stream(
+        &self,
+        reader: &mut RunLogReader,
+        runlog_digest: &RunlogDigest,
+        deduplication: RunlogDeduplication,
+        record_execution: bool,
+    ) -> Result<RunlogInsertion, Error> {
+        match self {
+            DbPool::Postgres(pool) => {
+                insert_runlog_stream(pool, reader, runlog_digest, deduplication, record_execution)
+            }
+            DbPool::Sqlite(pool) => {
+                insert_runlog_stream(pool, reader, runlog_digest, deduplication, record_execution)
+            }
+        }
+    }
+
+    pub fn insert_runlogs(
+        &self,
+        runlogs: &[PendingRunlog],
+        deduplication: RunlogDeduplication,
+    ) -> Vec<Result<RunlogInsertion, Error>> {
+        match self {
+            DbPool::Postgres(pool) => insert_runlogs(pool, runlogs, deduplication),
+            DbPool::Sqlite(pool) => insert_runlogs(pool, runlogs, deduplication),
+        }
+    }
+}
+
+/// `ruddersysevents` inserts use 11 parameters per row
+const REPORT_PARAMETERS: usize = 11;
+/// `reportsexecution` inserts use 6 parameters per row
+const RUNLOG_PARAMETERS: usize = 6;
+/// `runlogdigests` inserts use 3 parameters per row
+const DIGEST_PARAMETERS: usize = 3;
+
+/// Columns used to identify an already inserted report
+pub type ReportKey = (
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<DateTime<Utc>>,
+    DateTime<Utc>,
+    String,
+    String,
+    String,
+);
+
+/// Columns used to identify an already inserted digest
+pub type DigestKey = (String, DateTime<Utc>, String);
+
+/// Queries used by runlog insertion, implemented for each supported database.
+///
+/// Duplicate detection, transactions and batching are shared by all backends.
+pub trait ReportsBackend: R2D2Connection + Send + 'static {
+    /// Maximum number of bind parameters in a statement
+    const MAX_BIND_PARAMETERS: usize;
+    /// Schema migrations, in order
+    const MIGRATIONS: &'static [Migration];
+
+    /// Tables of the `table!` definitions
+    fn expected_tables() -> Vec<ExpectedTable>;
+
+    /// Columns of the table in the database, empty if it does not exist
+    fn table_columns(&mut self, table: &str) -> Result<Vec<TableColumn>, Error>;
+
+    fn applied_migrations(&mut self) -> Result<Vec<i32>, Error>;
+
+    /// Checks the reports table can be read
+    fn check_reports(&mut self) -> Result<(), Error>;
+
+    /// Returns the digests that were not there yet, uses the primary key
+    fn record_digests(&mut self, digests: &[&RunlogDigest]) -> Result<Vec<DigestKey>, Error>;
+
+    fn is_report_inserted(&mut self, report: &Report) -> Result<bool, Error>;
+
+    /// Keys of the reports matching the given nodes and run timestamps
+    fn report_keys(
+        &mut self,
+        node_ids: &[&str],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<Vec<ReportKey>, Error>;
+
+    /// Returns the ids of the inserted reports, in insertion order
+    fn insert_reports(&mut self, reports: &[&Report]) -> Result<Vec<i64>, Error>;
+
+    fn insert_executions(&mut self, executions: &[InsertedRunlog]) -> Result<(), Error>;
+
+    /// Removes at most `limit` reports older than `before`, moving them
+    /// to `archivedruddersysevents` when `archive` is set
+    fn purge_reports(
+        &mut self,
+        before: DateTime<Utc>,
+        limit: i64,
+        archive: bool,
+    ) -> Result<usize, Error>;
+
+    /// Removes at most `limit` executions older than `before`
+    fn purge_executions(&mut self, before: DateTime<Utc>, limit: i64) -> Result<usize, Error>;
+
+    /// Removes at most `limit` runlog digests of runs older than `before`
+    fn purge_digests(&mut self, before: DateTime<Utc>, limit: i64) -> Result<usize, Error>;
+}
+
+impl ReportsBackend for PgConnection {
+    /// PostgreSQL limits the number of bind parameters in a statement to 65535
+    const MAX_BIND_PARAMETERS: usize = 65_535;
+    const MIGRATIONS: &'static [Migration] = POSTGRES_MIGRATIONS;
+
+    fn expected_tables() -> Vec<ExpectedTable> {
+        vec![
+            ExpectedTable::new::<Pg, schema::ruddersysevents::table>("ruddersysevents"),
+            ExpectedTable::new::<Pg, schema::reportsexecution::table>("reportsexecution"),
+            ExpectedTable::new::<Pg, schema::runlogdigests::table>("runlogdigests"),
+        ]
+    }
+
+    fn table_columns(&mut self, table: &str) -> Result<Vec<TableColumn>, Error> {
+        Ok(sql_query(
+            "SELECT column_name::text, data_type::text, is_nullable = 'YES' AS nullable
+             FROM information_schema.columns
+             WHERE table_schema = current_schema() AND table_name = $1",
+        )
+        .bind::<Text, _>(table)
+        .load(self)?)
+    }
+
+    fn applied_migrations(&mut self) -> Result<Vec<i32>, Error> {
+        Ok(
+            sql_query(format!("SELECT version FROM {}", MIGRATIONS_TABLE))
+                .load::<MigrationVersion>(self)?
+                .into_iter()
+                .map(|m| m.version)
+                .collect(),
+        )
+    }
+
+    fn check_reports(&mut self) -> Result<(), Error> {
+        use self::schema::ruddersysevents::dsl::*;
+
+        let _ = ruddersysevents.limit(1).load::<QueryableReport>(self)?;
+        Ok(())
+    }
+
+    fn record_digests(&mut self, digests: &[&RunlogDigest]) -> Result<Vec<DigestKey>, Error> {
+        use self::schema::runlogdigests::dsl::*;
+
+        Ok(insert_into(runlogdigests)
+            .values(
+                digests
+                    .iter()
+                    .map(|d| NewRunlogDigest::from(*d))
+                    .collect::<Vec<_>>(),
+            )
+            .on_conflict_do_nothing()
+            .returning((nodeid, date, digest))
+            .get_results(self)?)
+    }
+
+    fn is_report_inserted(&mut self, report: &Report) -> Result<bool, Error> {
+        use self::schema::ruddersysevents::dsl::*;
+
+        Ok(ruddersysevents
+            .filter(
+                component
+                    .eq(&report.component)
+                    .and(nodeid.eq(&report.node_id))
+                    .and(keyvalue.eq(&report.key_value))
+                    .and(eventtype.eq(&report.event_type))
+                    .and(msg.eq(&report.msg))
+                    .and(policy.eq(&report.policy))
+                    .and(executiontimestamp.eq(&report.start_datetime))
+                    .and(executiondate.eq(&report.execution_datetime))
+                    .and(reportid.eq(&report.report_id))
+                    .and(ruleid.eq(&report.rule_id))
+                    .and(directiveid.eq(&report.directive_id)),
+            )
+            .first::<QueryableReport>(self)
+            .optional()?
+            .is_some())
+    }
+
+    fn report_keys(
+        &mut self,
+        node_ids: &[&str],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<Vec<ReportKey>, Error> {
+        use self::schema::ruddersysevents::dsl::*;
+
+        // Uses the (nodeid, executiontimestamp) index
+        Ok(ruddersysevents
+            .select((
+                component,
+                nodeid,
+                keyvalue,
+                eventtype,
+                msg,
+                policy,
+                executiontimestamp,
+                executiondate,
+                reportid,
+                ruleid,
+                directiveid,
+            ))
+            .filter(nodeid.eq_any(node_ids))
+            .filter(executiontimestamp.eq_any(timestamps))
+            .load(self)?)
+    }
+
+    fn insert_reports(&mut self, reports: &[&Report]) -> Result<Vec<i64>, Error> {
+        use self::schema::ruddersysevents::dsl::*;
+
+        // Ids are returned in insertion order
+        Ok(insert_into(ruddersysevents)
+            .values(
+                reports
+                    .iter()
+                    .map(|r| NewReport::from(*r))
+                    .collect::<Vec<_>>(),
+            )
+            .returning(id)
+            .get_results(self)?)
+    }
+
+    fn insert_executions(&mut self, executions: &[InsertedRunlog]) -> Result<(), Error> {
+        use self::schema::reportsexecution::dsl::*;
+
+        insert_into(reportsexecution)
+            .values(
+                executions
+                    .iter()
+                    .map(NewExecution::from)
+                    .collect::<Vec<_>>(),
+            )
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn purge_reports(
+        &mut self,
+        before: DateTime<Utc>,
+        limit: i64,
+        archive: bool,
+    ) -> Result<usize, Error> {
+        // Uses the executiontimestamp index
+        let batch = "SELECT id FROM ruddersysevents WHERE executiontimestamp < $1 LIMIT $2";
+        let query = if archive {
+            format!(
+                "WITH purged AS (DELETE FROM ruddersysevents WHERE id IN ({}) RETURNING *)
+                 INSERT INTO archivedruddersysevents ({}) SELECT {} FROM purged",
+                batch, REPORT_COLUMNS, REPORT_COLUMNS
+            )
+        } else {
+            format!("DELETE FROM ruddersysevents WHERE id IN ({})", batch)
+        };
+        Ok(sql_query(query)
+            .bind::<Timestamptz, _>(before)
+            .bind::<BigInt, _>(limit)
+            .execute(self)?)
+    }
+
+    fn purge_executions(&mut self, before: DateTime<Utc>, limit: i64) -> Result<usize, Error> {
+        Ok(sql_query(
+            "DELETE FROM reportsexecution WHERE (nodeid, date) IN
+             (SELECT nodeid, date FROM reportsexecution WHERE date < $1 LIMIT $2)",
+        )
+        .bind::<Timestamptz, _>(before)
+        .bind::<BigInt, _>(limit)
+        .execute(self)?)
+    }
+
+    fn purge_digests(&mut self, before: DateTime<Utc>, limit: i64) -> Result<usize, Error> {
+        Ok(sql_query(
+            "DELETE FROM runlogdigests WHERE (nodeid, date, digest) IN
+             (SELECT nodeid, date, digest FROM runlogdigests WHERE date < $1 LIMIT $2)",
+        )
+        .bind::<Timestamptz, _>(before)
+        .bind::<BigInt, _>(limit)
+        .execute(self)?)
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum RunlogInsertion {
     Inserted,
     AlreadyThere,
 }
 
-pub fn ping(pool: &PgPool) -> Result<(), Error> {
-    use self::schema::ruddersysevents::dsl::*;
-    let connection = &mut *pool.get()?;
+pub fn ping<C: ReportsBackend>(pool: &Pool<ConnectionManager<C>>) -> Result<(), Error> {
+    pool.get()?.check_reports()
+}
 
-    let _ = ruddersysevents
-        .limit(1)
-        .load::<QueryableReport>(connection)?;
-    Ok(())
+/// Duplicate detection, records the digest of new runlogs
+fn is_inserted<C: ReportsBackend>(
+    connection: &mut C,
+    deduplication: RunlogDeduplication,
+    runlog_digest: &RunlogDigest,
+    first_report: &Report,
+) -> Result<bool, Error> {
+    Ok(match deduplication {
+        RunlogDeduplication::Digest => !record_digest(connection, runlog_digest)?,
+        RunlogDeduplication::DigestAndFirstReport => {
+            !record_digest(connection, runlog_digest)?
+                || is_first_report_inserted(connection, first_report)?
+        }
+        RunlogDeduplication::FirstReport => is_first_report_inserted(connection, first_report)?,
+    })
 }
 
-#[instrument(name = "database", level = "debug", skip(pool))]
-pub fn insert_runlog(pool: &PgPool, runlog: &RunLog) -> Result<RunlogInsertion, Error> {
-    use self::schema::{
-        reportsexecution::dsl::*,
-        ruddersysevents::dsl::{nodeid, *},
-    };
+/// Returns false if the digest was already there
+fn record_digest<C: ReportsBackend>(
+    connection: &mut C,
+    runlog_digest: &RunlogDigest,
+) -> Result<bool, Error> {
+    trace!("Recording digest {}", runlog_digest.digest);
+    Ok(connection.record_digests(&[runlog_digest])?.len() == 1)
+}
 
+/// Duplicate detection, based on the first report of the runlog
+fn is_first_report_inserted<C: ReportsBackend>(
+    connection: &mut C,
+    first_report: &Report,
+) -> Result<bool, Error> {
+    trace!(
+        "Checking if first report {} is in the database",
+        first_report
+    );
+    connection.is_report_inserted(first_report)
+}
+
+/// Inserts the reports with as few statements as the backend allows,
+/// returns their ids in insertion order
+fn insert_reports<C: ReportsBackend>(
+    connection: &mut C,
+    reports: &[&Report],
+) -> Result<Vec<i64>, Error> {
+    let mut ids = Vec::with_capacity(reports.len());
+    for chunk in reports.chunks(C::MAX_BIND_PARAMETERS / REPORT_PARAMETERS) {
+        ids.extend(connection.insert_reports(chunk)?);
+    }
+    Ok(ids)
+}
+
+#[instrument(name = "database", level = "debug", skip(pool))]
+pub fn insert_runlog<C: ReportsBackend>(
+    pool: &Pool<ConnectionManager<C>>,
+    runlog: &RunLog,
+    runlog_digest: &RunlogDigest,
+    deduplication: RunlogDeduplication,
+    record_execution: bool,
+) -> Result<RunlogInsertion, Error> {
     let connection = &mut *pool.get()?;
 
     let first_report = runlog
@@ -102,45 +634,25 @@ pub fn insert_runlog(pool: &PgPool, runlog: &RunLog) -> Result<RunlogInsertion,
         .first()
         .expect("a runlog should never be empty");
 
-    trace!(
-        "Checking if first report {} is in the database",
-        first_report
-    );
     connection.transaction::<_, Error, _>(|connection| {
-        let new_runlog = ruddersysevents
-            .filter(
-                component
-                    .eq(&first_report.component)
-                    .and(nodeid.eq(&first_report.node_id))
-                    .and(keyvalue.eq(&first_report.key_value))
-                    .and(eventtype.eq(&first_report.event_type))
-                    .and(msg.eq(&first_report.msg))
-                    .and(policy.eq(&first_report.policy))
-                    .and(executiontimestamp.eq(&first_report.start_datetime))
-                    .and(executiondate.eq(&first_report.execution_datetime))
-                    .and(reportid.eq(&first_report.report_id))
-                    .and(ruleid.eq(&first_report.rule_id))
-                    .and(directiveid.eq(&first_report.directive_id)),
-            )
-            .first::<QueryableReport>(connection)
-            .optional()?
-            .is_none();
+        let new_runlog = !is_inserted(connection, deduplication, runlog_digest, first_report)?;
 
         if new_runlog {
             trace!("Inserting runlog {:#?}", runlog);
-            let report_id = insert_into(ruddersysevents)
-                .values(&runlog.reports)
-                .get_results::<QueryableReport>(connection)?
-                .get(0)
-                .expect("inserted runlog cannot be empty")
-                .id;
+            let reports: Vec<&Report> = runlog.reports.iter().collect();
+            let report_id = *insert_reports(connection, &reports)?
+                .first()
+                .expect("inserted runlog cannot be empty");
 
             // Only insert full run logs into `reportsexecution`
-            if runlog.log_type() == RunLogType::Complete {
+            if !record_execution {
+                debug!(
+                    "The {} runlog was not inserted into 'reportsexecution' as it is older than the last one of the node",
+                    runlog.info
+                );
+            } else if runlog.log_type() == RunLogType::Complete {
                 let runlog_info = InsertedRunlog::new(runlog, report_id);
-                insert_into(reportsexecution)
-                    .values(runlog_info)
-                    .execute(connection)?;
+                connection.insert_executions(&[runlog_info])?;
             } else {
                 debug!(
                     "The {} runlog was not inserted into 'reportsexecution' as it was not complete",
@@ -163,14 +675,286 @@ pub fn insert_runlog(pool: &PgPool, runlog: &RunLog) -> Result<RunlogInsertion,
     })
 }
 
+/// Insert a runlog as it is parsed, without loading all its reports in memory.
+///
+/// Everything is done in a single transaction, so an invalid report anywhere in the
+/// runlog leads to no insertion at all, like with `insert_runlog`.
+#[instrument(name = "database_stream", level = "debug", skip(pool, reader, runlog_digest), fields(runlog = %reader.info))]
+pub fn insert_runlog_stream<C: ReportsBackend>(
+    pool: &Pool<ConnectionManager<C>>,
+    reader: &mut RunLogReader,
+    runlog_digest: &RunlogDigest,
+    deduplication: RunlogDeduplication,
+    record_execution: bool,
+) -> Result<RunlogInsertion, Error> {
+    let connection = &mut *pool.get()?;
+
+    connection.transaction::<_, Error, _>(|connection| {
+        let first_batch = match reader.next() {
+            Some(batch) => batch?,
+            None => return Err(RudderError::EmptyRunlog.into()),
+        };
+
+        if is_inserted(connection, deduplication, runlog_digest, &first_batch[0])? {
+            error!(
+                "The {} runlog was already there, skipping insertion",
+                reader.info
+            );
+            debug!(
+                "The report that was already present in database is: {}",
+                first_batch[0]
+            );
+            return Ok(RunlogInsertion::AlreadyThere);
+        }
+
+        let mut first_id = None;
+        let mut inserted = 0;
+        for batch in once(Ok(first_batch)).chain(&mut *reader) {
+            let batch = batch?;
+            let reports: Vec<&Report> = batch.iter().collect();
+            let ids = insert_reports(connection, &reports)?;
+            first_id = first_id.or_else(|| ids.first().copied());
+            inserted += batch.len();
+            trace!("Inserted {} reports", inserted);
+        }
+        let report_id = first_id.expect("inserted runlog cannot be empty");
+
+        // Only insert full run logs into `reportsexecution`
+        if !record_execution {
+            debug!(
+                "The {} runlog was not inserted into 'reportsexecution' as it is older than the last one of the node",
+                reader.info
+            );
+        } else if reader.log_type() == RunLogType::Complete {
+            let runlog_info = reader
+                .inserted_runlog(report_id)
+                .expect("inserted runlog cannot be empty");
+            connection.insert_executions(&[runlog_info])?;
+        } else {
+            debug!(
+                "The {} runlog was not inserted into 'reportsexecution' as it was not complete",
+                reader.info
+            );
+        }
+
+        Ok(RunlogInsertion::Inserted)
+    })
+}
+
+fn is_same_report(key: &ReportKey, report: &Report) -> bool {
+    let (
+        r_component,
+        r_nodeid,
+        r_keyvalue,
+        r_eventtype,
+        r_msg,
+        r_policy,
+        r_executiontimestamp,
+        r_executiondate,
+        r_reportid,
+        r_ruleid,
+        r_directiveid,
+    ) = key;
+    *r_component == report.component
+        && *r_nodeid == report.node_id
+        && r_keyvalue.as_ref() == Some(&report.key_value)
+        && r_eventtype.as_ref() == Some(&report.event_type)
+        && r_msg.as_ref() == Some(&report.msg)
+        && r_policy.as_ref() == Some(&report.policy)
+        && *r_executiontimestamp == Some(report.start_datetime.with_timezone(&Utc))
+        && *r_executiondate == report.execution_datetime
+        && *r_reportid == report.report_id
+        && *r_ruleid == report.rule_id
+        && *r_directiveid == report.directive_id
+}
+
+/// A parsed runlog waiting for insertion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingRunlog {
+    pub runlog: RunLog,
+    pub digest: RunlogDigest,
+    /// Insert it into `reportsexecution` if complete
+    pub record_execution: bool,
+}
+
+/// Insert several runlogs with a single transaction and multi-row inserts.
+///
+/// Returns a result for each runlog, in the same order. The duplicate detection is the
+/// same as in `insert_runlog`, but done with a single query for the whole batch.
+/// If the batch insertion fails, the runlogs are inserted one by one to isolate
+/// the faulty ones.
+#[instrument(name = "database_batch", level = "debug", skip(pool, runlogs), fields(runlogs = runlogs.len()))]
+pub fn insert_runlogs<C: ReportsBackend>(
+    pool: &Pool<ConnectionManager<C>>,
+    runlogs: &[PendingRunlog],
+    deduplication: RunlogDeduplication,
+) -> Vec<Result<RunlogInsertion, Error>> {
+    match insert_runlogs_batch(pool, runlogs, deduplication) {
+        Ok(res) => res.into_iter().map(Ok).collect(),
+        // Nothing to isolate
+        Err(e) if runlogs.len() == 1 => vec![Err(e)],
+        Err(e) => {
+            warn!(
+                "Batch insertion of {} runlogs failed, falling back to individual insertions: {}",
+                runlogs.len(),
+                e
+            );
+            runlogs
+                .iter()
+                .map(|p| {
+                    insert_runlog(
+                        pool,
+                        &p.runlog,
+                        &p.digest,
+                        deduplication,
+                        p.record_execution,
+                    )
+                })
+                .collect()
+        }
+    }
+}
+
+fn is_same_digest(key: &DigestKey, runlog_digest: &RunlogDigest) -> bool {
+    let (r_nodeid, r_date, r_digest) = key;
+    *r_nodeid == runlog_digest.node_id
+        && *r_date == runlog_digest.date
+        && *r_digest == runlog_digest.digest
+}
+
+fn insert_runlogs_batch<C: ReportsBackend>(
+    pool: &Pool<ConnectionManager<C>>,
+    runlogs: &[PendingRunlog],
+    deduplication: RunlogDeduplication,
+) -> Result<Vec<RunlogInsertion>, Error> {
+    let connection = &mut *pool.get()?;
+
+    let first_reports: Vec<&Report> = runlogs
+        .iter()
+        .map(|p| {
+            p.runlog
+                .reports
+                .first()
+                .expect("a runlog should never be empty")
+        })
+        .collect();
+
+    connection.transaction::<_, Error, _>(|connection| {
+        // Digests that were not there yet, duplicates in the batch are only returned once
+        let mut recorded: Vec<DigestKey> = vec![];
+        if deduplication != RunlogDeduplication::FirstReport {
+            trace!("Recording runlog digests");
+            let digests: Vec<&RunlogDigest> = runlogs.iter().map(|p| &p.digest).collect();
+            for chunk in digests.chunks(C::MAX_BIND_PARAMETERS / DIGEST_PARAMETERS) {
+                recorded.extend(connection.record_digests(chunk)?);
+            }
+        }
+
+        let mut existing: Vec<ReportKey> = vec![];
+        if deduplication != RunlogDeduplication::Digest {
+            let node_ids: Vec<&str> = first_reports.iter().map(|r| r.node_id.as_str()).collect();
+            let timestamps: Vec<DateTime<Utc>> = first_reports
+                .iter()
+                .map(|r| r.start_datetime.with_timezone(&Utc))
+                .collect();
+            trace!("Checking if first reports are in the database");
+            existing = connection.report_keys(&node_ids, &timestamps)?;
+        }
+
+        let mut results = Vec::with_capacity(runlogs.len());
+        let mut new_runlogs: Vec<&PendingRunlog> = vec![];
+        for (pending, first_report) in runlogs.iter().zip(first_reports.iter()) {
+            let runlog_digest = &pending.digest;
+            let already_there = match deduplication {
+                RunlogDeduplication::Digest => {
+                    !recorded.iter().any(|k| is_same_digest(k, runlog_digest))
+                        // Same runlog twice in the batch
+                        || new_runlogs.iter().any(|p| &p.digest == runlog_digest)
+                }
+                RunlogDeduplication::DigestAndFirstReport => {
+                    !recorded.iter().any(|k| is_same_digest(k, runlog_digest))
+                        || existing.iter().any(|k| is_same_report(k, first_report))
+                        || new_runlogs.iter().any(|p| {
+                            &p.digest == runlog_digest || &p.runlog.reports[0] == *first_report
+                        })
+                }
+                RunlogDeduplication::FirstReport => {
+                    existing.iter().any(|k| is_same_report(k, first_report))
+                        || new_runlogs
+                            .iter()
+                            .any(|p| &p.runlog.reports[0] == *first_report)
+                }
+            };
+            if already_there {
+                error!(
+                    "The {} runlog was already there, skipping insertion",
+                    pending.runlog.info
+                );
+                debug!(
+                    "The report that was already present in database is: {}",
+                    first_report
+                );
+                results.push(RunlogInsertion::AlreadyThere);
+            } else {
+                new_runlogs.push(pending);
+                results.push(RunlogInsertion::Inserted);
+            }
+        }
+
+        // Ids are returned in insertion order
+        let reports: Vec<&Report> = new_runlogs
+            .iter()
+            .flat_map(|p| &p.runlog.reports)
+            .collect();
+        let ids = insert_reports(connection, &reports)?;
+
+        // Only insert full run logs into `reportsexecution`
+        let mut offset = 0;
+        let mut executions = vec![];
+        for PendingRunlog {
+            runlog,
+            record_execution,
+            ..
+        } in &new_runlogs
+        {
+            if !record_execution {
+                debug!(
+                    "The {} runlog was not inserted into 'reportsexecution' as it is older than the last one of the node",
+                    runlog.info
+                );
+            } else if runlog.log_type() == RunLogType::Complete {
+                executions.push(InsertedRunlog::new(runlog, ids[offset]));
+            } else {
+                debug!(
+                    "The {} runlog was not inserted into 'reportsexecution' as it was not complete",
+                    runlog.info
+                );
+            }
+            offset += runlog.reports.len();
+        }
+        for chunk in executions.chunks(C::MAX_BIND_PARAMETERS / RUNLOG_PARAMETERS) {
+            connection.insert_executions(chunk)?;
+        }
+
+        debug!(
+            "Inserted {} runlogs ({} reports) in batch",
+            new_runlogs.len(),
+            reports.len()
+        );
+        Ok(results)
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use diesel::dsl::count;
+    use diesel::dsl::{count, count_star};
 
     use crate::{
         configuration::Secret,
         data::report::QueryableReport,
-        output::database::schema::{reportsexecution::dsl::*, ruddersysevents::dsl::*},
+        output::database::schema::{
+            reportsexecution::dsl::*, ruddersysevents::dsl::*, runlogdigests::dsl::runlogdigests,
+        },
     };
 
     use super::*;
@@ -180,6 +964,8 @@ mod tests {
             url: "postgres://rudderreports:@postgres/rudder".to_string(),
             password: Secret::new("PASSWORD".to_string()),
             max_pool_size: 5,
+            deduplication: RunlogDeduplication::Digest,
+            purge: Default::default(),
         };
         pg_pool(&db_config).unwrap()
     }
@@ -191,6 +977,7 @@ mod tests {
 
         diesel::delete(ruddersysevents).execute(db).unwrap();
         diesel::delete(reportsexecution).execute(db).unwrap();
+        diesel::delete(runlogdigests).execute(db).unwrap();
 
         let results = ruddersysevents
             .limit(1)
@@ -198,15 +985,23 @@ mod tests {
             .unwrap();
         assert_eq!(results.len(), 0);
 
-        let runlog = RunLog::new(
-            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
-        )
-        .unwrap();
+        let path =
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let content = std::fs::read_to_string(path).unwrap();
+        let runlog = RunLog::new(path).unwrap();
+        let runlog_digest = RunlogDigest::new(&runlog.info, &content);
 
         // Test inserting the runlog
 
         assert_eq!(
-            insert_runlog(&pool, &runlog).unwrap(),
+            insert_runlog(
+                &pool,
+                &runlog,
+                &runlog_digest,
+                RunlogDeduplication::Digest,
+                true
+            )
+            .unwrap(),
             RunlogInsertion::Inserted
         );
 
@@ -225,7 +1020,14 @@ mod tests {
         // Test inserting twice the same runlog
 
         assert_eq!(
-            insert_runlog(&pool, &runlog).unwrap(),
+            insert_runlog(
+                &pool,
+                &runlog,
+                &runlog_digest,
+                RunlogDeduplication::Digest,
+                true
+            )
+            .unwrap(),
             RunlogInsertion::AlreadyThere
         );
 
@@ -240,5 +1042,352 @@ mod tests {
             .first(db)
             .unwrap();
         assert_eq!(results, 1);
+
+        // Test batch insertion, with a duplicate in the batch and one in the database
+
+        diesel::delete(ruddersysevents).execute(db).unwrap();
+        diesel::delete(reportsexecution).execute(db).unwrap();
+        diesel::delete(runlogdigests).execute(db).unwrap();
+
+        let other_path =
+            "tests/files/runlogs/2017-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let other_runlog = RunLog::new(other_path).unwrap();
+        let other_digest = RunlogDigest::new(
+            &other_runlog.info,
+            &std::fs::read_to_string(other_path).unwrap(),
+        );
+        assert_eq!(
+            insert_runlog(
+                &pool,
+                &other_runlog,
+                &other_digest,
+                RunlogDeduplication::Digest,
+                true
+            )
+            .unwrap(),
+            RunlogInsertion::Inserted
+        );
+
+        let pending = |runlog: &RunLog, digest: &RunlogDigest| PendingRunlog {
+            runlog: runlog.clone(),
+            digest: digest.clone(),
+            record_execution: true,
+        };
+        let entries = [
+            pending(&runlog, &runlog_digest),
+            pending(&other_runlog, &other_digest),
+            pending(&runlog, &runlog_digest),
+        ];
+        let results = insert_runlogs(&pool, &entries, RunlogDeduplication::Digest);
+        assert_eq!(
+            results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            vec![
+                RunlogInsertion::Inserted,
+                RunlogInsertion::AlreadyThere,
+                RunlogInsertion::AlreadyThere
+            ]
+        );
+
+        let results: i64 = ruddersysevents.select(count(id)).first(db).unwrap();
+        assert_eq!(
+            results,
+            (runlog.reports.len() + other_runlog.reports.len()) as i64
+        );
+
+        let results: i64 = reportsexecution
+            .select(count(insertionid))
+            .first(db)
+            .unwrap();
+        assert_eq!(results, 2);
+
+        // Test streaming insertion
+
+        diesel::delete(ruddersysevents).execute(db).unwrap();
+        diesel::delete(reportsexecution).execute(db).unwrap();
+        diesel::delete(runlogdigests).execute(db).unwrap();
+
+        assert_eq!(
+            insert_runlog_stream(
+                &pool,
+                &mut RunLogReader::new(runlog.info.clone(), &content, 10),
+                &runlog_digest,
+                RunlogDeduplication::Digest,
+                true
+            )
+            .unwrap(),
+            RunlogInsertion::Inserted
+        );
+        assert_eq!(
+            insert_runlog_stream(
+                &pool,
+                &mut RunLogReader::new(runlog.info.clone(), &content, 10),
+                &runlog_digest,
+                RunlogDeduplication::Digest,
+                true
+            )
+            .unwrap(),
+            RunlogInsertion::AlreadyThere
+        );
+        // Same content as a non-streaming insertion
+        assert_eq!(
+            insert_runlog(
+                &pool,
+                &runlog,
+                &runlog_digest,
+                RunlogDeduplication::Digest,
+                true
+            )
+            .unwrap(),
+            RunlogInsertion::AlreadyThere
+        );
+
+        // Runlog inserted before digests were recorded
+        diesel::delete(runlogdigests).execute(db).unwrap();
+        assert_eq!(
+            insert_runlogs(
+                &pool,
+                &entries[0..1],
+                RunlogDeduplication::DigestAndFirstReport
+            )
+            .pop()
+            .unwrap()
+            .unwrap(),
+            RunlogInsertion::AlreadyThere
+        );
+        let results: i64 = runlogdigests.select(count_star()).first(db).unwrap();
+        assert_eq!(results, 1);
+        assert_eq!(
+            insert_runlog(
+                &pool,
+                &runlog,
+                &runlog_digest,
+                RunlogDeduplication::Digest,
+                true
+            )
+            .unwrap(),
+            RunlogInsertion::AlreadyThere
+        );
+        assert_eq!(
+            insert_runlog(
+                &pool,
+                &runlog,
+                &runlog_digest,
+                RunlogDeduplication::FirstReport,
+                true
+            )
+            .unwrap(),
+            RunlogInsertion::AlreadyThere
+        );
+
+        let results = ruddersysevents
+            .limit(100)
+            .load::<QueryableReport>(db)
+            .unwrap();
+        assert_eq!(results.len(), 72);
+
+        let results: i64 = reportsexecution
+            .select(count(insertionid))
+            .first(db)
+            .unwrap();
+        assert_eq!(results, 1);
+
+        // Test insertion without execution
+
+        diesel::delete(ruddersysevents).execute(db).unwrap();
+        diesel::delete(reportsexecution).execute(db).unwrap();
+        diesel::delete(runlogdigests).execute(db).unwrap();
+
+        assert_eq!(
+            insert_runlog(
+                &pool,
+                &other_runlog,
+                &other_digest,
+                RunlogDeduplication::Digest,
+                false
+            )
+            .unwrap(),
+            RunlogInsertion::Inserted
+        );
+
+        let results: i64 = ruddersysevents.select(count(id)).first(db).unwrap();
+        assert_eq!(results, other_runlog.reports.len() as i64);
+
+        let results: i64 = reportsexecution
+            .select(count(insertionid))
+            .first(db)
+            .unwrap();
+        assert_eq!(results, 0);
+    }
+
+    #[test]
+    fn it_inserts_runlog_into_sqlite() {
+        use self::sqlite::schema::{
+            reportsexecution::dsl as sqlite_executions, ruddersysevents::dsl as sqlite_reports,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_config = DatabaseConfig {
+            url: format!(
+                "{}{}",
+                SQLITE_URL_PREFIX,
+                dir.path().join("reports.sqlite").display()
+            ),
+            ..Default::default()
+        };
+        let pool = match DbPool::new(&db_config).unwrap() {
+            DbPool::Sqlite(pool) => pool,
+            DbPool::Postgres(_) => panic!("expected a SQLite database"),
+        };
+        ping(&pool).unwrap();
+
+        let path =
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let content = std::fs::read_to_string(path).unwrap();
+        let runlog = RunLog::new(path).unwrap();
+        let runlog_digest = RunlogDigest::new(&runlog.info, &content);
+        let other_path =
+            "tests/files/runlogs/2017-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let other_runlog = RunLog::new(other_path).unwrap();
+        let other_digest = RunlogDigest::new(
+            &other_runlog.info,
+            &std::fs::read_to_string(other_path).unwrap(),
+        );
+
+        assert_eq!(
+            insert_runlog_stream(
+                &pool,
+                &mut RunLogReader::new(runlog.info.clone(), &content, 10),
+                &runlog_digest,
+                RunlogDeduplication::Digest,
+                true
+            )
+            .unwrap(),
+            RunlogInsertion::Inserted
+        );
+        assert_eq!(
+            insert_runlog(
+                &pool,
+                &runlog,
+                &runlog_digest,
+                RunlogDeduplication::DigestAndFirstReport,
+                true
+            )
+            .unwrap(),
+            RunlogInsertion::AlreadyThere
+        );
+
+        let pending = |runlog: &RunLog, digest: &RunlogDigest| PendingRunlog {
+            runlog: runlog.clone(),
+            digest: digest.clone(),
+            record_execution: true,
+        };
+        let entries = [
+            pending(&other_runlog, &other_digest),
+            pending(&runlog, &runlog_digest),
+            pending(&other_runlog, &other_digest),
+        ];
+        let results = insert_runlogs(&pool, &entries, RunlogDeduplication::FirstReport);
+        assert_eq!(
+            results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            vec![
+                RunlogInsertion::Inserted,
+                RunlogInsertion::AlreadyThere,
+                RunlogInsertion::AlreadyThere
+            ]
+        );
+
+        let db = &mut *pool.get().unwrap();
+        let results: i64 = sqlite_reports::ruddersysevents
+            .select(count(sqlite_reports::id))
+            .first(db)
+            .unwrap();
+        assert_eq!(
+            results,
+            (runlog.reports.len() + other_runlog.reports.len()) as i64
+        );
+        // Executions point to the first report of each runlog
+        let insertions: Vec<Option<i64>> = sqlite_executions::reportsexecution
+            .select(sqlite_executions::insertionid)
+            .order(sqlite_executions::insertionid)
+            .load(db)
+            .unwrap();
+        assert_eq!(
+            insertions,
+            vec![Some(1), Some(runlog.reports.len() as i64 + 1)]
+        );
+    }
+
+    #[test]
+    fn it_deduplicates_runlogs_with_offset_into_sqlite() {
+        use self::sqlite::schema::ruddersysevents::dsl as sqlite_reports;
+
+        let dir = tempfile::tempdir().unwrap();
+        let pool = sqlite::sqlite_pool(
+            dir.path().join("reports.sqlite").to_str().unwrap(),
+            DatabaseConfig::default().max_pool_size,
+        )
+        .unwrap();
+
+        // Reports are timestamped with a +02:00 offset
+        let path =
+            "tests/files/runlogs/2020-04-07T11_20_58+00_00@e745a140-40bc-4b86-b6dc-084488fc906b.log";
+        let runlog = RunLog::new(path).unwrap();
+        assert_eq!(
+            runlog.reports[0].start_datetime.offset().local_minus_utc(),
+            2 * 3600
+        );
+        let runlog_digest =
+            RunlogDigest::new(&runlog.info, &std::fs::read_to_string(path).unwrap());
+        let entries = [PendingRunlog {
+            runlog: runlog.clone(),
+            digest: runlog_digest.clone(),
+            record_execution: true,
+        }];
+
+        assert_eq!(
+            insert_runlog(
+                &pool,
+                &runlog,
+                &runlog_digest,
+                RunlogDeduplication::FirstReport,
+                true
+            )
+            .unwrap(),
+            RunlogInsertion::Inserted
+        );
+        for deduplication in [
+            RunlogDeduplication::FirstReport,
+            RunlogDeduplication::DigestAndFirstReport,
+        ] {
+            assert_eq!(
+                insert_runlog(&pool, &runlog, &runlog_digest, deduplication, true).unwrap(),
+                RunlogInsertion::AlreadyThere
+            );
+            assert_eq!(
+                insert_runlogs(&pool, &entries, deduplication)
+                    .into_iter()
+                    .map(|r| r.unwrap())
+                    .collect::<Vec<_>>(),
+                vec![RunlogInsertion::AlreadyThere]
+            );
+        }
+
+        // Stored in UTC
+        let db = &mut *pool.get().unwrap();
+        let timestamps: Vec<Option<DateTime<Utc>>> = sqlite_reports::ruddersysevents
+            .select(sqlite_reports::executiontimestamp)
+            .distinct()
+            .load(db)
+            .unwrap();
+        assert_eq!(
+            timestamps,
+            vec![Some(runlog.reports[0].start_datetime.with_timezone(&Utc))]
+        );
+        let raw: Vec<String> = sqlite_reports::ruddersysevents
+            .select(diesel::dsl::sql::<Text>("executiontimestamp"))
+            .distinct()
+            .load(db)
+            .unwrap();
+        assert_eq!(raw, vec!["2020-04-07 11:20:57+00:00".to_string()]);
     }
 }
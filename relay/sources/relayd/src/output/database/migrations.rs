@@ -0,0 +1,659 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+//! Creation and verification of the reports database schema.
+//!
+//! Migrations are applied in order and recorded in the `relaydmigrations` table.
+//! They only create missing objects, so that initializing a database created by
+//! Rudder's own schema scripts is harmless.
+
+use std::fmt::{self, Display};
+
+use diesel::{
+    pg::Pg,
+    prelude::*,
+    sql_types::{BigInt, Bool, Integer, Nullable, Text, Timestamptz, TimestamptzSqlite},
+    sqlite::Sqlite,
+};
+use tracing::info;
+
+use crate::{
+    configuration::main::RunlogDeduplication, error::RudderError, output::database::ReportsBackend,
+    Error,
+};
+
+/// Records applied migrations
+pub const MIGRATIONS_TABLE: &str = "relaydmigrations";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    pub version: i32,
+    /// Must not contain quotes
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+impl Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.version, self.description)
+    }
+}
+
+/// Same objects as Rudder's `reportsSchema.sql`
+pub const POSTGRES_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create reports and executions tables",
+        sql: "
+CREATE SEQUENCE IF NOT EXISTS serial START 101;
+
+CREATE TABLE IF NOT EXISTS ruddersysevents (
+  id                 bigint PRIMARY KEY default nextval('serial')
+, executiondate      timestamp with time zone NOT NULL
+, nodeid             text NOT NULL CHECK (nodeid <> '')
+, directiveid        text NOT NULL CHECK (directiveid <> '')
+, ruleid             text NOT NULL CHECK (ruleid <> '')
+, reportid           text NOT NULL CHECK (reportid <> '')
+, component          text NOT NULL CHECK (component <> '')
+, keyvalue           text
+, executiontimestamp timestamp with time zone NOT NULL
+, eventtype          text
+, policy             text
+, msg                text
+);
+
+CREATE INDEX IF NOT EXISTS executiontimestamp_idx ON ruddersysevents (executiontimestamp);
+CREATE INDEX IF NOT EXISTS composite_node_execution_idx ON ruddersysevents (nodeid, executiontimestamp);
+CREATE INDEX IF NOT EXISTS ruleid_idx ON ruddersysevents (ruleid);
+CREATE INDEX IF NOT EXISTS endrun_control_idx ON ruddersysevents (id) WHERE eventtype = 'control' and component = 'end';
+CREATE INDEX IF NOT EXISTS changes_executiontimestamp_idx ON ruddersysevents (executiontimestamp) WHERE eventtype = 'result_repaired';
+
+CREATE TABLE IF NOT EXISTS reportsexecution (
+  nodeid                    text NOT NULL
+, date                      timestamp with time zone NOT NULL
+, nodeconfigid              text
+, insertionid               bigint
+, insertiondate             timestamp default now()
+, compliancecomputationdate timestamp
+, PRIMARY KEY(nodeid, date)
+);
+
+CREATE INDEX IF NOT EXISTS reportsexecution_date_idx ON reportsexecution (date);
+CREATE INDEX IF NOT EXISTS reportsexecution_nodeid_nodeconfigid_idx ON reportsexecution (nodeid, nodeconfigid);
+CREATE INDEX IF NOT EXISTS reportsexecution_uncomputedrun_idx ON reportsexecution (compliancecomputationdate) WHERE compliancecomputationdate IS NULL;
+",
+    },
+    Migration {
+        version: 2,
+        description: "create runlog digests table",
+        sql: "
+CREATE TABLE IF NOT EXISTS runlogdigests (
+  nodeid        text NOT NULL
+, date          timestamp with time zone NOT NULL
+, digest        text NOT NULL
+, insertiondate timestamp with time zone default now()
+, PRIMARY KEY(nodeid, date, digest)
+);
+
+CREATE INDEX IF NOT EXISTS runlogdigests_insertiondate_idx ON runlogdigests (insertiondate);
+",
+    },
+    Migration {
+        version: 3,
+        description: "create archived reports table",
+        sql: "
+CREATE TABLE IF NOT EXISTS archivedruddersysevents (
+  id                 bigint PRIMARY KEY
+, executiondate      timestamp with time zone NOT NULL
+, nodeid             text NOT NULL CHECK (nodeid <> '')
+, directiveid        text NOT NULL CHECK (directiveid <> '')
+, ruleid             text NOT NULL CHECK (ruleid <> '')
+, reportid           text NOT NULL CHECK (reportid <> '')
+, component          text NOT NULL CHECK (component <> '')
+, keyvalue           text
+, executiontimestamp timestamp with time zone NOT NULL
+, eventtype          text
+, policy             text
+, msg                text
+);
+
+CREATE INDEX IF NOT EXISTS executiontimestamp_archived_idx ON archivedruddersysevents (executiontimestamp);
+",
+    },
+];
+
+/// Equivalent of the PostgreSQL migrations
+pub const SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create reports and executions tables",
+        sql: "
+CREATE TABLE IF NOT EXISTS ruddersysevents (
+  id                 INTEGER PRIMARY KEY AUTOINCREMENT
+, executiondate      TEXT NOT NULL
+, nodeid             TEXT NOT NULL CHECK (nodeid <> '')
+, directiveid        TEXT NOT NULL CHECK (directiveid <> '')
+, ruleid             TEXT NOT NULL CHECK (ruleid <> '')
+, reportid           TEXT NOT NULL CHECK (reportid <> '')
+, component          TEXT NOT NULL CHECK (component <> '')
+, keyvalue           TEXT
+, executiontimestamp TEXT NOT NULL
+, eventtype          TEXT
+, policy             TEXT
+, msg                TEXT
+);
+
+CREATE INDEX IF NOT EXISTS executiontimestamp_idx ON ruddersysevents (executiontimestamp);
+CREATE INDEX IF NOT EXISTS composite_node_execution_idx ON ruddersysevents (nodeid, executiontimestamp);
+
+CREATE TABLE IF NOT EXISTS reportsexecution (
+  nodeid                    TEXT NOT NULL
+, date                      TEXT NOT NULL
+, nodeconfigid              TEXT
+, insertionid               INTEGER
+, insertiondate             TEXT DEFAULT CURRENT_TIMESTAMP
+, compliancecomputationdate TEXT
+, PRIMARY KEY(nodeid, date)
+);
+
+CREATE INDEX IF NOT EXISTS reportsexecution_date_idx ON reportsexecution (date);
+",
+    },
+    Migration {
+        version: 2,
+        description: "create runlog digests table",
+        sql: "
+CREATE TABLE IF NOT EXISTS runlogdigests (
+  nodeid        TEXT NOT NULL
+, date          TEXT NOT NULL
+, digest        TEXT NOT NULL
+, insertiondate TEXT DEFAULT CURRENT_TIMESTAMP
+, PRIMARY KEY(nodeid, date, digest)
+);
+",
+    },
+    Migration {
+        version: 3,
+        description: "create archived reports table",
+        sql: "
+CREATE TABLE IF NOT EXISTS archivedruddersysevents (
+  id                 INTEGER PRIMARY KEY
+, executiondate      TEXT NOT NULL
+, nodeid             TEXT NOT NULL CHECK (nodeid <> '')
+, directiveid        TEXT NOT NULL CHECK (directiveid <> '')
+, ruleid             TEXT NOT NULL CHECK (ruleid <> '')
+, reportid           TEXT NOT NULL CHECK (reportid <> '')
+, component          TEXT NOT NULL CHECK (component <> '')
+, keyvalue           TEXT
+, executiontimestamp TEXT NOT NULL
+, eventtype          TEXT
+, policy             TEXT
+, msg                TEXT
+);
+
+CREATE INDEX IF NOT EXISTS executiontimestamp_archived_idx ON archivedruddersysevents (executiontimestamp);
+",
+    },
+];
+
+/// Database type names compatible with a diesel SQL type
+pub trait ColumnType<DB> {
+    /// Lowercase names, as reported by the database
+    const NAMES: &'static [&'static str];
+    const NULLABLE: bool = false;
+}
+
+impl<DB, T: ColumnType<DB>> ColumnType<DB> for Nullable<T> {
+    const NAMES: &'static [&'static str] = T::NAMES;
+    const NULLABLE: bool = true;
+}
+
+impl ColumnType<Pg> for BigInt {
+    const NAMES: &'static [&'static str] = &["bigint"];
+}
+
+impl ColumnType<Pg> for Text {
+    const NAMES: &'static [&'static str] = &["text"];
+}
+
+impl ColumnType<Pg> for Timestamptz {
+    // Rudder's `reportsexecution` uses timestamps without time zone for its
+    // insertion dates, PostgreSQL converts them on insertion
+    const NAMES: &'static [&'static str] =
+        &["timestamp with time zone", "timestamp without time zone"];
+}
+
+impl ColumnType<Sqlite> for BigInt {
+    const NAMES: &'static [&'static str] = &["integer", "bigint"];
+}
+
+impl ColumnType<Sqlite> for Text {
+    const NAMES: &'static [&'static str] = &["text"];
+}
+
+impl ColumnType<Sqlite> for TimestamptzSqlite {
+    const NAMES: &'static [&'static str] = &["text"];
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedColumn {
+    pub name: &'static str,
+    pub types: &'static [&'static str],
+    pub nullable: bool,
+}
+
+impl ExpectedColumn {
+    fn new<DB, C: Column>() -> Self
+    where
+        C::SqlType: ColumnType<DB>,
+    {
+        Self {
+            name: C::NAME,
+            types: <C::SqlType as ColumnType<DB>>::NAMES,
+            nullable: <C::SqlType as ColumnType<DB>>::NULLABLE,
+        }
+    }
+}
+
+/// Columns of a `table!` definition, implemented for the `all_columns` tuples
+pub trait TableColumns<DB> {
+    fn columns() -> Vec<ExpectedColumn>;
+}
+
+macro_rules! table_columns {
+    ($($column:ident),+) => {
+        impl<DB, $($column),+> TableColumns<DB> for ($($column,)+)
+        where
+            $($column: Column, $column::SqlType: ColumnType<DB>,)+
+        {
+            fn columns() -> Vec<ExpectedColumn> {
+                vec![$(ExpectedColumn::new::<DB, $column>()),+]
+            }
+        }
+    };
+}
+
+// Sizes of `runlogdigests`, `reportsexecution` and `ruddersysevents`
+table_columns!(A, B, C, D);
+table_columns!(A, B, C, D, E, F);
+table_columns!(A, B, C, D, E, F, G, H, I, J, K, L);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedTable {
+    pub name: &'static str,
+    pub columns: Vec<ExpectedColumn>,
+}
+
+impl ExpectedTable {
+    pub fn new<DB, T: Table>(name: &'static str) -> Self
+    where
+        T::AllColumns: TableColumns<DB>,
+    {
+        Self {
+            name,
+            columns: T::AllColumns::columns(),
+        }
+    }
+}
+
+/// Column as described by the database
+#[derive(QueryableByName, Debug, Clone, PartialEq, Eq)]
+pub struct TableColumn {
+    #[diesel(sql_type = Text)]
+    pub column_name: String,
+    #[diesel(sql_type = Text)]
+    pub data_type: String,
+    #[diesel(sql_type = Bool)]
+    pub nullable: bool,
+}
+
+#[derive(QueryableByName, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationVersion {
+    #[diesel(sql_type = Integer)]
+    pub version: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaDifference {
+    MissingTable(&'static str),
+    MissingColumn {
+        table: &'static str,
+        column: &'static str,
+    },
+    ColumnType {
+        table: &'static str,
+        column: &'static str,
+        expected: &'static [&'static str],
+        found: String,
+    },
+    /// Nullable columns are not a problem when the definition is not nullable
+    NullableColumn {
+        table: &'static str,
+        column: &'static str,
+    },
+    PendingMigration(Migration),
+}
+
+impl Display for SchemaDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaDifference::MissingTable(table) => write!(f, "missing table '{}'", table),
+            SchemaDifference::MissingColumn { table, column } => {
+                write!(f, "missing column '{}.{}'", table, column)
+            }
+            SchemaDifference::ColumnType {
+                table,
+                column,
+                expected,
+                found,
+            } => write!(
+                f,
+                "column '{}.{}' has type '{}', expected '{}'",
+                table,
+                column,
+                found,
+                expected.join("' or '")
+            ),
+            SchemaDifference::NullableColumn { table, column } => {
+                write!(f, "column '{}.{}' should be not null", table, column)
+            }
+            SchemaDifference::PendingMigration(migration) => {
+                write!(f, "migration {} is not applied", migration)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaCheck {
+    /// Applied migrations are recorded in the database
+    pub tracked: bool,
+    pub differences: Vec<SchemaDifference>,
+}
+
+impl SchemaCheck {
+    /// Fails when the schema differs from the expected one
+    pub fn health(&self) -> Result<(), Error> {
+        if self.differences.is_empty() {
+            Ok(())
+        } else {
+            Err(RudderError::InvalidDatabaseSchema(
+                self.differences
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )
+            .into())
+        }
+    }
+}
+
+fn is_tracked<C: ReportsBackend>(connection: &mut C) -> Result<bool, Error> {
+    Ok(!connection.table_columns(MIGRATIONS_TABLE)?.is_empty())
+}
+
+fn pending_migrations<C: ReportsBackend>(
+    connection: &mut C,
+) -> Result<Vec<&'static Migration>, Error> {
+    let applied = connection.applied_migrations()?;
+    Ok(C::MIGRATIONS
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .collect())
+}
+
+/// Compares the database with the `table!` definitions and the migrations.
+///
+/// The `runlogdigests` table is only expected when the deduplication uses digests.
+pub fn check_schema<C: ReportsBackend>(
+    connection: &mut C,
+    deduplication: RunlogDeduplication,
+) -> Result<SchemaCheck, Error> {
+    let mut differences = vec![];
+
+    for table in C::expected_tables() {
+        if table.name == "runlogdigests" && deduplication == RunlogDeduplication::FirstReport {
+            continue;
+        }
+        let columns = connection.table_columns(table.name)?;
+        if columns.is_empty() {
+            differences.push(SchemaDifference::MissingTable(table.name));
+            continue;
+        }
+        for expected in table.columns {
+            match columns.iter().find(|c| c.column_name == expected.name) {
+                None => differences.push(SchemaDifference::MissingColumn {
+                    table: table.name,
+                    column: expected.name,
+                }),
+                Some(column) => {
+                    if !expected
+                        .types
+                        .contains(&column.data_type.to_lowercase().as_str())
+                    {
+                        differences.push(SchemaDifference::ColumnType {
+                            table: table.name,
+                            column: expected.name,
+                            expected: expected.types,
+                            found: column.data_type.clone(),
+                        });
+                    }
+                    if column.nullable && !expected.nullable {
+                        differences.push(SchemaDifference::NullableColumn {
+                            table: table.name,
+                            column: expected.name,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    // Databases created by Rudder do not track migrations
+    let tracked = is_tracked(connection)?;
+    if tracked {
+        differences.extend(
+            pending_migrations(connection)?
+                .into_iter()
+                .map(|m| SchemaDifference::PendingMigration(*m)),
+        );
+    }
+
+    Ok(SchemaCheck {
+        tracked,
+        differences,
+    })
+}
+
+fn apply_migrations<C: ReportsBackend>(
+    connection: &mut C,
+) -> Result<Vec<&'static Migration>, Error> {
+    connection.transaction::<_, Error, _>(|connection| {
+        let pending = pending_migrations(connection)?;
+        for migration in &pending {
+            info!("Applying database migration {}", migration);
+            connection.batch_execute(migration.sql)?;
+            connection.batch_execute(&format!(
+                "INSERT INTO {} (version, description) VALUES ({}, '{}');",
+                MIGRATIONS_TABLE, migration.version, migration.description
+            ))?;
+        }
+        Ok(pending)
+    })
+}
+
+/// Creates missing tables and indexes, and records migrations.
+///
+/// Returns the applied migrations.
+pub fn init_schema<C: ReportsBackend>(
+    connection: &mut C,
+) -> Result<Vec<&'static Migration>, Error> {
+    connection.batch_execute(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+  version     integer PRIMARY KEY
+, description text NOT NULL
+, applieddate timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);",
+        MIGRATIONS_TABLE
+    ))?;
+    apply_migrations(connection)
+}
+
+/// Applies pending migrations to an initialized database
+pub fn migrate_schema<C: ReportsBackend>(
+    connection: &mut C,
+) -> Result<Vec<&'static Migration>, Error> {
+    if !is_tracked(connection)? {
+        return Err(RudderError::UninitializedDatabaseSchema.into());
+    }
+    apply_migrations(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::{connection::SimpleConnection, sqlite::SqliteConnection};
+
+    use super::*;
+    use crate::output::database::{schema, sqlite};
+
+    #[test]
+    fn it_lists_table_columns() {
+        let columns = ExpectedTable::new::<Pg, schema::runlogdigests::table>("runlogdigests");
+        assert_eq!(
+            columns.columns,
+            vec![
+                ExpectedColumn {
+                    name: "nodeid",
+                    types: &["text"],
+                    nullable: false
+                },
+                ExpectedColumn {
+                    name: "date",
+                    types: &["timestamp with time zone", "timestamp without time zone"],
+                    nullable: false
+                },
+                ExpectedColumn {
+                    name: "digest",
+                    types: &["text"],
+                    nullable: false
+                },
+                ExpectedColumn {
+                    name: "insertiondate",
+                    types: &["timestamp with time zone", "timestamp without time zone"],
+                    nullable: true
+                },
+            ]
+        );
+        assert_eq!(
+            ExpectedTable::new::<Sqlite, sqlite::schema::ruddersysevents::table>("ruddersysevents")
+                .columns
+                .len(),
+            12
+        );
+    }
+
+    #[test]
+    fn it_initializes_and_checks_schema() {
+        let connection = &mut SqliteConnection::establish(":memory:").unwrap();
+
+        let check = check_schema(connection, RunlogDeduplication::default()).unwrap();
+        assert!(!check.tracked);
+        assert_eq!(
+            check.differences,
+            vec![
+                SchemaDifference::MissingTable("ruddersysevents"),
+                SchemaDifference::MissingTable("reportsexecution"),
+                SchemaDifference::MissingTable("runlogdigests"),
+            ]
+        );
+        assert!(migrate_schema(connection).is_err());
+
+        assert_eq!(init_schema(connection).unwrap().len(), 3);
+        assert_eq!(
+            check_schema(connection, RunlogDeduplication::default()).unwrap(),
+            SchemaCheck {
+                tracked: true,
+                differences: vec![]
+            }
+        );
+        assert!(init_schema(connection).unwrap().is_empty());
+
+        // Pending migration
+        connection
+            .batch_execute(
+                "DROP TABLE runlogdigests; DELETE FROM relaydmigrations WHERE version = 2;",
+            )
+            .unwrap();
+        let check = check_schema(connection, RunlogDeduplication::default()).unwrap();
+        assert_eq!(
+            check.differences,
+            vec![
+                SchemaDifference::MissingTable("runlogdigests"),
+                SchemaDifference::PendingMigration(SQLITE_MIGRATIONS[1]),
+            ]
+        );
+        assert!(check.health().is_err());
+        assert_eq!(
+            migrate_schema(connection).unwrap(),
+            vec![&SQLITE_MIGRATIONS[1]]
+        );
+        assert!(check_schema(connection, RunlogDeduplication::default())
+            .unwrap()
+            .health()
+            .is_ok());
+
+        // Wrong columns
+        connection
+            .batch_execute(
+                "DROP TABLE runlogdigests;
+                 CREATE TABLE runlogdigests (nodeid TEXT, date INTEGER NOT NULL, insertiondate TEXT);",
+            )
+            .unwrap();
+        assert_eq!(
+            check_schema(connection, RunlogDeduplication::default())
+                .unwrap()
+                .differences,
+            vec![
+                SchemaDifference::NullableColumn {
+                    table: "runlogdigests",
+                    column: "nodeid"
+                },
+                SchemaDifference::ColumnType {
+                    table: "runlogdigests",
+                    column: "date",
+                    expected: &["text"],
+                    found: "integer".to_string()
+                },
+                SchemaDifference::MissingColumn {
+                    table: "runlogdigests",
+                    column: "digest"
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn it_only_expects_used_tables() {
+        let connection = &mut SqliteConnection::establish(":memory:").unwrap();
+        init_schema(connection).unwrap();
+        // Like a database created by Rudder, without runlog digests
+        connection
+            .batch_execute(&format!(
+                "DROP TABLE runlogdigests; DROP TABLE {};",
+                MIGRATIONS_TABLE
+            ))
+            .unwrap();
+
+        assert!(check_schema(connection, RunlogDeduplication::FirstReport)
+            .unwrap()
+            .health()
+            .is_ok());
+        assert_eq!(
+            check_schema(connection, RunlogDeduplication::Digest)
+                .unwrap()
+                .differences,
+            vec![SchemaDifference::MissingTable("runlogdigests")]
+        );
+    }
+}
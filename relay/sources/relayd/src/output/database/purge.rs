@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+//! Removal of old reports, for relays storing reports in their own database.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use tokio::{task::spawn_blocking, time::interval};
+use tracing::{debug, error, info};
+
+use crate::{
+    configuration::main::DatabasePurgeConfig,
+    metrics::{DATABASE_PURGED_ROWS, DATABASE_PURGE_DURATION},
+    output::database::ReportsBackend,
+    Error, JobConfig,
+};
+
+/// Columns copied to `archivedruddersysevents`
+pub const REPORT_COLUMNS: &str = "id, executiondate, nodeid, directiveid, ruleid, reportid, component, keyvalue, executiontimestamp, eventtype, policy, msg";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PurgedRows {
+    pub reports: usize,
+    pub executions: usize,
+    pub digests: usize,
+}
+
+/// Removes reports, executions and runlog digests older than `before`.
+///
+/// Rows are removed by batches, with a transaction each, to avoid long locks.
+pub fn purge<C: ReportsBackend>(
+    connection: &mut C,
+    before: DateTime<Utc>,
+    cfg: DatabasePurgeConfig,
+) -> Result<PurgedRows, Error> {
+    let limit = i64::from(cfg.batch_size.max(1));
+
+    // Executions first, so that they never point to removed reports
+    let executions = purge_table(connection, "reportsexecution", limit, |c| {
+        c.purge_executions(before, limit)
+    })?;
+    let reports = purge_table(connection, "ruddersysevents", limit, |c| {
+        c.purge_reports(before, limit, cfg.archive)
+    })?;
+    // Only created by relayd migrations
+    let digests = if connection.table_columns("runlogdigests")?.is_empty() {
+        0
+    } else {
+        purge_table(connection, "runlogdigests", limit, |c| {
+            c.purge_digests(before, limit)
+        })?
+    };
+
+    Ok(PurgedRows {
+        reports,
+        executions,
+        digests,
+    })
+}
+
+/// Removes batches until one is not full
+fn purge_table<C, F>(
+    connection: &mut C,
+    table: &str,
+    limit: i64,
+    mut remove: F,
+) -> Result<usize, Error>
+where
+    C: ReportsBackend,
+    F: FnMut(&mut C) -> Result<usize, Error>,
+{
+    let mut purged = 0;
+    loop {
+        let removed = connection.transaction::<_, Error, _>(|c| remove(c))?;
+        DATABASE_PURGED_ROWS
+            .with_label_values(&[table])
+            .inc_by(removed as u64);
+        purged += removed;
+        if (removed as i64) < limit {
+            break;
+        }
+    }
+    Ok(purged)
+}
+
+pub fn start(job_config: &Arc<JobConfig>) {
+    let cfg = job_config.cfg.output.database.purge;
+    let pool = match job_config.pool {
+        Some(ref pool) if cfg.enabled => pool.clone(),
+        _ => return,
+    };
+    let job_config = job_config.clone();
+    tokio::spawn(async move {
+        info!(
+            "Starting database purge every {:?}, keeping {:?}",
+            cfg.frequency, cfg.retention
+        );
+        let mut timer = interval(cfg.frequency);
+        let mut shutdown = job_config.shutdown_signal();
+        loop {
+            tokio::select! {
+                _ = shutdown.requested() => break,
+                _ = timer.tick() => {
+                    let pool = pool.clone();
+                    let duration = DATABASE_PURGE_DURATION.start_timer();
+                    // Diesel uses blocking io, put it on the blocking threadpool
+                    let result = spawn_blocking(move || {
+                        let before = Utc::now() - Duration::from_std(cfg.retention)?;
+                        debug!("Purging reports older than {}", before);
+                        pool.purge(before, cfg)
+                    })
+                    .await
+                    .map_err(Error::from)
+                    .and_then(|r| r);
+                    duration.observe_duration();
+                    match result {
+                        Ok(purged) => info!(
+                            "Purged {} reports, {} executions and {} runlog digests from the database",
+                            purged.reports, purged.executions, purged.digests
+                        ),
+                        Err(e) => error!("database purge error: {:?}", e),
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use diesel::{dsl::count_star, prelude::*, sqlite::SqliteConnection};
+
+    use super::*;
+    use crate::{
+        configuration::main::RunlogDeduplication,
+        data::{runlog::RunlogDigest, RunLog},
+        output::database::{
+            insert_runlog,
+            sqlite::{schema, sqlite_pool},
+        },
+    };
+
+    table! {
+        archivedruddersysevents {
+            id -> BigInt,
+        }
+    }
+
+    #[test]
+    fn it_purges_old_reports_by_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = sqlite_pool(dir.path().join("reports.sqlite").to_str().unwrap(), 2).unwrap();
+
+        for path in [
+            "tests/files/runlogs/2017-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        ] {
+            let runlog = RunLog::new(path).unwrap();
+            let digest = RunlogDigest::new(&runlog.info, &std::fs::read_to_string(path).unwrap());
+            insert_runlog(&pool, &runlog, &digest, RunlogDeduplication::Digest, true).unwrap();
+        }
+        let db: &mut SqliteConnection = &mut pool.get().unwrap();
+        let count_reports = |db: &mut SqliteConnection| -> i64 {
+            schema::ruddersysevents::table
+                .select(count_star())
+                .first(db)
+                .unwrap()
+        };
+        let total = count_reports(db);
+
+        let cfg = DatabasePurgeConfig {
+            archive: true,
+            batch_size: 10,
+            ..Default::default()
+        };
+        let before = Utc.with_ymd_and_hms(2018, 1, 1, 0, 0, 0).unwrap();
+        let purged = purge(db, before, cfg).unwrap();
+        assert_eq!(purged.executions, 1);
+        assert_eq!(purged.digests, 1);
+        assert!(purged.reports > 10);
+
+        assert_eq!(count_reports(db), total - purged.reports as i64);
+        let executions: i64 = schema::reportsexecution::table
+            .select(count_star())
+            .first(db)
+            .unwrap();
+        assert_eq!(executions, 1);
+        let archived: i64 = archivedruddersysevents::table
+            .select(count_star())
+            .first(db)
+            .unwrap();
+        assert_eq!(archived, purged.reports as i64);
+        let digests: i64 = schema::runlogdigests::table
+            .select(count_star())
+            .first(db)
+            .unwrap();
+        assert_eq!(digests, 1);
+
+        // Nothing left to purge
+        assert_eq!(purge(db, before, cfg).unwrap(), PurgedRows::default());
+    }
+}
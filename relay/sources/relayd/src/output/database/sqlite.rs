@@ -0,0 +1,256 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+//! Embedded reports database, for small installations and tests.
+//!
+//! It uses the same tables as the PostgreSQL database, the schema is
+//! initialized when opening it.
+//!
+//! Timestamps are stored as text, they are compared with `julianday()`
+//! as their offset can differ.
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    connection::SimpleConnection,
+    insert_into,
+    prelude::*,
+    r2d2::{self, ConnectionManager, CustomizeConnection, Pool},
+    sql_query,
+    sql_types::{BigInt, Text, TimestamptzSqlite},
+    sqlite::{Sqlite, SqliteConnection},
+};
+
+use crate::{
+    data::{
+        report::{QueryableReport, Report},
+        runlog::{InsertedRunlog, RunlogDigest},
+    },
+    output::database::{
+        migrations::{
+            init_schema, ExpectedTable, Migration, MigrationVersion, TableColumn, MIGRATIONS_TABLE,
+            SQLITE_MIGRATIONS,
+        },
+        purge::REPORT_COLUMNS,
+        DigestKey, NewExecution, NewReport, NewRunlogDigest, ReportKey, ReportsBackend,
+    },
+    Error,
+};
+
+pub mod schema {
+    // Same tables as `output::database::schema`, with SQLite timestamps.
+    // They are always written in UTC, so that text comparisons used for
+    // deduplication match whatever the offset of the runlog.
+    use crate::output::database::reports_schema;
+
+    reports_schema!(TimestamptzSqlite);
+}
+
+pub type SqlitePool = Pool<ConnectionManager<SqliteConnection>>;
+
+/// Connections of the pool wait for each other instead of failing
+/// when the database is locked
+#[derive(Debug)]
+struct BusyTimeout;
+
+impl CustomizeConnection<SqliteConnection, r2d2::Error> for BusyTimeout {
+    fn on_acquire(&self, connection: &mut SqliteConnection) -> Result<(), r2d2::Error> {
+        connection
+            .batch_execute("PRAGMA busy_timeout = 10000;")
+            .map_err(r2d2::Error::QueryError)
+    }
+}
+
+/// Opens the database file, creating it and the tables if needed
+pub fn sqlite_pool(path: &str, max_pool_size: u32) -> Result<SqlitePool, Error> {
+    let pool = Pool::builder()
+        .max_size(max_pool_size)
+        .connection_customizer(Box::new(BusyTimeout))
+        .build(ConnectionManager::<SqliteConnection>::new(path))?;
+    // Allows reading while another connection inserts
+    pool.get()?.batch_execute("PRAGMA journal_mode = WAL;")?;
+    init_schema(&mut *pool.get()?)?;
+    Ok(pool)
+}
+
+/// Rows are inserted one by one, as multi-row inserts do not return the
+/// inserted rows with SQLite. It is cheap as the database is embedded
+/// and everything happens in a single transaction.
+impl ReportsBackend for SqliteConnection {
+    /// Default `SQLITE_MAX_VARIABLE_NUMBER` since SQLite 3.32
+    const MAX_BIND_PARAMETERS: usize = 32_766;
+    const MIGRATIONS: &'static [Migration] = SQLITE_MIGRATIONS;
+
+    fn expected_tables() -> Vec<ExpectedTable> {
+        vec![
+            ExpectedTable::new::<Sqlite, schema::ruddersysevents::table>("ruddersysevents"),
+            ExpectedTable::new::<Sqlite, schema::reportsexecution::table>("reportsexecution"),
+            ExpectedTable::new::<Sqlite, schema::runlogdigests::table>("runlogdigests"),
+        ]
+    }
+
+    fn table_columns(&mut self, table: &str) -> Result<Vec<TableColumn>, Error> {
+        // Primary keys are implicitly not null
+        Ok(sql_query(
+            "SELECT name AS column_name, lower(type) AS data_type,
+                    \"notnull\" = 0 AND pk = 0 AS nullable
+             FROM pragma_table_info(?)",
+        )
+        .bind::<Text, _>(table)
+        .load(self)?)
+    }
+
+    fn applied_migrations(&mut self) -> Result<Vec<i32>, Error> {
+        Ok(
+            sql_query(format!("SELECT version FROM {}", MIGRATIONS_TABLE))
+                .load::<MigrationVersion>(self)?
+                .into_iter()
+                .map(|m| m.version)
+                .collect(),
+        )
+    }
+
+    fn check_reports(&mut self) -> Result<(), Error> {
+        use self::schema::ruddersysevents::dsl::*;
+
+        let _ = ruddersysevents.limit(1).load::<QueryableReport>(self)?;
+        Ok(())
+    }
+
+    fn record_digests(&mut self, digests: &[&RunlogDigest]) -> Result<Vec<DigestKey>, Error> {
+        use self::schema::runlogdigests::dsl::*;
+
+        let mut recorded = Vec::with_capacity(digests.len());
+        for runlog_digest in digests {
+            recorded.extend(
+                insert_into(runlogdigests)
+                    .values(NewRunlogDigest::from(*runlog_digest))
+                    .on_conflict_do_nothing()
+                    .returning((nodeid, date, digest))
+                    .get_result(self)
+                    .optional()?,
+            );
+        }
+        Ok(recorded)
+    }
+
+    fn is_report_inserted(&mut self, report: &Report) -> Result<bool, Error> {
+        use self::schema::ruddersysevents::dsl::*;
+
+        Ok(ruddersysevents
+            .filter(
+                component
+                    .eq(&report.component)
+                    .and(nodeid.eq(&report.node_id))
+                    .and(keyvalue.eq(&report.key_value))
+                    .and(eventtype.eq(&report.event_type))
+                    .and(msg.eq(&report.msg))
+                    .and(policy.eq(&report.policy))
+                    .and(executiontimestamp.eq(&report.start_datetime))
+                    .and(executiondate.eq(&report.execution_datetime))
+                    .and(reportid.eq(&report.report_id))
+                    .and(ruleid.eq(&report.rule_id))
+                    .and(directiveid.eq(&report.directive_id)),
+            )
+            .first::<QueryableReport>(self)
+            .optional()?
+            .is_some())
+    }
+
+    fn report_keys(
+        &mut self,
+        node_ids: &[&str],
+        timestamps: &[DateTime<Utc>],
+    ) -> Result<Vec<ReportKey>, Error> {
+        use self::schema::ruddersysevents::dsl::*;
+
+        Ok(ruddersysevents
+            .select((
+                component,
+                nodeid,
+                keyvalue,
+                eventtype,
+                msg,
+                policy,
+                executiontimestamp,
+                executiondate,
+                reportid,
+                ruleid,
+                directiveid,
+            ))
+            .filter(nodeid.eq_any(node_ids))
+            .filter(executiontimestamp.eq_any(timestamps))
+            .load(self)?)
+    }
+
+    fn insert_reports(&mut self, reports: &[&Report]) -> Result<Vec<i64>, Error> {
+        use self::schema::ruddersysevents::dsl::*;
+
+        reports
+            .iter()
+            .map(|report| {
+                Ok(insert_into(ruddersysevents)
+                    .values(NewReport::from(*report))
+                    .returning(id)
+                    .get_result(self)?)
+            })
+            .collect()
+    }
+
+    fn insert_executions(&mut self, executions: &[InsertedRunlog]) -> Result<(), Error> {
+        use self::schema::reportsexecution::dsl::*;
+
+        for execution in executions {
+            insert_into(reportsexecution)
+                .values(NewExecution::from(execution))
+                .execute(self)?;
+        }
+        Ok(())
+    }
+
+    fn purge_reports(
+        &mut self,
+        before: DateTime<Utc>,
+        limit: i64,
+        archive: bool,
+    ) -> Result<usize, Error> {
+        // Ordered to get the same rows in both statements
+        let batch = "SELECT id FROM ruddersysevents
+                     WHERE julianday(executiontimestamp) < julianday(?) ORDER BY id LIMIT ?";
+        if archive {
+            sql_query(format!(
+                "INSERT INTO archivedruddersysevents ({}) SELECT {} FROM ruddersysevents WHERE id IN ({})",
+                REPORT_COLUMNS, REPORT_COLUMNS, batch
+            ))
+            .bind::<TimestamptzSqlite, _>(before)
+            .bind::<BigInt, _>(limit)
+            .execute(self)?;
+        }
+        Ok(sql_query(format!(
+            "DELETE FROM ruddersysevents WHERE id IN ({})",
+            batch
+        ))
+        .bind::<TimestamptzSqlite, _>(before)
+        .bind::<BigInt, _>(limit)
+        .execute(self)?)
+    }
+
+    fn purge_executions(&mut self, before: DateTime<Utc>, limit: i64) -> Result<usize, Error> {
+        Ok(sql_query(
+            "DELETE FROM reportsexecution WHERE (nodeid, date) IN
+             (SELECT nodeid, date FROM reportsexecution WHERE julianday(date) < julianday(?) LIMIT ?)",
+        )
+        .bind::<TimestamptzSqlite, _>(before)
+        .bind::<BigInt, _>(limit)
+        .execute(self)?)
+    }
+
+    fn purge_digests(&mut self, before: DateTime<Utc>, limit: i64) -> Result<usize, Error> {
+        Ok(sql_query(
+            "DELETE FROM runlogdigests WHERE (nodeid, date, digest) IN
+             (SELECT nodeid, date, digest FROM runlogdigests WHERE julianday(date) < julianday(?) LIMIT ?)",
+        )
+        .bind::<TimestamptzSqlite, _>(before)
+        .bind::<BigInt, _>(limit)
+        .execute(self)?)
+    }
+}
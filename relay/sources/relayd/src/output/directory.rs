@@ -0,0 +1,350 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{create_dir_all, rename, write, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+    task::spawn_blocking,
+};
+use tracing::{debug, instrument};
+
+use crate::{
+    data::node::NodeId,
+    error::RudderError,
+    hashing::{Hash, HashType},
+    input::{
+        decompress,
+        inventory::{check_signature, check_xml},
+    },
+    processing::inventory::{inventory_node_id, InventoryType},
+};
+
+/// One JSON entry by line, appended for each exported file
+pub const MANIFEST: &str = "manifest.jsonl";
+/// Files already imported, in the inventory directory, one JSON entry by line
+pub const IMPORTED: &str = "imported.jsonl";
+
+/// Describes an exported inventory or signature file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Name of the file in the export directory
+    pub file: String,
+    /// From the file name, when it contains it
+    #[serde(default)]
+    pub node_id: Option<NodeId>,
+    pub inventory_type: InventoryType,
+    /// Hash of the file content
+    pub hash: String,
+    /// For signatures, name of the signed inventory file
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signed_file: Option<String>,
+    pub date: DateTime<Utc>,
+}
+
+/// Export directory for inventories, to be carried to the root server
+#[derive(Debug)]
+pub struct InventoryExport {
+    directory: PathBuf,
+    /// Serializes manifest writes
+    manifest: Mutex<()>,
+}
+
+impl InventoryExport {
+    pub fn new(directory: &Path) -> Self {
+        Self {
+            directory: directory.to_path_buf(),
+            manifest: Mutex::new(()),
+        }
+    }
+
+    /// Checks the inventory and its signature, copies them to the export directory,
+    /// then records them in the manifest.
+    ///
+    /// The signature is checked against the uncompressed inventory, like the
+    /// inventory validation does.
+    #[instrument(name = "export", level = "debug", skip(self, known_key_hash))]
+    pub async fn export(
+        &self,
+        inventory: &Path,
+        signature: Option<&Path>,
+        inventory_type: InventoryType,
+        known_key_hash: Option<&Hash>,
+    ) -> Result<(), Error> {
+        let inventory_path = inventory.to_path_buf();
+        // Decompression is CPU-bound and uses blocking io
+        let (content, uncompressed) = spawn_blocking(move || -> Result<_, Error> {
+            let content = fs::read(&inventory_path)?;
+            let uncompressed = decompress(&content, &inventory_path)?;
+            Ok((content, uncompressed))
+        })
+        .await??;
+        check_xml(&uncompressed)?;
+
+        let mut files = vec![(inventory, content, None)];
+        if let Some(signature) = signature {
+            let signature_content = tokio::fs::read_to_string(signature).await?;
+            check_signature(&uncompressed, &signature_content, known_key_hash)?;
+            files.push((
+                signature,
+                signature_content.into_bytes(),
+                Some(file_name(inventory)?.to_string()),
+            ));
+        }
+
+        create_dir_all(&self.directory).await?;
+        let mut lines = vec![];
+        for (path, content, signed_file) in files {
+            let name = file_name(path)?;
+            let target = self.directory.join(name);
+            let tmp = tmp_path(&target);
+            write(&tmp, &content).await?;
+            rename(&tmp, &target).await?;
+            debug!("exported {:?} to {:?}", path, target);
+
+            let entry = ManifestEntry {
+                file: name.to_string(),
+                node_id: inventory_node_id(inventory),
+                inventory_type,
+                hash: HashType::Sha256.hash(&content).to_string(),
+                signed_file,
+                date: Utc::now(),
+            };
+            lines.extend(serde_json::to_vec(&entry)?);
+            lines.push(b'\n');
+        }
+
+        let _lock = self.manifest.lock().await;
+        let mut manifest = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.directory.join(MANIFEST))
+            .await?;
+        manifest.write_all(&lines).await?;
+        manifest.flush().await?;
+        Ok(())
+    }
+}
+
+fn file_name(path: &Path) -> Result<&str, RudderError> {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .ok_or(RudderError::InvalidFileName)
+}
+
+/// Files are written to a temporary file first and then moved, to never expose
+/// a partial file. The extension prevents processing it in queue directories.
+fn tmp_path(target: &Path) -> PathBuf {
+    let mut tmp_name = OsStr::new(".").to_owned();
+    tmp_name.push(target.file_name().expect("not a file"));
+    tmp_name.push(".tmp");
+    target.with_file_name(tmp_name)
+}
+
+/// Read the manifest, only keeping the last entry of each file
+fn read_manifest(directory: &Path) -> Result<Vec<ManifestEntry>, Error> {
+    let path = directory.join(MANIFEST);
+    let manifest =
+        fs::read_to_string(&path).with_context(|| format!("could not read {:?}", path))?;
+    let mut entries: Vec<ManifestEntry> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for (n, line) in manifest.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: ManifestEntry = serde_json::from_str(line)
+            .map_err(|e| anyhow!("invalid manifest entry on line {}: {}", n + 1, e))?;
+        match positions.get(&entry.file) {
+            Some(&p) => entries[p] = entry,
+            None => {
+                positions.insert(entry.file.clone(), entries.len());
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Files already imported, with their hash
+fn read_imported(inventory_directory: &Path) -> Result<HashSet<(String, String)>, Error> {
+    let path = inventory_directory.join(IMPORTED);
+    let imported = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).with_context(|| format!("could not read {:?}", path)),
+    };
+    imported
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let entry: ManifestEntry =
+                serde_json::from_str(l).with_context(|| format!("invalid entry in {:?}", path))?;
+            Ok((entry.file, entry.hash))
+        })
+        .collect()
+}
+
+/// Copy the exported files into the inventory queues, inventories before signatures.
+///
+/// Files already imported with the same content are skipped, so that the same
+/// export directory can be imported several times.
+/// All files are checked against the manifest before importing any of them.
+/// Returns the imported files.
+pub fn import(export: &Path, inventory_directory: &Path) -> Result<Vec<PathBuf>, Error> {
+    let already_imported = read_imported(inventory_directory)?;
+    let mut entries: Vec<ManifestEntry> = read_manifest(export)?
+        .into_iter()
+        .filter(|e| {
+            let skip = already_imported.contains(&(e.file.clone(), e.hash.clone()));
+            if skip {
+                debug!("skipping already imported {}", e.file);
+            }
+            !skip
+        })
+        .collect();
+    entries.sort_by_key(|e| e.signed_file.is_some());
+
+    let mut contents = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        // Only accept plain file names
+        if Path::new(&entry.file).file_name() != Some(OsStr::new(&entry.file)) {
+            return Err(RudderError::InvalidFile(PathBuf::from(&entry.file)).into());
+        }
+        let content = fs::read(export.join(&entry.file))?;
+        let expected = Hash::from_str(&entry.hash)?;
+        if expected.hash_type.hash(&content) != expected {
+            return Err(anyhow!(
+                "hash of {} does not match the manifest",
+                entry.file
+            ));
+        }
+        contents.push(content);
+    }
+
+    let mut record = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(inventory_directory.join(IMPORTED))?;
+    let mut imported = Vec::with_capacity(entries.len());
+    for (entry, content) in entries.iter().zip(contents) {
+        let target = inventory_directory
+            .join(match entry.inventory_type {
+                InventoryType::New => "incoming",
+                InventoryType::Update => "accepted-nodes-updates",
+            })
+            .join(&entry.file);
+        let tmp = tmp_path(&target);
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &target)?;
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        record.write_all(&line)?;
+        debug!("imported {:?}", target);
+        imported.push(target);
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+    use openssl::rsa::Rsa;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::input::inventory::tests::sign;
+
+    #[tokio::test]
+    async fn it_exports_and_imports_inventories() {
+        let dir = tempdir().unwrap();
+        let base = dir.path();
+        let inventory_name = "node1-e745a140-40bc-4b86-b6dc-084488fc906b.ocs.gz";
+        let signature_name = format!("{}.sign", inventory_name);
+        let source = base.join("source");
+        fs::create_dir_all(&source).unwrap();
+        let content = b"<REQUEST><CONTENT></CONTENT></REQUEST>";
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(content).unwrap();
+        fs::write(source.join(inventory_name), gz.finish().unwrap()).unwrap();
+        let key = Rsa::generate(2048).unwrap();
+        fs::write(source.join(&signature_name), sign(&key, content)).unwrap();
+        fs::write(
+            source.join("forged.sign"),
+            sign(&key, b"<REQUEST>forged</REQUEST>"),
+        )
+        .unwrap();
+        fs::write(source.join("invalid.ocs"), "<REQUEST>").unwrap();
+
+        let export = InventoryExport::new(&base.join("export"));
+        export
+            .export(
+                &source.join(inventory_name),
+                Some(&source.join(&signature_name)),
+                InventoryType::Update,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(export
+            .export(&source.join("invalid.ocs"), None, InventoryType::New, None)
+            .await
+            .is_err());
+        assert!(export
+            .export(
+                &source.join(inventory_name),
+                Some(&source.join("forged.sign")),
+                InventoryType::Update,
+                None,
+            )
+            .await
+            .is_err());
+
+        let entries = read_manifest(&base.join("export")).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].node_id.as_deref(),
+            Some("e745a140-40bc-4b86-b6dc-084488fc906b")
+        );
+        assert_eq!(entries[1].file, signature_name);
+        assert_eq!(entries[1].signed_file.as_deref(), Some(inventory_name));
+
+        let inventories = base.join("inventories");
+        fs::create_dir_all(inventories.join("accepted-nodes-updates")).unwrap();
+        let imported = import(&base.join("export"), &inventories).unwrap();
+        assert_eq!(
+            imported
+                .iter()
+                .map(|p| p.strip_prefix(base).unwrap().to_str().unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                format!("inventories/accepted-nodes-updates/{}", inventory_name),
+                format!("inventories/accepted-nodes-updates/{}", signature_name),
+            ]
+        );
+        // Already imported
+        assert!(import(&base.join("export"), &inventories)
+            .unwrap()
+            .is_empty());
+
+        // Altered file
+        let other_inventories = base.join("other");
+        fs::create_dir_all(other_inventories.join("accepted-nodes-updates")).unwrap();
+        fs::write(base.join("export").join(inventory_name), "altered").unwrap();
+        assert!(import(&base.join("export"), &other_inventories).is_err());
+    }
+}
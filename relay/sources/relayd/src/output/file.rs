@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::{
+    fs::{create_dir_all, rename, File, Metadata, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Error;
+use chrono::Utc;
+use serde::Serialize;
+use tokio::{task::spawn_blocking, time::interval};
+use tracing::{debug, error, info, instrument};
+
+use crate::{
+    configuration::main::FileOutputConfig,
+    data::{Report, RunInfo, RunLog},
+    error::RudderError,
+    JobConfig,
+};
+
+/// File currently written, rotated files get a date suffix
+const CURRENT_FILE: &str = "reports";
+const EXTENSION: &str = "jsonl";
+/// Maximum delay between age checks, when no runlog is written
+const ROTATION_CHECK: Duration = Duration::from_secs(60);
+
+/// A report with its runlog information, written as one line
+#[derive(Serialize, Debug)]
+struct ReportLine<'a> {
+    #[serde(flatten)]
+    report: &'a Report,
+    run_info: &'a RunInfo,
+    config_id: &'a Option<String>,
+}
+
+/// JSON lines file output for reports, with size and age based rotation
+#[derive(Debug)]
+pub struct ReportsFile {
+    cfg: FileOutputConfig,
+    file: File,
+    size: u64,
+    /// Taken from the file, so that the age is kept across restarts
+    created: SystemTime,
+}
+
+impl ReportsFile {
+    pub fn new(cfg: &FileOutputConfig) -> Result<Self, Error> {
+        create_dir_all(&cfg.directory)?;
+        let (file, size, created) = Self::open(cfg)?;
+        Ok(Self {
+            cfg: cfg.clone(),
+            file,
+            size,
+            created,
+        })
+    }
+
+    fn current_path(cfg: &FileOutputConfig) -> PathBuf {
+        cfg.directory
+            .join(format!("{}.{}", CURRENT_FILE, EXTENSION))
+    }
+
+    /// Appends to an existing file
+    fn open(cfg: &FileOutputConfig) -> Result<(File, u64, SystemTime), Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::current_path(cfg))?;
+        let metadata = file.metadata()?;
+        Ok((file, metadata.len(), Self::creation_time(&metadata)?))
+    }
+
+    /// Creation time is not available on all filesystems, the modification
+    /// time is then used, which is older when the file was touched
+    fn creation_time(metadata: &Metadata) -> Result<SystemTime, Error> {
+        let modified = metadata.modified()?;
+        Ok(match metadata.created() {
+            Ok(created) => created.min(modified),
+            Err(_) => modified,
+        })
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        let rotated = self.cfg.directory.join(format!(
+            "{}-{}.{}",
+            CURRENT_FILE,
+            Utc::now().format("%Y%m%dT%H%M%S%.6fZ"),
+            EXTENSION
+        ));
+        debug!("rotating reports file to {:?}", rotated);
+        rename(Self::current_path(&self.cfg), rotated)?;
+        let (file, size, created) = Self::open(&self.cfg)?;
+        self.file = file;
+        self.size = size;
+        self.created = created;
+        Ok(())
+    }
+
+    fn is_expired(&self) -> bool {
+        // A creation time in the future is treated as a new file
+        self.created
+            .elapsed()
+            .map(|age| age >= self.cfg.max_age)
+            .unwrap_or(false)
+    }
+
+    fn needs_rotation(&self, additional: u64) -> bool {
+        self.size > 0 && (self.size + additional > self.cfg.max_size || self.is_expired())
+    }
+
+    /// Called periodically, so that files are rotated even when no runlog is received
+    pub fn rotate_if_expired(&mut self) -> Result<bool, Error> {
+        if self.size > 0 && self.is_expired() {
+            self.rotate()
+                .map_err(|e| RudderError::OutputFile(e.to_string()))?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Writes all reports of the runlog at once, a runlog is never split between files
+    #[instrument(name = "file_output", level = "debug", skip(self, runlog), fields(runlog = %runlog.info))]
+    pub fn write_runlog(&mut self, runlog: &RunLog) -> Result<(), Error> {
+        let mut lines = vec![];
+        for report in &runlog.reports {
+            serde_json::to_writer(
+                &mut lines,
+                &ReportLine {
+                    report,
+                    run_info: &runlog.info,
+                    config_id: &runlog.config_id,
+                },
+            )?;
+            lines.push(b'\n');
+        }
+
+        if self.needs_rotation(lines.len() as u64) {
+            self.rotate()
+                .map_err(|e| RudderError::OutputFile(e.to_string()))?;
+        }
+        self.file
+            .write_all(&lines)
+            .map_err(|e| RudderError::OutputFile(e.to_string()))?;
+        self.size += lines.len() as u64;
+        debug!("{} reports written", runlog.reports.len());
+        Ok(())
+    }
+}
+
+pub fn start(job_config: &Arc<JobConfig>) {
+    if job_config.reports_file.is_none() {
+        return;
+    }
+    let job_config = job_config.clone();
+    tokio::spawn(async move {
+        let frequency = job_config.cfg.output.file.max_age.min(ROTATION_CHECK);
+        info!("Checking reports file age every {:?}", frequency);
+        let mut timer = interval(frequency);
+        let mut shutdown = job_config.shutdown_signal();
+        loop {
+            tokio::select! {
+                _ = shutdown.requested() => break,
+                _ = timer.tick() => {
+                    let job_config = job_config.clone();
+                    // Rotation uses blocking io, put it on the blocking threadpool
+                    let result = spawn_blocking(move || {
+                        job_config
+                            .reports_file
+                            .as_ref()
+                            .expect("output uses file but no file was opened")
+                            .lock()
+                            .expect("reports file lock poisoned")
+                            .rotate_if_expired()
+                    })
+                    .await
+                    .map_err(Error::from)
+                    .and_then(|r| r);
+                    match result {
+                        Ok(true) => debug!("rotated expired reports file"),
+                        Ok(false) => (),
+                        Err(e) => error!("reports file rotation error: {}", e),
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read_dir;
+
+    use filetime::{set_file_mtime, FileTime};
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn it_writes_and_rotates_reports() {
+        let dir = tempdir().unwrap();
+        let cfg = FileOutputConfig {
+            directory: dir.path().join("output"),
+            max_size: 1_000,
+            max_age: Duration::from_secs(3600),
+        };
+        let runlog = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+
+        let mut file = ReportsFile::new(&cfg).unwrap();
+        file.write_runlog(&runlog).unwrap();
+        let content = std::fs::read_to_string(ReportsFile::current_path(&cfg)).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), runlog.reports.len());
+        assert_eq!(lines[0]["rule_id"], runlog.reports[0].rule_id.as_str());
+        assert_eq!(
+            lines[0]["run_info"]["node_id"],
+            "e745a140-40bc-4b86-b6dc-084488fc906b"
+        );
+        assert_eq!(lines[0]["config_id"], "20180824-130007-3ad37587");
+
+        // Larger than max size, so written in a new file
+        file.write_runlog(&runlog).unwrap();
+        assert_eq!(read_dir(&cfg.directory).unwrap().count(), 2);
+        assert_eq!(
+            std::fs::read_to_string(ReportsFile::current_path(&cfg)).unwrap(),
+            content
+        );
+    }
+
+    #[test]
+    fn it_rotates_old_files_without_writes() {
+        let dir = tempdir().unwrap();
+        let cfg = FileOutputConfig {
+            directory: dir.path().join("output"),
+            max_size: 1_000_000,
+            max_age: Duration::from_secs(3600),
+        };
+        create_dir_all(&cfg.directory).unwrap();
+        let current = ReportsFile::current_path(&cfg);
+        std::fs::write(&current, "{}\n").unwrap();
+        // Written before a restart
+        set_file_mtime(
+            &current,
+            FileTime::from_system_time(SystemTime::now() - Duration::from_secs(7200)),
+        )
+        .unwrap();
+
+        let mut file = ReportsFile::new(&cfg).unwrap();
+        assert!(file.rotate_if_expired().unwrap());
+        assert_eq!(read_dir(&cfg.directory).unwrap().count(), 2);
+        // The new file is empty and recent
+        assert!(!file.rotate_if_expired().unwrap());
+        assert_eq!(read_dir(&cfg.directory).unwrap().count(), 2);
+    }
+}
@@ -1,12 +1,220 @@
 // SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use reqwest::Body;
-use tracing::{debug, instrument};
+use anyhow::anyhow;
+use async_compression::tokio::bufread::GzipEncoder;
+use reqwest::{Body, Client, RequestBuilder, Response};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, BufReader},
+    sync::RwLock,
+    time::interval,
+};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info, instrument, warn};
 
-use crate::{configuration::Secret, processing::inventory::InventoryType, Error, JobConfig};
+use crate::{
+    configuration::{
+        main::{Configuration, PeerAuthentication},
+        Secret,
+    },
+    http_client::HttpClient,
+    input::inventory::{is_signature, signature_path, signed_path},
+    metrics::{UPSTREAM_ACTIVE, UPSTREAM_FAILOVERS},
+    processing::inventory::InventoryType,
+    Error, JobConfig,
+};
+
+/// Relay API of the upstream server, through its reverse proxy
+const HEALTH_CHECK_PATH: &str = "rudder/relay-api/system/info";
+/// Files with these extensions are sent as is
+const COMPRESSED_EXTENSIONS: &[&str] = &["gz", "zst", "xz", "zip"];
+const GZIP_EXTENSION: &str = "gz";
+
+#[derive(Debug)]
+struct UpstreamServer {
+    url: String,
+    certificate_file: Option<PathBuf>,
+    client: RwLock<HttpClient>,
+}
+
+/// Upstream servers in order of preference, with the one currently used.
+///
+/// Requests go to the active server and fail over to the next ones after
+/// transient errors. The health check goes back to the first available server.
+#[derive(Debug)]
+pub struct Upstreams {
+    servers: Vec<UpstreamServer>,
+    active: AtomicUsize,
+}
+
+impl Upstreams {
+    pub fn new(cfg: &Configuration) -> Result<Self, Error> {
+        let servers = cfg
+            .upstream_servers()
+            .into_iter()
+            .map(|(url, certificate_file)| {
+                debug!("Creating HTTP client for upstream {}", url);
+                let builder = HttpClient::builder(cfg.general.https_idle_timeout);
+                let client = match cfg.peer_authentication() {
+                    PeerAuthentication::CertPinning => {
+                        let path = certificate_file.ok_or_else(|| {
+                            anyhow!(
+                                "server_certificate_file is needed for upstream {} with cert_pinning",
+                                url
+                            )
+                        })?;
+                        builder.pinned(vec![fs::read(path)?])
+                    }
+                    PeerAuthentication::SystemRootCerts => builder.system(),
+                    PeerAuthentication::DangerousNone => builder.no_verify(),
+                }?;
+                Ok(UpstreamServer {
+                    url,
+                    certificate_file: certificate_file.map(Path::to_path_buf),
+                    client: RwLock::new(client),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let upstreams = Self {
+            servers,
+            active: AtomicUsize::new(0),
+        };
+        upstreams.update_metrics(0);
+        Ok(upstreams)
+    }
+
+    /// Replace clients with outdated pinned certificate
+    pub async fn reload(&self, idle_timeout: Duration) -> Result<(), Error> {
+        for server in &self.servers {
+            let path = match server.certificate_file {
+                Some(ref p) => p,
+                None => continue,
+            };
+            let certs = vec![fs::read(path)?];
+            let needs_reload = server.client.read().await.outdated(&certs);
+            if needs_reload {
+                debug!(
+                    "HTTP client for upstream {} has outdated certificate, updating from {}",
+                    server.url,
+                    path.display()
+                );
+                *server.client.write().await = HttpClient::builder(idle_timeout).pinned(certs)?;
+            } else {
+                debug!(
+                    "HTTP client for upstream {} has up-to-date certificate",
+                    server.url
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn activate(&self, index: usize) {
+        let previous = self.active.swap(index, Ordering::Relaxed);
+        if previous != index {
+            warn!(
+                "switching upstream server from {} to {}",
+                self.servers[previous].url, self.servers[index].url
+            );
+            UPSTREAM_FAILOVERS.inc();
+            self.update_metrics(index);
+        }
+    }
+
+    fn update_metrics(&self, active: usize) {
+        for (index, server) in self.servers.iter().enumerate() {
+            UPSTREAM_ACTIVE
+                .with_label_values(&[&server.url])
+                .set((index == active) as i64);
+        }
+    }
+
+    /// Sends the request built for the active server, and then for the next ones after
+    /// transient errors (unreachable server, timeout or server error).
+    ///
+    /// The last result is returned when no server accepted the request. Errors while
+    /// building the request are returned immediately.
+    pub async fn send<F>(&self, request: F) -> Result<Response, Error>
+    where
+        F: Fn(&Client, &str) -> Result<RequestBuilder, Error>,
+    {
+        let start = self.active.load(Ordering::Relaxed);
+        let mut result = None;
+        for index in (start..self.servers.len()).chain(0..start) {
+            let server = &self.servers[index];
+            let client = server.client.read().await.inner().clone();
+            let response = request(&client, &server.url)?.send().await;
+            match response {
+                Ok(ref r) if !r.status().is_server_error() => {
+                    self.activate(index);
+                    return Ok(response?);
+                }
+                Ok(ref r) => warn!("upstream {} answered {}", server.url, r.status()),
+                Err(ref e) => warn!("could not reach upstream {}: {}", server.url, e),
+            }
+            result = Some(response);
+        }
+        Ok(result.expect("no upstream server configured")?)
+    }
+
+    /// Switch to the first available server, in order of preference
+    pub async fn check(&self, timeout: Duration) {
+        for (index, server) in self.servers.iter().enumerate() {
+            let client = server.client.read().await.inner().clone();
+            let result = client
+                .get(format!(
+                    "{}/{}",
+                    server.url.trim_end_matches('/'),
+                    HEALTH_CHECK_PATH
+                ))
+                .timeout(timeout)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            match result {
+                Ok(_) => {
+                    self.activate(index);
+                    return;
+                }
+                Err(e) => debug!("upstream {} is unavailable: {}", server.url, e),
+            }
+        }
+        warn!("no upstream server is available");
+    }
+}
+
+/// Only needed with failover servers
+pub fn start_health_check(job_config: &Arc<JobConfig>) {
+    if job_config.upstreams.servers.len() < 2 {
+        return;
+    }
+    let job_config = job_config.clone();
+    tokio::spawn(async move {
+        let cfg = job_config.cfg.output.upstream.health_check;
+        info!("Starting upstream health check every {:?}", cfg.frequency);
+        let mut timer = interval(cfg.frequency);
+        let mut shutdown = job_config.shutdown_signal();
+        loop {
+            tokio::select! {
+                _ = shutdown.requested() => break,
+                _ = timer.tick() => job_config.upstreams.check(cfg.timeout).await,
+            }
+        }
+    });
+}
 
 #[instrument(name = "upstream", level = "debug", skip(job_config))]
 pub async fn send_report(job_config: Arc<JobConfig>, path: PathBuf) -> Result<(), Error> {
@@ -40,34 +248,144 @@ pub async fn send_inventory(
     .await
 }
 
+/// Uncompressed files are compressed on the fly when enabled
 async fn forward_file(
     job_config: Arc<JobConfig>,
     endpoint: &str,
     path: PathBuf,
     password: Secret,
 ) -> Result<(), Error> {
-    let content = tokio::fs::read(path.clone()).await?;
-
-    let client = job_config.upstream_client.read().await.inner().clone();
-
-    let result = client
-        .put(&format!(
-            "{}/{}/{}",
-            job_config.cfg.upstream_url(),
-            endpoint,
-            path.file_name().expect("not a file").to_string_lossy()
-        ))
-        .basic_auth(
-            &job_config.cfg.output.upstream.user,
-            Some(&password.value()),
-        )
-        .body(Body::from(content))
-        .send()
-        .await;
-
-    result
-        // HTTP error -> Err()
-        .and_then(|r| r.error_for_status())
-        .map(|r| debug!("Server response: {:#?}", r))?;
+    let compress = job_config.cfg.output.upstream.compress;
+    let file_name = remote_name(&path, compress);
+
+    let response = job_config
+        .upstreams
+        .send(|client, url| {
+            Ok(client
+                .put(&format!("{}/{}/{}", url, endpoint, file_name))
+                .basic_auth(
+                    &job_config.cfg.output.upstream.user,
+                    Some(&password.value()),
+                )
+                .body(Body::wrap_stream(ReaderStream::new(file_reader(
+                    &path,
+                    compress && needs_compression(&path),
+                )?))))
+        })
+        .await?;
+
+    // HTTP error -> Err()
+    let response = response.error_for_status()?;
+    debug!("Server response: {:#?}", response);
     Ok(())
 }
+
+/// Signatures are never compressed, as they are checked against the uncompressed inventory
+fn needs_compression(path: &Path) -> bool {
+    let is_compressed = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(|e| COMPRESSED_EXTENSIONS.contains(&e))
+        .unwrap_or(false);
+    !is_signature(path) && !is_compressed
+}
+
+fn gz_path(path: &Path) -> PathBuf {
+    let mut gz = path.as_os_str().to_owned();
+    gz.push(".");
+    gz.push(GZIP_EXTENSION);
+    gz.into()
+}
+
+/// File name on the upstream server. Signatures keep the name of their inventory.
+fn remote_name(path: &Path, compress: bool) -> String {
+    let remote = if !compress {
+        path.to_path_buf()
+    } else if is_signature(path) && needs_compression(&signed_path(path)) {
+        signature_path(&gz_path(&signed_path(path)))
+    } else if needs_compression(path) {
+        gz_path(path)
+    } else {
+        path.to_path_buf()
+    };
+    remote
+        .file_name()
+        .expect("not a file")
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Reads the file from disk while sending it, instead of loading it in memory.
+///
+/// Opened for each attempt as the body can only be sent once.
+fn file_reader(
+    path: &Path,
+    compress: bool,
+) -> Result<Box<dyn AsyncRead + Send + Sync + Unpin>, Error> {
+    let file = File::from_std(fs::File::open(path)?);
+    Ok(if compress {
+        Box::new(GzipEncoder::new(BufReader::new(file)))
+    } else {
+        Box::new(file)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use tempfile::tempdir;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[test]
+    fn it_computes_remote_names() {
+        let runlog = Path::new("incoming/2018-08-24T15:55:01+00:00@root.log");
+        let inventory = Path::new("incoming/node-root.ocs");
+        let signature = Path::new("incoming/node-root.ocs.sign");
+        let compressed = Path::new("incoming/node-root.ocs.gz.sign");
+
+        assert_eq!(
+            remote_name(runlog, false),
+            "2018-08-24T15:55:01+00:00@root.log"
+        );
+        assert_eq!(
+            remote_name(runlog, true),
+            "2018-08-24T15:55:01+00:00@root.log.gz"
+        );
+        assert_eq!(remote_name(inventory, true), "node-root.ocs.gz");
+        assert_eq!(remote_name(signature, true), "node-root.ocs.gz.sign");
+        assert_eq!(remote_name(compressed, true), "node-root.ocs.gz.sign");
+        assert!(!needs_compression(signature));
+        assert!(!needs_compression(&signed_path(compressed)));
+    }
+
+    #[tokio::test]
+    async fn it_compresses_while_reading() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("node-root.ocs");
+        fs::write(&path, "<REQUEST></REQUEST>").unwrap();
+
+        let mut sent = vec![];
+        file_reader(&path, true)
+            .unwrap()
+            .read_to_end(&mut sent)
+            .await
+            .unwrap();
+        let mut content = String::new();
+        GzDecoder::new(sent.as_slice())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "<REQUEST></REQUEST>");
+
+        let mut sent = vec![];
+        file_reader(&path, false)
+            .unwrap()
+            .read_to_end(&mut sent)
+            .await
+            .unwrap();
+        assert_eq!(sent, b"<REQUEST></REQUEST>");
+    }
+}
@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::{fs, time::Duration};
+
+use anyhow::Error;
+use reqwest::{header::CONTENT_TYPE, StatusCode};
+use serde::Serialize;
+use tracing::{debug, instrument};
+
+use crate::{
+    configuration::main::{PeerAuthentication, WebhookConfig},
+    data::{runlog::RunLogType, Report, RunInfo, RunLog},
+    error::RudderError,
+    http_client::HttpClient,
+};
+
+/// Runlog in the body of the webhook requests, which contain a list of them
+#[derive(Serialize, Debug)]
+struct WebhookRunlog<'a> {
+    run_info: &'a RunInfo,
+    config_id: &'a Option<String>,
+    log_type: RunLogType,
+    reports: &'a [Report],
+}
+
+/// Creates the client for the webhook with the configured certificate verification
+pub fn client(cfg: &WebhookConfig, idle_timeout: Duration) -> Result<HttpClient, Error> {
+    if cfg.url.is_empty() {
+        return Err(Error::msg(
+            "output.webhook.url is needed for webhook output",
+        ));
+    }
+    let builder = HttpClient::builder(idle_timeout);
+    match cfg.peer_authentication {
+        PeerAuthentication::CertPinning => {
+            let path = cfg.server_certificate_file.as_ref().ok_or_else(|| {
+                Error::msg("output.webhook.server_certificate_file is needed for cert_pinning")
+            })?;
+            builder.pinned(vec![fs::read(path)?])
+        }
+        PeerAuthentication::SystemRootCerts => builder.system(),
+        PeerAuthentication::DangerousNone => builder.no_verify(),
+    }
+}
+
+/// Sends the runlogs with a single request.
+///
+/// Server errors, timeouts and rate limiting can be retried. Other refused requests
+/// give a `RudderError`, considered permanent.
+#[instrument(name = "webhook", level = "debug", skip(client, cfg, runlogs), fields(runlogs = runlogs.len()))]
+pub async fn send_runlogs(
+    client: &HttpClient,
+    cfg: &WebhookConfig,
+    runlogs: &[&RunLog],
+) -> Result<(), Error> {
+    let body: Vec<WebhookRunlog> = runlogs
+        .iter()
+        .map(|runlog| WebhookRunlog {
+            run_info: &runlog.info,
+            config_id: &runlog.config_id,
+            log_type: runlog.log_type(),
+            reports: &runlog.reports,
+        })
+        .collect();
+
+    let mut request = client
+        .inner()
+        .post(&cfg.url)
+        .timeout(cfg.timeout)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&body)?);
+    for (name, value) in &cfg.headers {
+        request = request.header(name, value);
+    }
+    if let Some(ref token) = cfg.token {
+        request = request.bearer_auth(token.value());
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    if status.is_client_error()
+        && status != StatusCode::REQUEST_TIMEOUT
+        && status != StatusCode::TOO_MANY_REQUESTS
+    {
+        return Err(RudderError::WebhookRefused(status.as_u16()).into());
+    }
+    // Other HTTP errors -> Err()
+    let response = response.error_for_status()?;
+    debug!("Server response: {:#?}", response);
+    Ok(())
+}
@@ -5,11 +5,25 @@ use std::path::PathBuf;
 
 use anyhow::Error;
 use tokio::fs::{remove_file, rename};
-use tracing::debug;
+use tracing::{debug, info, warn};
 
+use crate::{
+    error::RudderError,
+    processing::{
+        failed::{write_reason, FailureReason},
+        retry::{RetryDecision, RetryQueue},
+    },
+};
+
+pub mod compliance;
+pub mod failed;
 pub mod inventory;
+pub mod ordering;
 pub mod reporting;
+pub mod retry;
 pub mod shared_files;
+pub mod sinks;
+pub mod workers;
 
 pub type ReceivedFile = PathBuf;
 pub type RootDirectory = PathBuf;
@@ -31,6 +45,9 @@ impl From<Error> for OutputError {
         if let Some(_e) = err.downcast_ref::<reqwest::Error>() {
             return OutputError::Transient;
         }
+        if let Some(RudderError::OutputFile(_)) = err.downcast_ref::<RudderError>() {
+            return OutputError::Transient;
+        }
 
         OutputError::Permanent
     }
@@ -43,21 +60,40 @@ async fn success(file: ReceivedFile) -> Result<(), Error> {
     Ok(())
 }
 
-async fn failure(file: ReceivedFile, directory: RootDirectory) -> Result<(), Error> {
-    rename(
-        file.clone(),
-        directory
-            .join("failed")
-            .join(file.file_name().expect("not a file")),
-    )
-    .await?;
-
-    debug!(
-        "moved: {:#?} to {:#?}",
-        file,
-        directory
-            .join("failed")
-            .join(file.file_name().expect("not a file"))
-    );
-    Ok(())
+/// Move the file to `failed/`, with the reason stored next to it
+async fn failure(
+    file: ReceivedFile,
+    directory: RootDirectory,
+    reason: FailureReason,
+) -> Result<(), Error> {
+    let failed = directory
+        .join("failed")
+        .join(file.file_name().expect("not a file"));
+    rename(file.clone(), &failed).await?;
+
+    debug!("moved: {:#?} to {:#?}", file, failed);
+    write_reason(&failed, &reason).await
+}
+
+/// Keep the file in place for a later attempt, or move it to `failed/`
+/// once the maximum number of attempts is reached.
+///
+/// Returns `true` when the file was given up.
+async fn transient_failure(
+    file: ReceivedFile,
+    directory: RootDirectory,
+    retry: &RetryQueue,
+    reason: FailureReason,
+) -> Result<bool, Error> {
+    match retry.failed(&file).await? {
+        RetryDecision::RetryAt(next) => {
+            info!("transient error, next attempt after {}", next);
+            Ok(false)
+        }
+        RetryDecision::GiveUp => {
+            warn!("transient error, maximum number of attempts reached, giving up");
+            failure(file, directory, reason).await?;
+            Ok(true)
+        }
+    }
 }
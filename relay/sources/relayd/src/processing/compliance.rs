@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::{collections::HashMap, time::Duration};
+
+use chrono::Utc;
+use tokio::sync::RwLock;
+use tracing::debug;
+
+use crate::{
+    data::{
+        compliance::{ComplianceCounts, ComplianceSummary},
+        node::{NodeId, NodesList},
+    },
+    metrics::{COMPLIANCE_NODES, COMPLIANCE_RECENT_NODES, COMPLIANCE_REPORTS},
+};
+
+/// Runs more recent than this are counted in `COMPLIANCE_RECENT_NODES`
+const RECENT_RUN: Duration = Duration::from_secs(3600);
+
+/// Compliance of the last run received from each node.
+///
+/// Only kept in memory, it is filled again by the next runs after a restart.
+#[derive(Debug, Default)]
+pub struct ComplianceStore {
+    summaries: RwLock<HashMap<NodeId, ComplianceSummary>>,
+}
+
+impl ComplianceStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs older than the known one for the node are ignored
+    pub async fn update(&self, summary: ComplianceSummary) {
+        let mut summaries = self.summaries.write().await;
+        if let Some(known) = summaries.get(&summary.node_id) {
+            if known.date > summary.date {
+                debug!(
+                    "Ignoring compliance of run {} for {}, a more recent run is known",
+                    summary.date, summary.node_id
+                );
+                return;
+            }
+        }
+
+        summaries.insert(summary.node_id.clone(), summary);
+    }
+
+    pub async fn get(&self, node_id: &str) -> Option<ComplianceSummary> {
+        self.summaries.read().await.get(node_id).cloned()
+    }
+
+    /// Sorted by node id
+    pub async fn list(&self) -> Vec<ComplianceSummary> {
+        let mut summaries: Vec<ComplianceSummary> =
+            self.summaries.read().await.values().cloned().collect();
+        summaries.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        summaries
+    }
+
+    /// Relay-wide gauges, computed when metrics are requested as the
+    /// recent nodes depend on the current time
+    pub async fn update_metrics(&self) {
+        let summaries = self.summaries.read().await;
+        let mut totals = [0; 8];
+        for summary in summaries.values() {
+            for (total, value) in totals.iter_mut().zip(summary.total.values()) {
+                *total += value;
+            }
+        }
+        for (status, total) in ComplianceCounts::STATUSES.iter().zip(totals) {
+            COMPLIANCE_REPORTS
+                .with_label_values(&[status])
+                .set(total as i64);
+        }
+        COMPLIANCE_NODES.set(summaries.len() as i64);
+        let recent = Utc::now()
+            - chrono::Duration::from_std(RECENT_RUN).expect("recent run delay out of range");
+        COMPLIANCE_RECENT_NODES.set(summaries.values().filter(|s| s.date >= recent).count() as i64);
+    }
+
+    /// Forget nodes which are not behind this relay anymore
+    pub async fn retain(&self, nodes: &NodesList) {
+        self.summaries
+            .write()
+            .await
+            .retain(|node_id, _| nodes.is_subnode(node_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryFrom, str::FromStr};
+
+    use crate::data::{RunInfo, RunLog};
+
+    use super::*;
+
+    fn summary(file_name: &str) -> ComplianceSummary {
+        let info = RunInfo::from_str(file_name).unwrap();
+        let runlog = RunLog::try_from((
+            info,
+            "2018-08-24T15:55:01+00:00 R: @@Common@@control@@rudder@@run@@0@@start@@20180824-130007-3ad37587@@2018-08-24 15:55:01+00:00##e745a140-40bc-4b86-b6dc-084488fc906b@#Start execution\n",
+        ))
+        .unwrap();
+        ComplianceSummary::from(&runlog)
+    }
+
+    #[tokio::test]
+    async fn it_keeps_last_run_compliance() {
+        let store = ComplianceStore::new();
+        let newer = summary("2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log");
+        let older = summary("2018-08-24T14:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log");
+
+        store.update(newer.clone()).await;
+        store.update(older).await;
+        assert_eq!(
+            store.get("e745a140-40bc-4b86-b6dc-084488fc906b").await,
+            Some(newer.clone())
+        );
+        assert_eq!(store.list().await, vec![newer]);
+        assert_eq!(store.get("root").await, None);
+    }
+
+    #[tokio::test]
+    async fn it_updates_relay_wide_metrics() {
+        let store = ComplianceStore::new();
+        let mut old = summary("2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log");
+        old.total.success = 2;
+        let mut recent = old.clone();
+        recent.node_id = "root".to_string();
+        recent.date = Utc::now().into();
+        recent.total.success = 3;
+        recent.total.error = 1;
+        store.update(old).await;
+        store.update(recent).await;
+
+        store.update_metrics().await;
+        assert_eq!(COMPLIANCE_REPORTS.with_label_values(&["success"]).get(), 5);
+        assert_eq!(COMPLIANCE_REPORTS.with_label_values(&["error"]).get(), 1);
+        assert_eq!(COMPLIANCE_REPORTS.with_label_values(&["na"]).get(), 0);
+        assert_eq!(COMPLIANCE_NODES.get(), 2);
+        assert_eq!(COMPLIANCE_RECENT_NODES.get(), 1);
+    }
+}
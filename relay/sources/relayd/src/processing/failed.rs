@@ -0,0 +1,307 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::{
+    convert::TryFrom,
+    ffi::OsStr,
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use filetime::{set_file_mtime, FileTime};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{read, read_dir, remove_file, rename, write};
+use tracing::{debug, warn};
+
+use crate::{
+    data::{
+        node::NodeId,
+        report::{ParseDiagnostic, ParseDiagnostics},
+        RunInfo,
+    },
+    error::RudderError,
+    processing::RootDirectory,
+};
+
+/// Failure reasons are stored next to the failed file, with this extension added
+const REASON_EXTENSION: &str = "json";
+/// Directories where files can be taken from, and replayed into
+const SOURCE_DIRECTORIES: &[&str] = &["incoming", "accepted-nodes-updates"];
+const DEFAULT_SOURCE_DIRECTORY: &str = "incoming";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureStage {
+    /// The file does not come from a known node
+    Validation,
+    /// The file could not be read or decompressed
+    Reading,
+    /// Invalid signature or missing certificate
+    Signature,
+    Parsing,
+    /// Refused by the database or the upstream server
+    Output,
+}
+
+impl From<&Error> for FailureStage {
+    /// Guess the stage from the error type
+    fn from(error: &Error) -> Self {
+        if let Some(e) = error.downcast_ref::<RudderError>() {
+            return match e {
+                RudderError::UnknownNode(_) | RudderError::InvalidInventory(_) => {
+                    FailureStage::Validation
+                }
+                RudderError::MissingCertificateForNode(_)
+                | RudderError::CertificateForUnknownNode(_)
+                | RudderError::MissingIdInCertificate
+                | RudderError::InvalidInventorySignature(_)
+                | RudderError::MissingInventorySignature(_)
+                | RudderError::InvalidHeader(_) => FailureStage::Signature,
+                RudderError::InvalidRunLog(_)
+                | RudderError::InvalidRunInfo(_)
+                | RudderError::InconsistentRunlog
+                | RudderError::EmptyRunlog => FailureStage::Parsing,
+                _ => FailureStage::Output,
+            };
+        }
+        if error.downcast_ref::<openssl::error::ErrorStack>().is_some() {
+            return FailureStage::Signature;
+        }
+        if error.downcast_ref::<io::Error>().is_some()
+            || error.downcast_ref::<zip::result::ZipError>().is_some()
+        {
+            return FailureStage::Reading;
+        }
+        FailureStage::Output
+    }
+}
+
+/// Why a file was moved to a `failed/` directory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FailureReason {
+    pub date: DateTime<Utc>,
+    /// Only known for reports, from the file name
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<NodeId>,
+    pub stage: FailureStage,
+    /// Error and its causes, outermost first
+    pub errors: Vec<String>,
+    /// Directory the file was taken from, relative to the base directory
+    pub source: String,
+    /// Parts of the runlog that could not be parsed
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<ParseDiagnostic>,
+}
+
+impl FailureReason {
+    pub fn new(file: &Path, stage: FailureStage, error: &Error) -> Self {
+        Self {
+            date: Utc::now(),
+            node_id: RunInfo::try_from(file).ok().map(|i| i.node_id),
+            stage,
+            errors: error.chain().map(|e| e.to_string()).collect(),
+            source: file
+                .parent()
+                .and_then(Path::file_name)
+                .map(|d| d.to_string_lossy().to_string())
+                .unwrap_or_else(|| DEFAULT_SOURCE_DIRECTORY.to_string()),
+            diagnostics: error
+                .downcast_ref::<ParseDiagnostics>()
+                .map(|d| d.0.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Stage is guessed from the error
+    pub fn from_error(file: &Path, error: &Error) -> Self {
+        Self::new(file, FailureStage::from(error), error)
+    }
+}
+
+fn reason_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".");
+    path.push(REASON_EXTENSION);
+    PathBuf::from(path)
+}
+
+fn is_reason(file: &Path) -> bool {
+    file.extension() == Some(OsStr::new(REASON_EXTENSION))
+}
+
+/// Store the reason next to an already moved file
+pub async fn write_reason(failed_file: &Path, reason: &FailureReason) -> Result<(), Error> {
+    write(reason_path(failed_file), serde_json::to_vec_pretty(reason)?).await?;
+    Ok(())
+}
+
+async fn read_reason(failed_file: &Path) -> Option<FailureReason> {
+    let content = read(reason_path(failed_file)).await.ok()?;
+    serde_json::from_slice(&content)
+        .map_err(|e| warn!("invalid failure reason for {:?}: {}", failed_file, e))
+        .ok()
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FailedFile {
+    pub file: String,
+    /// Missing for files that failed before reasons were recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<FailureReason>,
+}
+
+/// List failed files of a processing directory, sorted by name
+pub async fn list(directory: &RootDirectory) -> Result<Vec<FailedFile>, Error> {
+    let mut files = vec![];
+    let mut entries = read_dir(directory.join("failed")).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if is_reason(&path) || !entry.file_type().await?.is_file() {
+            continue;
+        }
+        files.push(FailedFile {
+            file: entry.file_name().to_string_lossy().to_string(),
+            reason: read_reason(&path).await,
+        });
+    }
+    files.sort_by(|a, b| a.file.cmp(&b.file));
+    Ok(files)
+}
+
+/// Move a failed file back to the directory it was taken from, to process it again.
+///
+/// Returns the new path of the file.
+pub async fn replay(directory: &RootDirectory, file_name: &str) -> Result<PathBuf, Error> {
+    // Only accept plain file names
+    if Path::new(file_name).file_name() != Some(OsStr::new(file_name))
+        || is_reason(Path::new(file_name))
+    {
+        return Err(RudderError::InvalidFile(PathBuf::from(file_name)).into());
+    }
+    let failed = directory.join("failed").join(file_name);
+
+    let source = read_reason(&failed)
+        .await
+        .map(|r| r.source)
+        .filter(|s| SOURCE_DIRECTORIES.contains(&s.as_str()))
+        .unwrap_or_else(|| DEFAULT_SOURCE_DIRECTORY.to_string());
+    let target = directory.join(source).join(file_name);
+
+    // Don't let the cleanup of the target directory remove it right away
+    set_file_mtime(&failed, FileTime::now())?;
+    rename(&failed, &target).await?;
+    match remove_file(reason_path(&failed)).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+    debug!("replayed: {:#?} to {:#?}", failed, target);
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, File};
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::data::RunLog;
+
+    #[test]
+    fn it_guesses_failure_stage() {
+        assert_eq!(
+            FailureStage::from(&Error::from(RudderError::UnknownNode("a".to_string()))),
+            FailureStage::Validation
+        );
+        assert_eq!(
+            FailureStage::from(&Error::from(RudderError::EmptyRunlog)),
+            FailureStage::Parsing
+        );
+        assert_eq!(
+            FailureStage::from(&Error::from(io::Error::from(io::ErrorKind::NotFound))),
+            FailureStage::Reading
+        );
+    }
+
+    #[test]
+    fn it_keeps_parse_diagnostics() {
+        let file = Path::new("incoming/2018-08-24T15:55:01+00:00@root.log");
+        let error = RunLog::try_from((
+            RunInfo::try_from(file).unwrap(),
+            "garbage\n2018-08-24T15:55:01+00:00 R: @@Common@@broken\n",
+        ))
+        .unwrap_err();
+
+        let reason = FailureReason::from_error(file, &error);
+        assert_eq!(reason.stage, FailureStage::Parsing);
+        assert_eq!(
+            reason
+                .diagnostics
+                .iter()
+                .map(|d| d.line)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_lists_and_replays_failed_files() {
+        let dir = tempdir().unwrap();
+        let base = dir.path().to_path_buf();
+        for d in ["failed", "incoming", "accepted-nodes-updates"] {
+            create_dir_all(base.join(d)).unwrap();
+        }
+
+        let report = "2018-08-24T15:55:01+00:00@root.log";
+        let error = Error::from(RudderError::EmptyRunlog).context("could not insert");
+        let reason = FailureReason::from_error(&base.join("incoming").join(report), &error);
+        assert_eq!(reason.node_id, Some("root".to_string()));
+        assert_eq!(reason.stage, FailureStage::Parsing);
+        assert_eq!(reason.errors, vec!["could not insert", "empty run log"]);
+        File::create(base.join("failed").join(report)).unwrap();
+        write_reason(&base.join("failed").join(report), &reason)
+            .await
+            .unwrap();
+
+        let inventory = "node.xml";
+        let reason = FailureReason::new(
+            &base.join("accepted-nodes-updates").join(inventory),
+            FailureStage::Output,
+            &Error::msg("refused"),
+        );
+        File::create(base.join("failed").join(inventory)).unwrap();
+        write_reason(&base.join("failed").join(inventory), &reason)
+            .await
+            .unwrap();
+
+        // Failed before reasons were recorded
+        File::create(base.join("failed").join("old.log")).unwrap();
+
+        let failed = list(&base).await.unwrap();
+        assert_eq!(
+            failed.iter().map(|f| f.file.as_str()).collect::<Vec<_>>(),
+            vec![report, inventory, "old.log"]
+        );
+        assert_eq!(failed[1].reason, Some(reason));
+        assert_eq!(failed[2].reason, None);
+
+        assert!(replay(&base, "../incoming").await.is_err());
+        assert!(replay(&base, "node.xml.json").await.is_err());
+        assert!(replay(&base, "missing.log").await.is_err());
+
+        assert_eq!(
+            replay(&base, inventory).await.unwrap(),
+            base.join("accepted-nodes-updates").join(inventory)
+        );
+        assert_eq!(
+            replay(&base, "old.log").await.unwrap(),
+            base.join("incoming").join("old.log")
+        );
+        assert!(!base.join("failed").join("node.xml.json").exists());
+        assert_eq!(list(&base).await.unwrap().len(), 1);
+    }
+}
@@ -1,32 +1,67 @@
 // SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use std::{os::unix::ffi::OsStrExt, sync::Arc};
+use std::{
+    collections::HashMap, iter, os::unix::ffi::OsStrExt, path::Path, sync::Arc, time::Duration,
+};
 
 use anyhow::Error;
 use md5::{Digest, Md5};
-use tokio::sync::mpsc;
-use tracing::{debug, error, info, instrument, span, Instrument, Level};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, error, instrument, span, warn, Instrument, Level};
 
 use crate::{
     configuration::main::InventoryOutputSelect,
-    input::watch::*,
+    data::node::{NodeId, NodesList},
+    error::RudderError,
+    hashing::Hash,
+    input::{
+        inventory::{is_signature, signature_path, signed_path, validate},
+        watch::*,
+    },
     metrics::INVENTORIES,
     output::upstream::send_inventory,
-    processing::{failure, success, OutputError, ReceivedFile},
+    processing::{
+        failed::FailureReason,
+        failure,
+        retry::RetryQueue,
+        success, transient_failure,
+        workers::{Active, Workers},
+        OutputError, ReceivedFile, RootDirectory,
+    },
     JobConfig,
 };
 
-static INVENTORY_EXTENSIONS: &[&str] = &["gz", "xml", "ocs", "sign"];
+static INVENTORY_EXTENSIONS: &[&str] = &["gz", "zst", "xz", "xml", "ocs", "sign"];
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
 pub enum InventoryType {
     New,
     Update,
 }
 
+/// Returns the processing tasks, to allow waiting for in-flight inventories on shutdown
 #[instrument(name = "inventory", level = "debug", skip(job_config))]
-pub fn start(job_config: &Arc<JobConfig>) {
+pub fn start(job_config: &Arc<JobConfig>) -> Vec<JoinHandle<Result<(), ()>>> {
+    // Shared by both queues as they use the same base directory
+    let retry = match RetryQueue::new(
+        "inventories",
+        &job_config.cfg.processing.inventory.directory,
+        job_config.cfg.processing.inventory.retry,
+    ) {
+        Ok(r) => Arc::new(r),
+        Err(e) => {
+            error!("could not load inventory retry state: {}", e);
+            return vec![];
+        }
+    };
+
     let (sender, receiver) = mpsc::channel(1_024);
 
     let incoming_path = job_config
@@ -35,7 +70,12 @@ pub fn start(job_config: &Arc<JobConfig>) {
         .inventory
         .directory
         .join("incoming");
-    tokio::spawn(serve(job_config.clone(), receiver, InventoryType::New));
+    let new_server = tokio::spawn(serve(
+        job_config.clone(),
+        receiver,
+        InventoryType::New,
+        retry.clone(),
+    ));
     tokio::spawn(cleanup(
         incoming_path.clone(),
         job_config.cfg.processing.inventory.cleanup,
@@ -44,6 +84,7 @@ pub fn start(job_config: &Arc<JobConfig>) {
         incoming_path,
         job_config.cfg.processing.inventory.catchup,
         sender,
+        job_config.shutdown_signal(),
     );
 
     let updates_path = job_config
@@ -53,7 +94,12 @@ pub fn start(job_config: &Arc<JobConfig>) {
         .directory
         .join("accepted-nodes-updates");
     let (sender, receiver) = mpsc::channel(1_024);
-    tokio::spawn(serve(job_config.clone(), receiver, InventoryType::Update));
+    let update_server = tokio::spawn(serve(
+        job_config.clone(),
+        receiver,
+        InventoryType::Update,
+        retry,
+    ));
     tokio::spawn(cleanup(
         updates_path.clone(),
         job_config.cfg.processing.inventory.cleanup,
@@ -62,15 +108,49 @@ pub fn start(job_config: &Arc<JobConfig>) {
         updates_path,
         job_config.cfg.processing.inventory.catchup,
         sender,
+        job_config.shutdown_signal(),
     );
+
+    vec![new_server, update_server]
 }
 
 async fn serve(
     job_config: Arc<JobConfig>,
     mut rx: mpsc::Receiver<ReceivedFile>,
     inventory_type: InventoryType,
+    retry: Arc<RetryQueue>,
 ) -> Result<(), ()> {
-    while let Some(file) = rx.recv().await {
+    let mut shutdown = job_config.shutdown_signal();
+    let job_config_workers = job_config.clone();
+    let retry_workers = retry.clone();
+    let workers = Workers::new(
+        "inventories",
+        job_config.cfg.processing.inventory.workers,
+        move |_, rx| {
+            work(
+                job_config_workers.clone(),
+                rx,
+                inventory_type,
+                retry_workers.clone(),
+            )
+        },
+    );
+
+    loop {
+        // Stop taking new files on shutdown, the remaining ones stay in place
+        // and will be picked up by the catchup after restart
+        let file = tokio::select! {
+            biased;
+            _ = shutdown.requested() => {
+                debug!("shutdown requested, stopping inventory queue");
+                break;
+            }
+            file = rx.recv() => match file {
+                Some(f) => f,
+                None => break,
+            },
+        };
+
         // allows skipping temporary .dav files
         if !file
             .extension()
@@ -84,20 +164,287 @@ async fn serve(
             continue;
         }
 
+        // backoff after transient errors, tracked with the inventory of the pair
+        let inventory = if is_signature(&file) {
+            signed_path(&file)
+        } else {
+            file.clone()
+        };
+        if !retry.is_due(&inventory).await {
+            debug!("skipping {:#?} as its next attempt is not due yet", file);
+            continue;
+        }
+
+        // Inventories from a node (and their signature) are processed in order
+        let key = inventory_key(&file);
+        workers.dispatch(&key, file).await;
+    }
+
+    workers.join().await;
+    Ok(())
+}
+
+/// Node inventory file name, without compression and signature extensions
+pub(crate) fn inventory_key(file: &Path) -> String {
+    let mut name = file
+        .file_name()
+        .unwrap_or(file.as_os_str())
+        .to_string_lossy()
+        .to_string();
+    while let Some((stem, ext)) = name.rsplit_once('.') {
+        if !["gz", "zst", "xz", "sign"].contains(&ext) {
+            break;
+        }
+        name = stem.to_string();
+    }
+    name
+}
+
+/// Node id at the end of inventory file names (`hostname-uuid.ocs`)
+pub(crate) fn inventory_node_id(file: &Path) -> Option<NodeId> {
+    let key = inventory_key(file);
+    let name = key
+        .strip_suffix(".ocs")
+        .or_else(|| key.strip_suffix(".xml"))
+        .unwrap_or(&key);
+    if name == "root" || name.ends_with("-root") {
+        return Some("root".to_string());
+    }
+    let id = name.get(name.len().checked_sub(36)?..)?;
+    let is_uuid = id.char_indices().all(|(i, c)| match i {
+        8 | 13 | 18 | 23 => c == '-',
+        _ => c.is_ascii_hexdigit(),
+    });
+    is_uuid.then(|| id.to_string())
+}
+
+/// An inventory with its signature, sent and moved together
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct InventoryPair {
+    inventory: ReceivedFile,
+    /// Missing when it was not received before the pairing timeout
+    signature: Option<ReceivedFile>,
+}
+
+impl InventoryPair {
+    /// Both files, in forwarding order
+    fn files(&self) -> impl Iterator<Item = &ReceivedFile> {
+        self.signature.iter().chain(iter::once(&self.inventory))
+    }
+
+    async fn succeeded(self) -> Result<(), Error> {
+        for file in self.files() {
+            success(file.clone()).await?;
+        }
+        Ok(())
+    }
+
+    async fn failed(self, directory: RootDirectory, reason: FailureReason) -> Result<(), Error> {
+        for file in self.files() {
+            failure(file.clone(), directory.clone(), reason.clone()).await?;
+        }
+        Ok(())
+    }
+
+    /// Retries are tracked with the inventory file.
+    ///
+    /// Returns `true` when the pair was given up.
+    async fn failed_transiently(
+        self,
+        directory: RootDirectory,
+        retry: &RetryQueue,
+        reason: FailureReason,
+    ) -> Result<bool, Error> {
+        let given_up =
+            transient_failure(self.inventory, directory.clone(), retry, reason.clone()).await?;
+        if given_up {
+            if let Some(signature) = self.signature {
+                failure(signature, directory, reason).await?;
+            }
+        }
+        Ok(given_up)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Pairing {
+    Complete(InventoryPair),
+    /// Inventory without signature after the pairing timeout
+    Unsigned(ReceivedFile),
+    /// Signature without inventory after the pairing timeout
+    Orphan(ReceivedFile),
+}
+
+/// Holds the first received file of each inventory until the second one is there.
+///
+/// Files are checked on disk as they can be received in any order, or only once
+/// for both in case of catchup.
+#[derive(Debug)]
+struct PairingBuffer {
+    timeout: Duration,
+    /// Inventory path, and when the first file of the pair was received
+    pending: HashMap<ReceivedFile, Instant>,
+}
+
+impl PairingBuffer {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Returns the pair when both files are present
+    fn push(&mut self, file: ReceivedFile) -> Option<InventoryPair> {
+        // Already processed with the other file
+        if !file.exists() {
+            return None;
+        }
+        let (inventory, signature) = if is_signature(&file) {
+            (signed_path(&file), file)
+        } else {
+            let signature = signature_path(&file);
+            (file, signature)
+        };
+
+        if inventory.exists() && signature.exists() {
+            self.pending.remove(&inventory);
+            Some(InventoryPair {
+                inventory,
+                signature: Some(signature),
+            })
+        } else {
+            self.pending.entry(inventory).or_insert_with(Instant::now);
+            None
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .min()
+            .map(|received| *received + self.timeout)
+    }
+
+    /// Oldest incomplete pair, without waiting for its deadline
+    fn pop(&mut self) -> Option<Pairing> {
+        while let Some(inventory) = self
+            .pending
+            .iter()
+            .min_by_key(|(_, received)| **received)
+            .map(|(inventory, _)| inventory.clone())
+        {
+            self.pending.remove(&inventory);
+            let signature = signature_path(&inventory);
+            if inventory.exists() {
+                return Some(if signature.exists() {
+                    Pairing::Complete(InventoryPair {
+                        inventory,
+                        signature: Some(signature),
+                    })
+                } else {
+                    Pairing::Unsigned(inventory)
+                });
+            } else if signature.exists() {
+                return Some(Pairing::Orphan(signature));
+            }
+        }
+        None
+    }
+}
+
+/// Processes the inventories of a subset of the nodes
+async fn work(
+    job_config: Arc<JobConfig>,
+    mut rx: mpsc::Receiver<ReceivedFile>,
+    inventory_type: InventoryType,
+    retry: Arc<RetryQueue>,
+) {
+    let mut shutdown = job_config.shutdown_signal();
+    // Incomplete pairs are left in place on shutdown, for the catchup
+    let mut buffer = PairingBuffer::new(job_config.cfg.processing.inventory.pairing_timeout);
+    loop {
+        let deadline = buffer.deadline().unwrap_or_else(Instant::now);
+        let pairing = tokio::select! {
+            biased;
+            _ = shutdown.requested() => break,
+            _ = sleep_until(deadline), if !buffer.is_empty() => match buffer.pop() {
+                Some(p) => p,
+                None => continue,
+            },
+            file = rx.recv() => match file {
+                Some(f) => {
+                    debug!("received: {:?}", f);
+                    match buffer.push(f) {
+                        Some(p) => Pairing::Complete(p),
+                        None => continue,
+                    }
+                }
+                None => break,
+            },
+        };
+        let _active = Active::new("inventories");
+
+        let pair = match pairing {
+            Pairing::Complete(p) => p,
+            Pairing::Unsigned(inventory) if job_config.cfg.processing.inventory.allow_unsigned => {
+                warn!("no signature received for {:?}", inventory);
+                InventoryPair {
+                    inventory,
+                    signature: None,
+                }
+            }
+            Pairing::Unsigned(inventory) => {
+                unsigned_inventory(inventory, &job_config, &retry)
+                    .await
+                    .unwrap_or_else(|e| error!("output error: {}", e));
+                continue;
+            }
+            Pairing::Orphan(signature) => {
+                orphan_signature(signature, &job_config)
+                    .await
+                    .unwrap_or_else(|e| error!("output error: {}", e));
+                continue;
+            }
+        };
+        // The second file can be received during the backoff delay
+        if !retry.is_due(&pair.inventory).await {
+            continue;
+        }
+
         let queue_id = format!(
             "{:X}",
-            Md5::digest(file.file_name().unwrap_or(file.as_os_str()).as_bytes())
+            Md5::digest(
+                pair.inventory
+                    .file_name()
+                    .unwrap_or(pair.inventory.as_os_str())
+                    .as_bytes()
+            )
         );
-        debug!("received: {:?}", file);
-
         let span = span!(
             Level::INFO,
             "inventory",
             queue_id = %queue_id,
         );
+        if job_config.cfg.processing.inventory.validation.is_enabled()
+            && !validate_inventory(pair.clone(), inventory_type, &job_config, &retry)
+                .instrument(span.clone())
+                .await
+        {
+            continue;
+        }
         match job_config.cfg.processing.inventory.output {
             InventoryOutputSelect::Upstream => {
-                output_inventory_upstream(file, inventory_type, job_config.clone())
+                output_inventory_upstream(pair, inventory_type, job_config.clone(), &retry)
+                    .instrument(span)
+                    .await
+            }
+            InventoryOutputSelect::Directory => {
+                output_inventory_directory(pair, inventory_type, job_config.clone())
                     .instrument(span)
                     .await
             }
@@ -106,41 +453,335 @@ async fn serve(
         }
         .unwrap_or_else(|e| error!("output error: {}", e));
     }
+}
+
+/// A signature is useless without its inventory
+async fn orphan_signature(signature: ReceivedFile, job_config: &JobConfig) -> Result<(), Error> {
+    let e = RudderError::InvalidInventory("no inventory received for signature".to_string()).into();
+    error!("{:?}: {}", signature, e);
+    INVENTORIES.with_label_values(&["invalid"]).inc();
+    let reason = FailureReason::from_error(&signature, &e);
+    failure(
+        signature,
+        job_config.cfg.processing.inventory.directory.clone(),
+        reason,
+    )
+    .await
+}
+
+/// Unsigned inventories are not forwarded unless explicitly allowed
+async fn unsigned_inventory(
+    inventory: ReceivedFile,
+    job_config: &JobConfig,
+    retry: &RetryQueue,
+) -> Result<(), Error> {
+    let e = RudderError::MissingInventorySignature(signature_path(&inventory)).into();
+    error!("{:?}: {}", inventory, e);
+    INVENTORIES.with_label_values(&["invalid"]).inc();
+    let reason = FailureReason::from_error(&inventory, &e);
+    retry.forget(&inventory).await?;
+    failure(
+        inventory,
+        job_config.cfg.processing.inventory.directory.clone(),
+        reason,
+    )
+    .await
+}
+
+/// Only accepted nodes have a known key. When signatures are checked, updates
+/// must be signed with it.
+fn expected_key_hash(
+    inventory: &Path,
+    inventory_type: InventoryType,
+    signature: bool,
+    nodes: &NodesList,
+) -> Result<Option<Hash>, Error> {
+    if !signature || inventory_type == InventoryType::New {
+        return Ok(None);
+    }
+    let id = inventory_node_id(inventory).ok_or_else(|| {
+        RudderError::InvalidInventorySignature(format!(
+            "no node id in inventory name {:?}",
+            inventory
+        ))
+    })?;
+    match nodes.key_hash(&id) {
+        Ok(hash) => Ok(Some(hash)),
+        Err(e) => match e.downcast::<RudderError>() {
+            Ok(RudderError::UnknownNode(id)) => Err(RudderError::UnknownNode(id).into()),
+            Ok(e) => Err(RudderError::InvalidInventorySignature(e.to_string()).into()),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+async fn known_key_hash(
+    pair: &InventoryPair,
+    inventory_type: InventoryType,
+    job_config: &JobConfig,
+) -> Result<Option<Hash>, Error> {
+    expected_key_hash(
+        &pair.inventory,
+        inventory_type,
+        job_config.cfg.processing.inventory.validation.signature,
+        &*job_config.nodes.read().await,
+    )
+}
+
+/// Returns `false` when the inventory should not be sent. Inventories waiting
+/// for their signature are retried, other invalid ones are moved to failed.
+async fn validate_inventory(
+    pair: InventoryPair,
+    inventory_type: InventoryType,
+    job_config: &JobConfig,
+    retry: &RetryQueue,
+) -> bool {
+    let cfg = job_config.cfg.processing.inventory.validation;
+    let result = match known_key_hash(&pair, inventory_type, job_config).await {
+        Ok(known_key_hash) => validate(&pair.inventory, cfg, known_key_hash.as_ref()).await,
+        Err(e) => Err(e),
+    };
+    let e = match result {
+        Ok(()) => return true,
+        Err(e) => e,
+    };
+    let reason = FailureReason::from_error(&pair.inventory, &e);
+    let directory = job_config.cfg.processing.inventory.directory.clone();
+    let result =
+        if let Some(RudderError::MissingInventorySignature(_)) = e.downcast_ref::<RudderError>() {
+            debug!("{}", e);
+            pair.failed_transiently(directory, retry, reason)
+                .await
+                .map(|given_up| {
+                    if given_up {
+                        INVENTORIES.with_label_values(&["invalid"]).inc();
+                    }
+                })
+        } else {
+            error!("invalid inventory: {}", e);
+            INVENTORIES.with_label_values(&["invalid"]).inc();
+            retry
+                .forget(&pair.inventory)
+                .await
+                .unwrap_or_else(|e| error!("could not forget retries: {}", e));
+            pair.failed(directory, reason).await
+        };
+    result.unwrap_or_else(|e| error!("could not move invalid inventory: {}", e));
+    false
+}
+
+/// The signature is sent first, so that an inventory is never accepted upstream
+/// when its signature is refused
+async fn send_pair(
+    pair: &InventoryPair,
+    inventory_type: InventoryType,
+    job_config: Arc<JobConfig>,
+) -> Result<(), Error> {
+    for file in pair.files() {
+        send_inventory(job_config.clone(), file.clone(), inventory_type).await?;
+    }
     Ok(())
 }
 
 async fn output_inventory_upstream(
-    path: ReceivedFile,
+    pair: InventoryPair,
     inventory_type: InventoryType,
     job_config: Arc<JobConfig>,
+    retry: &RetryQueue,
 ) -> Result<(), Error> {
-    let job_config_clone = job_config.clone();
-    let path_clone2 = path.clone();
-
-    let result = send_inventory(job_config, path.clone(), inventory_type).await;
+    let result = send_pair(&pair, inventory_type, job_config.clone()).await;
 
     match result {
         Ok(_) => {
             INVENTORIES.with_label_values(&["forward_ok"]).inc();
-            success(path.clone()).await
+            retry.forget(&pair.inventory).await?;
+            pair.succeeded().await
         }
         Err(e) => {
             error!("output error: {}", e);
+            let reason = FailureReason::from_error(&pair.inventory, &e);
+            let directory = job_config.cfg.processing.inventory.directory.clone();
             match OutputError::from(e) {
                 OutputError::Permanent => {
                     INVENTORIES.with_label_values(&["forward_error"]).inc();
-
-                    failure(
-                        path_clone2.clone(),
-                        job_config_clone.cfg.processing.inventory.directory.clone(),
-                    )
-                    .await
+                    retry.forget(&pair.inventory).await?;
+                    pair.failed(directory, reason).await
                 }
                 OutputError::Transient => {
-                    info!("transient error, skipping");
+                    if pair.failed_transiently(directory, retry, reason).await? {
+                        INVENTORIES.with_label_values(&["forward_error"]).inc();
+                    }
                     Ok(())
                 }
             }
         }
     }
 }
+
+/// Errors are never transient as it only involves local files
+async fn output_inventory_directory(
+    pair: InventoryPair,
+    inventory_type: InventoryType,
+    job_config: Arc<JobConfig>,
+) -> Result<(), Error> {
+    let export = job_config
+        .inventory_export
+        .as_ref()
+        .expect("output uses directory but no export was configured");
+    let result = match known_key_hash(&pair, inventory_type, &job_config).await {
+        Ok(known_key_hash) => {
+            export
+                .export(
+                    &pair.inventory,
+                    pair.signature.as_deref(),
+                    inventory_type,
+                    known_key_hash.as_ref(),
+                )
+                .await
+        }
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(_) => {
+            INVENTORIES.with_label_values(&["export_ok"]).inc();
+            pair.succeeded().await
+        }
+        Err(e) => {
+            error!("output error: {}", e);
+            INVENTORIES.with_label_values(&["export_error"]).inc();
+            let reason = FailureReason::from_error(&pair.inventory, &e);
+            pair.failed(
+                job_config.cfg.processing.inventory.directory.clone(),
+                reason,
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn it_pairs_inventories_and_signatures() {
+        let dir = tempdir().unwrap();
+        let inventory = dir.path().join("node1-root.ocs.gz");
+        let signature = dir.path().join("node1-root.ocs.gz.sign");
+        let mut buffer = PairingBuffer::new(Duration::from_secs(60));
+
+        // Signature received first
+        write(&signature, "sign").unwrap();
+        assert_eq!(buffer.push(signature.clone()), None);
+        assert!(buffer.deadline().is_some());
+        write(&inventory, "inventory").unwrap();
+        assert_eq!(
+            buffer.push(inventory.clone()),
+            Some(InventoryPair {
+                inventory: inventory.clone(),
+                signature: Some(signature.clone()),
+            })
+        );
+        assert!(buffer.is_empty());
+
+        // Files removed after processing
+        std::fs::remove_file(&inventory).unwrap();
+        assert_eq!(buffer.push(inventory.clone()), None);
+        assert!(buffer.is_empty());
+
+        // Timeout expired
+        assert_eq!(buffer.push(signature.clone()), None);
+        assert_eq!(buffer.pop(), Some(Pairing::Orphan(signature.clone())));
+        std::fs::remove_file(&signature).unwrap();
+        write(&inventory, "inventory").unwrap();
+        assert_eq!(buffer.push(inventory.clone()), None);
+        assert_eq!(buffer.pop(), Some(Pairing::Unsigned(inventory.clone())));
+        assert_eq!(buffer.pop(), None);
+
+        // Signature received by the timeout, but not notified yet
+        assert_eq!(buffer.push(inventory.clone()), None);
+        write(&signature, "sign").unwrap();
+        assert_eq!(
+            buffer.pop(),
+            Some(Pairing::Complete(InventoryPair {
+                inventory: inventory.clone(),
+                signature: Some(signature.clone()),
+            }))
+        );
+
+        // Signature forwarded first
+        let pair = InventoryPair {
+            inventory: inventory.clone(),
+            signature: Some(signature.clone()),
+        };
+        assert_eq!(
+            pair.files().collect::<Vec<_>>(),
+            vec![&signature, &inventory]
+        );
+    }
+
+    #[test]
+    fn it_computes_inventory_key() {
+        assert_eq!(
+            inventory_key(Path::new("incoming/node.example.com-uuid.ocs.gz")),
+            "node.example.com-uuid.ocs"
+        );
+        assert_eq!(
+            inventory_key(Path::new("incoming/node.example.com-uuid.ocs.sign")),
+            "node.example.com-uuid.ocs"
+        );
+        assert_eq!(inventory_key(Path::new("node.xml")), "node.xml");
+    }
+
+    #[test]
+    fn it_finds_inventory_node_id() {
+        assert_eq!(
+            inventory_node_id(Path::new(
+                "incoming/node1.example.com-e745a140-40bc-4b86-b6dc-084488fc906b.ocs.gz.sign"
+            )),
+            Some("e745a140-40bc-4b86-b6dc-084488fc906b".to_string())
+        );
+        assert_eq!(
+            inventory_node_id(Path::new("server.example.com-root.ocs")),
+            Some("root".to_string())
+        );
+        assert_eq!(inventory_node_id(Path::new("node.xml")), None);
+    }
+
+    #[test]
+    fn it_requires_known_key_for_updates() {
+        let nodes = NodesList::new("root".to_string(), "tests/files/nodeslist.json", None).unwrap();
+        let known = Path::new("node3-a745a140-40bc-4b86-b6dc-084488fc906b.ocs");
+        let without_key = Path::new("node4-b745a140-40bc-4b86-b6dc-084488fc906b.ocs");
+        let unknown = Path::new("node9-f745a140-40bc-4b86-b6dc-084488fc906b.ocs");
+        let no_id = Path::new("node.ocs");
+
+        assert!(
+            expected_key_hash(known, InventoryType::Update, true, &nodes)
+                .unwrap()
+                .is_some()
+        );
+        for (path, expected) in [
+            (without_key, "invalid inventory signature"),
+            (no_id, "invalid inventory signature"),
+            (unknown, "unknown node"),
+        ] {
+            let e = expected_key_hash(path, InventoryType::Update, true, &nodes).unwrap_err();
+            assert!(e.to_string().starts_with(expected), "{}", e);
+        }
+        // New nodes have no known key, and nothing is needed without signature check
+        assert_eq!(
+            expected_key_hash(unknown, InventoryType::New, true, &nodes).unwrap(),
+            None
+        );
+        assert_eq!(
+            expected_key_hash(unknown, InventoryType::Update, false, &nodes).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,152 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use chrono::{DateTime, FixedOffset};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::{
+    data::{node::NodeId, RunInfo},
+    metrics::REPORTS_OUT_OF_ORDER,
+    processing::ReceivedFile,
+};
+
+/// Reorders the runlogs waiting in a worker queue by run date.
+///
+/// As all the runlogs of a node go to the same worker, this gives
+/// per-node ordering.
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    delay: Duration,
+    /// Sorted by run date, the same file can only be queued once
+    pending: BTreeMap<(DateTime<FixedOffset>, ReceivedFile), (RunInfo, Instant)>,
+    /// Date of the last successfully processed runlog of each node.
+    ///
+    /// It is only kept in memory, after a restart the first processed runlog
+    /// of each node is considered as the latest one.
+    last: HashMap<NodeId, DateTime<FixedOffset>>,
+}
+
+impl ReorderBuffer {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            pending: BTreeMap::new(),
+            last: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, file: ReceivedFile, info: RunInfo) {
+        self.pending
+            .entry((info.timestamp, file))
+            .or_insert_with(|| (info, Instant::now()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// When the oldest runlog can be processed
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .next()
+            .map(|(_, received)| *received + self.delay)
+    }
+
+    /// Oldest runlog, without waiting for its deadline
+    pub fn pop(&mut self) -> Option<(ReceivedFile, RunInfo)> {
+        let key = self.pending.keys().next()?.clone();
+        self.pending
+            .remove_entry(&key)
+            .map(|((_, file), (info, _))| (file, info))
+    }
+
+    /// Returns `false` if the runlog is older than the last processed
+    /// runlog of the node
+    pub fn is_latest(&self, info: &RunInfo) -> bool {
+        match self.last.get(&info.node_id) {
+            Some(last) if *last > info.timestamp => {
+                REPORTS_OUT_OF_ORDER.inc();
+                warn!(
+                    "runlog {} is older than the last processed one for this node ({})",
+                    info, last
+                );
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Keeps track of a runlog once its output succeeded, failed runlogs
+    /// are retried later and must not hide the older ones
+    pub fn processed(&mut self, info: &RunInfo) {
+        let last = self
+            .last
+            .entry(info.node_id.clone())
+            .or_insert(info.timestamp);
+        if *last < info.timestamp {
+            *last = info.timestamp;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, str::FromStr};
+
+    use super::*;
+
+    fn runlog(name: &str) -> (ReceivedFile, RunInfo) {
+        (
+            PathBuf::from("incoming").join(name),
+            RunInfo::from_str(name).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn it_reorders_runlogs() {
+        let mut buffer = ReorderBuffer::new(Duration::from_secs(0));
+        assert!(buffer.deadline().is_none());
+
+        let new = runlog("2018-08-24T15:55:01+00:00@root.log");
+        let old = runlog("2017-08-24T15:55:01+00:00@root.log");
+        let other = runlog("2018-01-24T15:55:01+00:00@node.log");
+        buffer.push(new.0.clone(), new.1.clone());
+        buffer.push(old.0.clone(), old.1.clone());
+        buffer.push(other.0.clone(), other.1.clone());
+        // Already queued
+        buffer.push(new.0.clone(), new.1.clone());
+        assert!(buffer.deadline().unwrap() <= Instant::now());
+
+        assert_eq!(buffer.pop(), Some(old.clone()));
+        assert_eq!(buffer.pop(), Some(other));
+        assert_eq!(buffer.pop(), Some(new.clone()));
+        assert!(buffer.is_empty());
+
+        assert!(buffer.is_latest(&new.1));
+        buffer.processed(&new.1);
+        assert!(!buffer.is_latest(&old.1));
+        // Older runlogs don't go back in time
+        buffer.processed(&old.1);
+        assert!(buffer.is_latest(&new.1));
+    }
+
+    #[test]
+    fn it_ignores_failed_runlogs() {
+        let mut buffer = ReorderBuffer::new(Duration::from_secs(0));
+        let new = runlog("2018-08-24T15:55:01+00:00@root.log");
+        let old = runlog("2017-08-24T15:55:01+00:00@root.log");
+
+        // Its output failed, it will be retried
+        assert!(buffer.is_latest(&new.1));
+        assert!(buffer.is_latest(&old.1));
+        buffer.processed(&old.1);
+        assert!(buffer.is_latest(&new.1));
+    }
+}
@@ -1,30 +1,54 @@
 // SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use std::{convert::TryFrom, os::unix::ffi::OsStrExt, sync::Arc};
+use std::{convert::TryFrom, mem, os::unix::ffi::OsStrExt, path::Path, sync::Arc};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use md5::{Digest, Md5};
-use tokio::{sync::mpsc, task::spawn_blocking};
-use tracing::{debug, error, info, instrument, span, warn, Instrument, Level};
+use prometheus::HistogramTimer;
+use tokio::{
+    fs::{remove_file, write},
+    sync::mpsc::{self, error::TryRecvError},
+    task::{spawn_blocking, JoinHandle},
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, error, instrument, span, warn, Instrument, Level};
 
 use crate::{
-    configuration::main::ReportingOutputSelect,
-    data::{RunInfo, RunLog},
-    input::{read_compressed_file, signature, watch::*},
-    metrics::{REPORTS, REPORTS_PROCESSING_DURATION, REPORTS_SIZE_BYTES},
+    configuration::main::{BatchConfig, ReportingOutputSelect},
+    data::{
+        compliance::ComplianceSummary,
+        runlog::{RunLogReader, RunlogDigest},
+        RunInfo, RunLog,
+    },
+    error::RudderError,
+    input::{read_compressed_file, watch::*, SignedMessage},
+    metrics::{REPORTS, REPORTS_BATCH_SIZE, REPORTS_PROCESSING_DURATION, REPORTS_SIZE_BYTES},
     output::{
-        database::{insert_runlog, RunlogInsertion},
+        database::{PendingRunlog, RunlogInsertion},
         upstream::send_report,
+        webhook::send_runlogs,
+    },
+    processing::{
+        failed::{FailureReason, FailureStage},
+        failure,
+        ordering::ReorderBuffer,
+        retry::RetryQueue,
+        sinks::Sink,
+        success, transient_failure,
+        workers::{Active, Workers},
+        OutputError, ReceivedFile,
     },
-    processing::{failure, success, OutputError, ReceivedFile},
     JobConfig,
 };
 
-static REPORT_EXTENSIONS: &[&str] = &["gz", "zip", "log"];
+static REPORT_EXTENSIONS: &[&str] = &["gz", "zip", "zst", "xz", "log"];
+/// Number of reports parsed and inserted at once when not batching runlogs
+const PARSING_BATCH_SIZE: usize = 1_000;
 
+/// Returns the processing task, to allow waiting for in-flight reports on shutdown
 #[instrument(name = "reporting", level = "debug", skip(job_config))]
-pub fn start(job_config: &Arc<JobConfig>) {
+pub fn start(job_config: &Arc<JobConfig>) -> Vec<JoinHandle<Result<(), ()>>> {
     let incoming_path = job_config
         .cfg
         .processing
@@ -32,6 +56,17 @@ pub fn start(job_config: &Arc<JobConfig>) {
         .directory
         .join("incoming");
     let failed_path = job_config.cfg.processing.reporting.directory.join("failed");
+    let retry = match RetryQueue::new(
+        "reports",
+        &job_config.cfg.processing.reporting.directory,
+        job_config.cfg.processing.reporting.retry,
+    ) {
+        Ok(r) => Arc::new(r),
+        Err(e) => {
+            error!("could not load reporting retry state: {}", e);
+            return vec![];
+        }
+    };
 
     let (sender, receiver) = mpsc::channel(1_024);
     tokio::spawn(cleanup(
@@ -42,17 +77,94 @@ pub fn start(job_config: &Arc<JobConfig>) {
         failed_path,
         job_config.cfg.processing.reporting.cleanup,
     ));
-    tokio::spawn(serve(job_config.clone(), receiver));
+    let server = tokio::spawn(serve(job_config.clone(), receiver, retry));
     watch(
         incoming_path,
         job_config.cfg.processing.reporting.catchup,
         sender,
+        job_config.shutdown_signal(),
     );
+    vec![server]
+}
+
+/// Parsed runlogs waiting to be inserted into the database or sent to the webhook together
+struct Batch {
+    cfg: BatchConfig,
+    entries: Vec<(ReceivedFile, PendingRunlog, Option<ComplianceSummary>)>,
+    /// Insertion time for the oldest entry
+    deadline: Option<Instant>,
 }
 
-/// Should run forever except for fatal errors
-async fn serve(job_config: Arc<JobConfig>, mut rx: mpsc::Receiver<ReceivedFile>) -> Result<(), ()> {
-    while let Some(file) = rx.recv().await {
+impl Batch {
+    fn new(cfg: BatchConfig) -> Self {
+        Self {
+            cfg,
+            entries: Vec::with_capacity(cfg.size),
+            deadline: None,
+        }
+    }
+
+    fn push(
+        &mut self,
+        file: ReceivedFile,
+        runlog: PendingRunlog,
+        summary: Option<ComplianceSummary>,
+    ) {
+        if self.entries.is_empty() {
+            self.deadline = Some(Instant::now() + self.cfg.delay);
+        }
+        self.entries.push((file, runlog, summary));
+    }
+
+    /// The same file can be received twice, by the watcher and the catchup
+    fn contains(&self, file: &Path) -> bool {
+        self.entries.iter().any(|(f, _, _)| f == file)
+    }
+
+    fn is_full(&self) -> bool {
+        self.entries.len() >= self.cfg.size
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn take(&mut self) -> Vec<(ReceivedFile, PendingRunlog, Option<ComplianceSummary>)> {
+        self.deadline = None;
+        mem::replace(&mut self.entries, Vec::with_capacity(self.cfg.size))
+    }
+}
+
+/// Should run until shutdown except for fatal errors
+async fn serve(
+    job_config: Arc<JobConfig>,
+    mut rx: mpsc::Receiver<ReceivedFile>,
+    retry: Arc<RetryQueue>,
+) -> Result<(), ()> {
+    let mut shutdown = job_config.shutdown_signal();
+    let job_config_workers = job_config.clone();
+    let retry_workers = retry.clone();
+    let workers = Workers::new(
+        "reports",
+        job_config.cfg.processing.reporting.workers,
+        move |_, rx| work(job_config_workers.clone(), rx, retry_workers.clone()),
+    );
+
+    loop {
+        // Stop taking new files on shutdown, the remaining ones stay in place
+        // and will be picked up by the catchup after restart
+        let file = tokio::select! {
+            biased;
+            _ = shutdown.requested() => {
+                debug!("shutdown requested, stopping report queue");
+                break;
+            }
+            file = rx.recv() => match file {
+                Some(f) => f,
+                None => break,
+            },
+        };
+
         // allows skipping temporary .dav files
         if !file
             .extension()
@@ -66,6 +178,105 @@ async fn serve(job_config: Arc<JobConfig>, mut rx: mpsc::Receiver<ReceivedFile>)
             continue;
         }
 
+        // backoff after transient errors
+        if !retry.is_due(&file).await {
+            debug!("skipping {:#?} as its next attempt is not due yet", file);
+            continue;
+        }
+
+        // Check run info
+        // FIXME make async
+        let info = match RunInfo::try_from(file.as_ref()) {
+            Ok(info) => info,
+            Err(e) => {
+                // Keep serving, and join the workers on shutdown
+                warn!("received: {}", e);
+                continue;
+            }
+        };
+
+        // Reports from a node are processed in order
+        let node_id = info.node_id.clone();
+        workers.dispatch(&node_id, (file, info)).await;
+    }
+
+    workers.join().await;
+    Ok(())
+}
+
+/// Processes the reports of a subset of the nodes
+async fn work(
+    job_config: Arc<JobConfig>,
+    mut rx: mpsc::Receiver<(ReceivedFile, RunInfo)>,
+    retry: Arc<RetryQueue>,
+) {
+    let mut shutdown = job_config.shutdown_signal();
+    let mut batch = if matches!(
+        job_config.cfg.processing.reporting.output,
+        ReportingOutputSelect::Database | ReportingOutputSelect::Webhook
+    ) && job_config.cfg.processing.reporting.batch.is_enabled()
+    {
+        Some(Batch::new(job_config.cfg.processing.reporting.batch))
+    } else {
+        None
+    };
+
+    let ordering = job_config.cfg.processing.reporting.ordering;
+    let mut buffer = ReorderBuffer::new(ordering.delay);
+    let mut closed = false;
+
+    loop {
+        // Sort all the runlogs already waiting
+        while !closed {
+            match rx.try_recv() {
+                Ok((file, info)) => buffer.push(file, info),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => closed = true,
+            }
+        }
+        if closed && buffer.is_empty() {
+            break;
+        }
+
+        let batch_deadline = batch
+            .as_ref()
+            .and_then(|b| b.deadline)
+            .unwrap_or_else(Instant::now);
+        // Process the remaining runlogs without waiting when the queue is closed
+        let buffer_deadline = match buffer.deadline() {
+            Some(d) if !closed => d,
+            _ => Instant::now(),
+        };
+
+        let (file, info) = tokio::select! {
+            biased;
+            _ = shutdown.requested() => break,
+            _ = sleep_until(batch_deadline), if batch.as_ref().map(|b| !b.is_empty()).unwrap_or(false) => {
+                if let Some(b) = batch.as_mut() {
+                    let _active = Active::new("reports");
+                    for info in flush_batch(job_config.clone(), b.take(), &retry).await {
+                        buffer.processed(&info);
+                    }
+                }
+                continue;
+            }
+            _ = sleep_until(buffer_deadline), if !buffer.is_empty() => match buffer.pop() {
+                Some(r) => r,
+                None => continue,
+            },
+            file = rx.recv(), if !closed => {
+                match file {
+                    Some((file, info)) => buffer.push(file, info),
+                    None => closed = true,
+                }
+                continue;
+            }
+        };
+        let _active = Active::new("reports");
+
+        // Older runlogs are still inserted, but can't replace the last known run
+        let record_execution = buffer.is_latest(&info) || ordering.record_older_executions;
+
         let queue_id = format!(
             "{:X}",
             Md5::digest(file.file_name().unwrap_or(file.as_os_str()).as_bytes())
@@ -75,138 +286,699 @@ async fn serve(job_config: Arc<JobConfig>, mut rx: mpsc::Receiver<ReceivedFile>)
             "report",
             queue_id = %queue_id,
         );
-
-        // Check run info
-        // FIXME make async
-        let info = RunInfo::try_from(file.as_ref()).map_err(|e| warn!("received: {}", e))?;
-
         let node_span = span!(
             Level::INFO,
             "node",
             node_id = %info.node_id,
         );
 
-        handle_report(job_config.clone(), info, file)
-            .instrument(span)
-            .instrument(node_span)
-            .await
+        let processed = handle_report(
+            job_config.clone(),
+            info.clone(),
+            record_execution,
+            file,
+            retry.clone(),
+            batch.as_mut(),
+        )
+        .instrument(span)
+        .instrument(node_span)
+        .await;
+        if processed {
+            buffer.processed(&info);
+        }
+
+        if let Some(b) = batch.as_mut().filter(|b| b.is_full()) {
+            for info in flush_batch(job_config.clone(), b.take(), &retry).await {
+                buffer.processed(&info);
+            }
+        }
+    }
+
+    // Don't leave parsed runlogs behind
+    if let Some(mut b) = batch {
+        if !b.is_empty() {
+            let _active = Active::new("reports");
+            flush_batch(job_config.clone(), b.take(), &retry).await;
+        }
     }
-    Ok(())
 }
 
-async fn handle_report(job_config: Arc<JobConfig>, info: RunInfo, file: ReceivedFile) {
+/// Returns whether the runlog was successfully output, runlogs added to
+/// the batch are not output yet
+async fn handle_report(
+    job_config: Arc<JobConfig>,
+    info: RunInfo,
+    record_execution: bool,
+    file: ReceivedFile,
+    retry: Arc<RetryQueue>,
+    batch: Option<&mut Batch>,
+) -> bool {
     if !job_config.nodes.read().await.is_subnode(&info.node_id) {
         REPORTS.with_label_values(&["invalid"]).inc();
-        failure(file, job_config.cfg.processing.reporting.directory.clone())
-            .await
-            .unwrap_or_else(|e| error!("output error: {}", e));
+        let reason = FailureReason::new(
+            &file,
+            FailureStage::Validation,
+            &RudderError::UnknownNode(info.node_id.clone()).into(),
+        );
+        failure(
+            file,
+            job_config.cfg.processing.reporting.directory.clone(),
+            reason,
+        )
+        .await
+        .unwrap_or_else(|e| error!("output error: {}", e));
 
         error!("refused: report from {:?}, unknown id", &info.node_id);
         // this is actually expected behavior
-        return;
+        return false;
     }
 
     debug!("received: {:?}", file);
 
     match job_config.cfg.processing.reporting.output {
-        ReportingOutputSelect::Database => {
-            output_report_database(file, info, job_config.clone()).await
+        ReportingOutputSelect::Database => match batch {
+            Some(b) => {
+                queue_report(file, info, record_execution, job_config.clone(), &retry, b).await
+            }
+            None => {
+                output_report_database(file, info, record_execution, job_config.clone(), &retry)
+                    .await
+            }
+        },
+        ReportingOutputSelect::Upstream => {
+            let summary = if job_config.cfg.processing.reporting.compliance {
+                file_compliance(&file, info.clone(), &job_config).await
+            } else {
+                None
+            };
+            let result = output_report_upstream(file, info, job_config.clone(), &retry).await;
+            if let Ok(true) = result {
+                record_compliance(&job_config, summary).await;
+            }
+            result
+        }
+        ReportingOutputSelect::File => {
+            output_report_file(file, info, job_config.clone(), &retry).await
+        }
+        ReportingOutputSelect::Webhook => match batch {
+            Some(b) => {
+                queue_report(file, info, record_execution, job_config.clone(), &retry, b).await
+            }
+            None => output_report_webhook(file, info, job_config.clone(), &retry).await,
+        },
+        ReportingOutputSelect::Tee => {
+            output_report_tee(file, info, record_execution, job_config.clone(), &retry).await
         }
-        ReportingOutputSelect::Upstream => output_report_upstream(file, job_config.clone()).await,
         // The job should not be started in this case
         ReportingOutputSelect::Disabled => {
             unreachable!("Report server should be disabled")
         }
     }
-    .unwrap_or_else(|e| error!("output error: {}", e));
+    .unwrap_or_else(|e| {
+        error!("output error: {}", e);
+        false
+    })
 }
 
 async fn output_report_database(
     path: ReceivedFile,
     run_info: RunInfo,
+    record_execution: bool,
     job_config: Arc<JobConfig>,
-) -> Result<(), Error> {
-    let path_clone = path.clone();
+    retry: &RetryQueue,
+) -> Result<bool, Error> {
+    let timer = REPORTS_PROCESSING_DURATION.start_timer();
+    let result = insert_report_database(&path, run_info, record_execution, &job_config).await;
+    timer.observe_duration();
+
+    handle_output_result(path, result, &job_config, retry).await
+}
+
+async fn insert_report_database(
+    path: &ReceivedFile,
+    run_info: RunInfo,
+    record_execution: bool,
+    job_config: &Arc<JobConfig>,
+) -> Result<RunlogInsertion, Error> {
+    debug!("Starting insertion of {:#?}", path);
+    let content = read_runlog(path, &run_info, job_config).await?;
+    let job_config_clone = job_config.clone();
+    // Diesel uses blocking io, put it on the blocking threadpool
+    let (insertion, compliance) = spawn_blocking(
+        move || -> Result<(RunlogInsertion, ComplianceSummary), Error> {
+            let runlog_digest = RunlogDigest::new(&run_info, &content);
+            // Reports are parsed and inserted by batches
+            let mut reader = RunLogReader::new(run_info, &content, PARSING_BATCH_SIZE)
+                .with_redaction(&job_config_clone.cfg.processing.reporting.redaction.patterns)
+                .without_types(&job_config_clone.cfg.processing.reporting.skip_event_types)
+                .with_rules(&job_config_clone.cfg.processing.reporting.rules);
+            let insertion = job_config_clone
+                .pool
+                .as_ref()
+                .expect("output uses database but no config provided")
+                .insert_runlog_stream(
+                    &mut reader,
+                    &runlog_digest,
+                    job_config_clone.deduplication,
+                    record_execution,
+                )?;
+            Ok((insertion, reader.compliance_summary()))
+        },
+    )
+    .await
+    .map_err(Error::from)
+    .and_then(|r| r)?;
+
+    // Duplicates are not read entirely
+    if insertion == RunlogInsertion::Inserted && job_config.cfg.processing.reporting.compliance {
+        job_config.compliance.update(compliance).await;
+    }
+    Ok(insertion)
+}
+
+/// Inserts the runlog into the database, then forwards the original file upstream.
+///
+/// Sinks done during a previous attempt are skipped. The file is removed once all
+/// sinks succeeded, and moved to `failed/` once all sinks are done if one of them
+/// failed permanently.
+async fn output_report_tee(
+    path: ReceivedFile,
+    run_info: RunInfo,
+    record_execution: bool,
+    job_config: Arc<JobConfig>,
+    retry: &RetryQueue,
+) -> Result<bool, Error> {
+    let sinks = job_config
+        .sinks
+        .as_ref()
+        .expect("output uses tee but no sinks state was loaded");
+    let mut outcomes = sinks.outcomes(&path).await;
+
+    let mut results = vec![];
+    if !outcomes.contains_key(&Sink::Database) {
+        let timer = REPORTS_PROCESSING_DURATION.start_timer();
+        let result = insert_report_database(&path, run_info.clone(), record_execution, &job_config)
+            .await
+            .map(|_| ());
+        timer.observe_duration();
+        results.push((Sink::Database, result));
+    }
+    if !outcomes.contains_key(&Sink::Upstream) {
+        results.push((
+            Sink::Upstream,
+            forward_report(&job_config, &path, run_info).await,
+        ));
+    }
+
+    let mut transient = vec![];
+    for (sink, result) in results {
+        let (ok_label, error_label) = sink.metric_labels();
+        match result {
+            Ok(()) => {
+                REPORTS.with_label_values(&[ok_label]).inc();
+                outcomes.insert(sink, None);
+            }
+            Err(e) => {
+                error!("{:?} output error: {}", sink, e);
+                let reason = FailureReason::from_error(&path, &e);
+                match OutputError::from(e) {
+                    OutputError::Permanent => {
+                        REPORTS.with_label_values(&[error_label]).inc();
+                        outcomes.insert(sink, Some(reason));
+                    }
+                    OutputError::Transient => transient.push((sink, reason)),
+                }
+            }
+        }
+    }
+
+    let directory = job_config.cfg.processing.reporting.directory.clone();
+    if let Some((_, reason)) = transient.first() {
+        sinks.record(&path, outcomes).await?;
+        if transient_failure(path.clone(), directory, retry, reason.clone()).await? {
+            for (sink, _) in transient {
+                REPORTS.with_label_values(&[sink.metric_labels().1]).inc();
+            }
+            sinks.forget(&path).await?;
+        }
+        return Ok(false);
+    }
+
+    retry.forget(&path).await?;
+    sinks.forget(&path).await?;
+    // First permanent failure, in sink order
+    match Sink::ALL.iter().find_map(|s| outcomes.remove(s).flatten()) {
+        Some(reason) => failure(path, directory, reason).await.map(|_| false),
+        None => success(path).await.map(|_| true),
+    }
+}
+
+async fn output_report_file(
+    path: ReceivedFile,
+    run_info: RunInfo,
+    job_config: Arc<JobConfig>,
+    retry: &RetryQueue,
+) -> Result<bool, Error> {
+    debug!("Starting writing of {:#?}", path);
+    let timer = REPORTS_PROCESSING_DURATION.start_timer();
+
+    let (result, summary) = match parse_runlog(&path, run_info, &job_config).await {
+        Ok((runlog, _, summary)) => {
+            let job_config_clone = job_config.clone();
+            // Writes are blocking, put them on the blocking threadpool
+            let result = spawn_blocking(move || {
+                job_config_clone
+                    .reports_file
+                    .as_ref()
+                    .expect("output uses file but no file was opened")
+                    .lock()
+                    .expect("reports file lock poisoned")
+                    .write_runlog(&runlog)
+            })
+            .await
+            .map_err(Error::from)
+            .and_then(|r| r);
+            (result, summary)
+        }
+        Err(e) => (Err(e), None),
+    };
+    timer.observe_duration();
+
+    let output = handle_output_result(path, result, &job_config, retry).await;
+    if let Ok(true) = output {
+        record_compliance(&job_config, summary).await;
+    }
+    output
+}
+
+async fn output_report_webhook(
+    path: ReceivedFile,
+    run_info: RunInfo,
+    job_config: Arc<JobConfig>,
+    retry: &RetryQueue,
+) -> Result<bool, Error> {
+    debug!("Starting sending of {:#?}", path);
+    let timer = REPORTS_PROCESSING_DURATION.start_timer();
+
+    let (result, summary) = match parse_runlog(&path, run_info, &job_config).await {
+        Ok((runlog, _, summary)) => (send_webhook(&job_config, &[&runlog]).await, summary),
+        Err(e) => (Err(e), None),
+    };
+    timer.observe_duration();
+
+    let output = handle_output_result(path, result, &job_config, retry).await;
+    if let Ok(true) = output {
+        record_compliance(&job_config, summary).await;
+    }
+    output
+}
+
+async fn send_webhook(job_config: &JobConfig, runlogs: &[&RunLog]) -> Result<(), Error> {
+    send_runlogs(
+        job_config
+            .webhook_client
+            .as_ref()
+            .expect("output uses webhook but no client was created"),
+        &job_config.cfg.output.webhook,
+        runlogs,
+    )
+    .await
+}
+
+/// Parse the runlog and add it to the batch
+async fn queue_report(
+    path: ReceivedFile,
+    run_info: RunInfo,
+    record_execution: bool,
+    job_config: Arc<JobConfig>,
+    retry: &RetryQueue,
+    batch: &mut Batch,
+) -> Result<bool, Error> {
+    if batch.contains(&path) {
+        debug!("{:#?} is already in the batch, skipping", path);
+        return Ok(false);
+    }
+    debug!("Adding {:#?} to batch", path);
+
+    match parse_runlog(&path, run_info, &job_config).await {
+        Ok((runlog, digest, summary)) => {
+            batch.push(
+                path,
+                PendingRunlog {
+                    runlog,
+                    digest,
+                    record_execution,
+                },
+                summary,
+            );
+            Ok(false)
+        }
+        Err(e) => handle_output_result::<()>(path, Err(e), &job_config, retry).await,
+    }
+}
+
+/// Send the runlogs of the batch to the configured output, returns the
+/// successfully output ones
+async fn flush_batch(
+    job_config: Arc<JobConfig>,
+    entries: Vec<(ReceivedFile, PendingRunlog, Option<ComplianceSummary>)>,
+    retry: &RetryQueue,
+) -> Vec<RunInfo> {
+    debug!("Processing batch of {} runlogs", entries.len());
+    REPORTS_BATCH_SIZE.observe(entries.len() as f64);
+
+    // Waiting in the batch is not part of the processing
+    let timers = entries
+        .iter()
+        .map(|_| REPORTS_PROCESSING_DURATION.start_timer())
+        .collect();
+    let (files, runlogs_summaries): (Vec<_>, Vec<_>) =
+        entries.into_iter().map(|(f, r, s)| (f, (r, s))).unzip();
+    let (runlogs, summaries): (Vec<_>, Vec<_>) = runlogs_summaries.into_iter().unzip();
+
+    if job_config.cfg.processing.reporting.output == ReportingOutputSelect::Webhook {
+        send_batch(job_config, files, runlogs, summaries, timers, retry).await
+    } else {
+        insert_batch(job_config, files, runlogs, summaries, timers, retry).await
+    }
+}
+
+/// Insert the runlogs together, each file is then handled according to its own result
+async fn insert_batch(
+    job_config: Arc<JobConfig>,
+    files: Vec<ReceivedFile>,
+    runlogs: Vec<PendingRunlog>,
+    summaries: Vec<Option<ComplianceSummary>>,
+    timers: Vec<HistogramTimer>,
+    retry: &RetryQueue,
+) -> Vec<RunInfo> {
+    let infos: Vec<RunInfo> = runlogs.iter().map(|p| p.runlog.info.clone()).collect();
     let job_config_clone = job_config.clone();
+    // Diesel uses blocking io, put it on the blocking threadpool
+    let results = match spawn_blocking(move || {
+        job_config_clone
+            .pool
+            .as_ref()
+            .expect("output uses database but no config provided")
+            .insert_runlogs(&runlogs, job_config_clone.deduplication)
+    })
+    .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            error!("batch insertion task failed: {}", e);
+            // Leave the files in place, they will be processed again
+            return vec![];
+        }
+    };
+
+    let mut processed = vec![];
+    for ((((file, timer), result), info), summary) in files
+        .into_iter()
+        .zip(timers)
+        .zip(results)
+        .zip(infos)
+        .zip(summaries)
+    {
+        timer.observe_duration();
+        // Like for single insertions, duplicates don't update compliance
+        let inserted = matches!(result, Ok(RunlogInsertion::Inserted));
+        if handle_output_result(file, result, &job_config, retry)
+            .await
+            .unwrap_or_else(|e| {
+                error!("output error: {}", e);
+                false
+            })
+        {
+            if inserted {
+                record_compliance(&job_config, summary).await;
+            }
+            processed.push(info);
+        }
+    }
+    processed
+}
+
+/// Send the runlogs with a single request, all files share its result
+async fn send_batch(
+    job_config: Arc<JobConfig>,
+    files: Vec<ReceivedFile>,
+    runlogs: Vec<PendingRunlog>,
+    summaries: Vec<Option<ComplianceSummary>>,
+    timers: Vec<HistogramTimer>,
+    retry: &RetryQueue,
+) -> Vec<RunInfo> {
+    let result = send_webhook(
+        &job_config,
+        &runlogs.iter().map(|p| &p.runlog).collect::<Vec<_>>(),
+    )
+    .await;
+
+    match result {
+        Ok(()) => {
+            let mut processed = vec![];
+            for (((file, timer), pending), summary) in
+                files.into_iter().zip(timers).zip(runlogs).zip(summaries)
+            {
+                timer.observe_duration();
+                if handle_output_result(file, Ok(()), &job_config, retry)
+                    .await
+                    .unwrap_or_else(|e| {
+                        error!("output error: {}", e);
+                        false
+                    })
+                {
+                    record_compliance(&job_config, summary).await;
+                    processed.push(pending.runlog.info);
+                }
+            }
+            processed
+        }
+        Err(e) => {
+            error!("output error for a batch of {} runlogs: {}", files.len(), e);
+            let reasons: Vec<FailureReason> = files
+                .iter()
+                .map(|f| FailureReason::from_error(f, &e))
+                .collect();
+            let kind = OutputError::from(e);
+            for ((file, timer), reason) in files.into_iter().zip(timers).zip(reasons) {
+                timer.observe_duration();
+                handle_output_error(file, reason, kind, &job_config, retry)
+                    .await
+                    .unwrap_or_else(|e| error!("output error: {}", e));
+            }
+            vec![]
+        }
+    }
+}
 
-    match output_report_database_inner(path, run_info, job_config).await {
+/// Returns whether the output succeeded
+async fn handle_output_result<T>(
+    path: ReceivedFile,
+    result: Result<T, Error>,
+    job_config: &JobConfig,
+    retry: &RetryQueue,
+) -> Result<bool, Error> {
+    match result {
         Ok(_) => {
             REPORTS.with_label_values(&["ok"]).inc();
-            success(path_clone.clone()).await
+            retry.forget(&path).await?;
+            success(path).await.map(|_| true)
         }
         Err(e) => {
             error!("output error: {}", e);
-            match OutputError::from(e) {
-                OutputError::Permanent => {
-                    REPORTS.with_label_values(&["error"]).inc();
-                    failure(
-                        path_clone.clone(),
-                        job_config_clone.cfg.processing.reporting.directory.clone(),
-                    )
-                    .await
-                }
-                OutputError::Transient => {
-                    info!("transient error, skipping");
-                    Ok(())
-                }
+            let reason = FailureReason::from_error(&path, &e);
+            handle_output_error(path, reason, OutputError::from(e), job_config, retry)
+                .await
+                .map(|_| false)
+        }
+    }
+}
+
+async fn handle_output_error(
+    path: ReceivedFile,
+    reason: FailureReason,
+    kind: OutputError,
+    job_config: &JobConfig,
+    retry: &RetryQueue,
+) -> Result<(), Error> {
+    match kind {
+        OutputError::Permanent => {
+            REPORTS.with_label_values(&["error"]).inc();
+            retry.forget(&path).await?;
+            failure(
+                path,
+                job_config.cfg.processing.reporting.directory.clone(),
+                reason,
+            )
+            .await
+        }
+        OutputError::Transient => {
+            if transient_failure(
+                path,
+                job_config.cfg.processing.reporting.directory.clone(),
+                retry,
+                reason,
+            )
+            .await?
+            {
+                REPORTS.with_label_values(&["error"]).inc();
             }
+            Ok(())
         }
     }
 }
 
 async fn output_report_upstream(
     path: ReceivedFile,
+    run_info: RunInfo,
     job_config: Arc<JobConfig>,
-) -> Result<(), Error> {
+    retry: &RetryQueue,
+) -> Result<bool, Error> {
     let job_config_clone = job_config.clone();
     let path_clone2 = path.clone();
 
-    let result = send_report(job_config, path.clone()).await;
+    let result = forward_report(&job_config, &path, run_info).await;
 
     match result {
         Ok(_) => {
             REPORTS.with_label_values(&["forward_ok"]).inc();
-            success(path.clone()).await
+            retry.forget(&path).await?;
+            success(path.clone()).await.map(|_| true)
         }
         Err(e) => {
             error!("output error: {}", e);
+            let reason = FailureReason::from_error(&path, &e);
             match OutputError::from(e) {
                 OutputError::Permanent => {
                     REPORTS.with_label_values(&["forward_error"]).inc();
+                    retry.forget(&path_clone2).await?;
                     failure(
                         path_clone2.clone(),
                         job_config_clone.cfg.processing.reporting.directory.clone(),
+                        reason,
                     )
                     .await
+                    .map(|_| false)
                 }
                 OutputError::Transient => {
-                    info!("transient error, skipping");
-                    Ok(())
+                    if transient_failure(
+                        path_clone2.clone(),
+                        job_config_clone.cfg.processing.reporting.directory.clone(),
+                        retry,
+                        reason,
+                    )
+                    .await?
+                    {
+                        REPORTS.with_label_values(&["forward_error"]).inc();
+                    }
+                    Ok(false)
                 }
             }
         }
     }
 }
 
-async fn output_report_database_inner(
-    path: ReceivedFile,
+/// Read and check a runlog file, returns the signed content
+async fn read_runlog(
+    path: &ReceivedFile,
+    run_info: &RunInfo,
+    job_config: &JobConfig,
+) -> Result<String, Error> {
+    // The raw message is dropped once parsed, to keep only one copy of the content
+    // next to the verified runlog
+    let message = SignedMessage::from_smime(&read_compressed_file(path).await?)?;
+    let nodes = job_config.nodes.read().await;
+    let signed_runlog = match message.verify(nodes.certs(&run_info.node_id)?) {
+        Ok(r) => r,
+        // Redacted runlogs are signed by the relay which forwarded them
+        Err(e)
+            if job_config
+                .cfg
+                .processing
+                .reporting
+                .redaction
+                .accept_relay_signatures =>
+        {
+            nodes
+                .relays_certs(&run_info.node_id)
+                .into_iter()
+                .find_map(|certs| message.verify(certs).ok())
+                .ok_or(e)?
+        }
+        Err(e) => return Err(e),
+    };
+
+    REPORTS_SIZE_BYTES.observe(signed_runlog.len() as f64);
+    Ok(signed_runlog)
+}
+
+/// Forwards the original file, or a redacted copy signed by this relay
+async fn forward_report(
+    job_config: &Arc<JobConfig>,
+    path: &ReceivedFile,
     run_info: RunInfo,
-    job_config: Arc<JobConfig>,
-) -> Result<RunlogInsertion, Error> {
-    debug!("Starting insertion of {:#?}", path);
-    let timer = REPORTS_PROCESSING_DURATION.start_timer();
+) -> Result<(), Error> {
+    let redaction = &job_config.cfg.processing.reporting.redaction;
+    if !redaction.forward_redacted {
+        return send_report(job_config.clone(), path.clone()).await;
+    }
 
-    let content = read_compressed_file(&path).await?;
-    let signed_runlog = signature(
-        &content,
-        job_config.nodes.read().await.certs(&run_info.node_id)?,
-    )?;
+    let signed_runlog = read_runlog(path, &run_info, job_config).await?;
+    let runlog =
+        RunLog::try_from((run_info, signed_runlog.as_ref()))?.redacted(&redaction.patterns);
+    let signed = job_config
+        .runlog_signer
+        .as_ref()
+        .ok_or_else(|| anyhow!("redacted runlogs are forwarded but no signing key was loaded"))?
+        .sign(&format!("{:#}", runlog))?;
 
-    REPORTS_SIZE_BYTES.observe(signed_runlog.len() as f64);
+    // Always uncompressed, with the original name
+    let redacted_path = job_config
+        .cfg
+        .processing
+        .reporting
+        .directory
+        .join("redacted")
+        .join(format!(
+            "{}@{}.log",
+            runlog.info.timestamp.to_rfc3339(),
+            runlog.info.node_id
+        ));
+    write(&redacted_path, signed).await?;
+    let result = send_report(job_config.clone(), redacted_path.clone()).await;
+    // Don't retry an already forwarded runlog because of the cleanup
+    if let Err(e) = remove_file(&redacted_path).await {
+        error!(
+            "Could not remove redacted runlog {}: {}",
+            redacted_path.display(),
+            e
+        );
+    }
+    result
+}
 
-    let parsed_runlog: RunLog = RunLog::try_from((run_info.clone(), signed_runlog.as_ref()))?;
+/// Read, check and parse a whole runlog file, with its compliance summary when enabled.
+///
+/// The summary is only recorded once the runlog was successfully output.
+async fn parse_runlog(
+    path: &ReceivedFile,
+    run_info: RunInfo,
+    job_config: &JobConfig,
+) -> Result<(RunLog, RunlogDigest, Option<ComplianceSummary>), Error> {
+    let signed_runlog = read_runlog(path, &run_info, job_config).await?;
+    let runlog_digest = RunlogDigest::new(&run_info, &signed_runlog);
+    let parsed_runlog: RunLog = RunLog::try_from((run_info, signed_runlog.as_ref()))?
+        .redacted(&job_config.cfg.processing.reporting.redaction.patterns);
+    // Before removing skipped event types
+    let summary = job_config
+        .cfg
+        .processing
+        .reporting
+        .compliance
+        .then(|| ComplianceSummary::from(&parsed_runlog));
 
-    let filtered_runlog: RunLog = if !job_config
+    let runlog = if !job_config
         .cfg
         .processing
         .reporting
@@ -217,19 +989,38 @@ async fn output_report_database_inner(
     } else {
         parsed_runlog
     };
+    let runlog = if !job_config.cfg.processing.reporting.rules.is_empty() {
+        runlog.with_rules(&job_config.cfg.processing.reporting.rules)
+    } else {
+        runlog
+    };
+    Ok((runlog, runlog_digest, summary))
+}
 
-    // Diesel uses blocking io, put it on the blocking threadpool
-    let result = spawn_blocking(move || -> Result<RunlogInsertion, Error> {
-        insert_runlog(
-            &job_config
-                .pool
-                .clone()
-                .expect("output uses database but no config provided"),
-            &filtered_runlog,
-        )
-    })
-    .await?;
+/// Only used to compute compliance when the runlog is not parsed for the output.
+///
+/// Errors are only logged, as they do not prevent the output.
+async fn file_compliance(
+    path: &ReceivedFile,
+    run_info: RunInfo,
+    job_config: &JobConfig,
+) -> Option<ComplianceSummary> {
+    let result = match read_runlog(path, &run_info, job_config).await {
+        Ok(signed_runlog) => RunLog::try_from((run_info, signed_runlog.as_ref())),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(runlog) => Some(ComplianceSummary::from(&runlog)),
+        Err(e) => {
+            warn!("could not compute compliance of {:#?}: {}", path, e);
+            None
+        }
+    }
+}
 
-    timer.observe_duration();
-    result
+/// Only called once the runlog was successfully output
+async fn record_compliance(job_config: &JobConfig, summary: Option<ComplianceSummary>) {
+    if let Some(summary) = summary {
+        job_config.compliance.update(summary).await;
+    }
 }
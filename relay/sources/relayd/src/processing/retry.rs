@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{metadata, rename, write},
+    sync::Mutex,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    configuration::main::RetryConfig,
+    metrics::{RETRIES, RETRY_BACKLOG},
+};
+
+/// Name of the state file, stored in the queue base directory
+const STATE_FILE: &str = "retry.json";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+struct RetryEntry {
+    /// Number of failed attempts
+    attempts: u32,
+    next_attempt: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// The file stays in place and will be processed again after this date
+    RetryAt(DateTime<Utc>),
+    /// Maximum number of attempts reached
+    GiveUp,
+}
+
+/// Tracks files which encountered a transient error, to retry them with an
+/// exponential backoff instead of at each catchup run.
+///
+/// The state is kept on disk to survive restarts.
+pub struct RetryQueue {
+    /// Used as metrics label
+    name: &'static str,
+    state_file: PathBuf,
+    cfg: RetryConfig,
+    entries: Mutex<HashMap<PathBuf, RetryEntry>>,
+}
+
+impl RetryQueue {
+    /// Load existing state from the queue directory, forgetting files which
+    /// do not exist anymore
+    pub fn new(name: &'static str, directory: &Path, cfg: RetryConfig) -> Result<Self, Error> {
+        let state_file = directory.join(STATE_FILE);
+
+        let mut entries: HashMap<PathBuf, RetryEntry> = if state_file.exists() {
+            serde_json::from_str(&read_to_string(&state_file)?).unwrap_or_else(|e| {
+                warn!(
+                    "could not parse retry state from {}, resetting it: {}",
+                    state_file.display(),
+                    e
+                );
+                HashMap::new()
+            })
+        } else {
+            HashMap::new()
+        };
+        entries.retain(|file, _| file.exists());
+        RETRY_BACKLOG
+            .with_label_values(&[name])
+            .set(entries.len() as i64);
+
+        Ok(Self {
+            name,
+            state_file,
+            cfg,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Is the file new, or is its backoff delay over
+    pub async fn is_due(&self, file: &Path) -> bool {
+        self.entries
+            .lock()
+            .await
+            .get(file)
+            .map(|e| e.next_attempt <= Utc::now())
+            .unwrap_or(true)
+    }
+
+    /// Record a transient failure and decide what to do with the file
+    pub async fn failed(&self, file: &Path) -> Result<RetryDecision, Error> {
+        let mut entries = self.entries.lock().await;
+
+        let attempts = entries.get(file).map(|e| e.attempts).unwrap_or(0) + 1;
+        let decision = if attempts >= self.cfg.max_attempts {
+            entries.remove(file);
+            RetryDecision::GiveUp
+        } else {
+            let next_attempt = Utc::now()
+                + chrono::Duration::from_std(self.delay(attempts))
+                    .expect("retry delay out of range");
+            entries.insert(
+                file.to_path_buf(),
+                RetryEntry {
+                    attempts,
+                    next_attempt,
+                },
+            );
+            RETRIES.with_label_values(&[self.name]).inc();
+            RetryDecision::RetryAt(next_attempt)
+        };
+
+        self.persist(&mut entries).await?;
+        Ok(decision)
+    }
+
+    /// Forget a file once it has been processed (successfully or not)
+    pub async fn forget(&self, file: &Path) -> Result<(), Error> {
+        let mut entries = self.entries.lock().await;
+        if entries.remove(file).is_some() {
+            self.persist(&mut entries).await?;
+        }
+        Ok(())
+    }
+
+    /// Exponential backoff with equal jitter: the delay is randomly chosen between
+    /// half and the full computed value, to avoid synchronized retries.
+    fn delay(&self, attempts: u32) -> Duration {
+        let exp = self
+            .cfg
+            .initial_delay
+            .checked_mul(2_u32.saturating_pow(attempts.saturating_sub(1)))
+            .unwrap_or(self.cfg.max_delay)
+            .min(self.cfg.max_delay);
+        let half = exp / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    async fn persist(&self, entries: &mut HashMap<PathBuf, RetryEntry>) -> Result<(), Error> {
+        // Files can be removed by the cleanup task. Checked with async io, as
+        // the backlog can be large.
+        let mut removed = vec![];
+        for file in entries.keys() {
+            if matches!(metadata(file).await, Err(e) if e.kind() == std::io::ErrorKind::NotFound) {
+                removed.push(file.clone());
+            }
+        }
+        for file in removed {
+            entries.remove(&file);
+        }
+        RETRY_BACKLOG
+            .with_label_values(&[self.name])
+            .set(entries.len() as i64);
+
+        debug!(
+            "writing retry state with {} entries to {}",
+            entries.len(),
+            self.state_file.display()
+        );
+        let tmp = self.state_file.with_extension("json.tmp");
+        write(&tmp, serde_json::to_vec(entries)?).await?;
+        rename(&tmp, &self.state_file).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn config(max_attempts: u32) -> RetryConfig {
+        RetryConfig {
+            initial_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(60),
+            max_attempts,
+        }
+    }
+
+    #[test]
+    fn it_computes_backoff_delay() {
+        let dir = tempdir().unwrap();
+        let queue = RetryQueue::new("reports", dir.path(), config(10)).unwrap();
+
+        for _ in 0..20 {
+            let first = queue.delay(1);
+            assert!(first >= Duration::from_secs(5) && first <= Duration::from_secs(10));
+            let third = queue.delay(3);
+            assert!(third >= Duration::from_secs(20) && third <= Duration::from_secs(40));
+            let capped = queue.delay(40);
+            assert!(capped >= Duration::from_secs(30) && capped <= Duration::from_secs(60));
+        }
+    }
+
+    #[tokio::test]
+    async fn it_retries_and_gives_up() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("2018-08-24T15:55:01+00:00@root.log");
+        File::create(&file).unwrap();
+
+        let queue = RetryQueue::new("reports", dir.path(), config(3)).unwrap();
+        assert!(queue.is_due(&file).await);
+
+        assert!(matches!(
+            queue.failed(&file).await.unwrap(),
+            RetryDecision::RetryAt(_)
+        ));
+        assert!(!queue.is_due(&file).await);
+        assert!(matches!(
+            queue.failed(&file).await.unwrap(),
+            RetryDecision::RetryAt(_)
+        ));
+        assert_eq!(queue.failed(&file).await.unwrap(), RetryDecision::GiveUp);
+        assert!(queue.is_due(&file).await);
+    }
+
+    #[tokio::test]
+    async fn it_persists_state() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("2018-08-24T15:55:01+00:00@root.log");
+        let removed = dir.path().join("2018-08-24T15:55:02+00:00@root.log");
+        File::create(&file).unwrap();
+        File::create(&removed).unwrap();
+
+        let queue = RetryQueue::new("reports", dir.path(), config(3)).unwrap();
+        queue.failed(&file).await.unwrap();
+        queue.failed(&removed).await.unwrap();
+        std::fs::remove_file(&removed).unwrap();
+
+        let reloaded = RetryQueue::new("reports", dir.path(), config(3)).unwrap();
+        assert!(!reloaded.is_due(&file).await);
+        assert_eq!(reloaded.entries.lock().await.len(), 1);
+
+        reloaded.forget(&file).await.unwrap();
+        assert!(reloaded.is_due(&file).await);
+    }
+
+    #[tokio::test]
+    async fn it_prunes_removed_files_on_update() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("2018-08-24T15:55:01+00:00@root.log");
+        let removed = dir.path().join("2018-08-24T15:55:02+00:00@root.log");
+        File::create(&file).unwrap();
+        File::create(&removed).unwrap();
+
+        let queue = RetryQueue::new("reports", dir.path(), config(3)).unwrap();
+        queue.failed(&removed).await.unwrap();
+        std::fs::remove_file(&removed).unwrap();
+        queue.failed(&file).await.unwrap();
+
+        let entries = queue.entries.lock().await;
+        assert_eq!(entries.len(), 1);
+        assert!(entries.contains_key(&file));
+    }
+}
@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{rename, write},
+    sync::Mutex,
+};
+use tracing::{debug, warn};
+
+use crate::processing::failed::FailureReason;
+
+/// Name of the state file, stored in the queue base directory
+const STATE_FILE: &str = "sinks.json";
+
+/// Outputs of the tee mode, in processing order
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Sink {
+    Database,
+    Upstream,
+}
+
+impl Sink {
+    pub const ALL: [Sink; 2] = [Sink::Database, Sink::Upstream];
+
+    /// Same labels as the single output modes
+    pub fn metric_labels(self) -> (&'static str, &'static str) {
+        match self {
+            Sink::Database => ("ok", "error"),
+            Sink::Upstream => ("forward_ok", "forward_error"),
+        }
+    }
+}
+
+/// Sinks done for a file, with the reason for those which failed permanently
+pub type SinkOutcomes = HashMap<Sink, Option<FailureReason>>;
+
+/// Tracks the sinks already done for files waiting for a new attempt, so that
+/// a runlog is not inserted or forwarded twice.
+///
+/// The state is kept on disk to survive restarts.
+pub struct SinkState {
+    state_file: PathBuf,
+    entries: Mutex<HashMap<PathBuf, SinkOutcomes>>,
+}
+
+impl SinkState {
+    /// Load existing state from the queue directory, forgetting files which
+    /// do not exist anymore
+    pub fn new(directory: &Path) -> Result<Self, Error> {
+        let state_file = directory.join(STATE_FILE);
+
+        let mut entries: HashMap<PathBuf, SinkOutcomes> = if state_file.exists() {
+            serde_json::from_str(&read_to_string(&state_file)?).unwrap_or_else(|e| {
+                warn!(
+                    "could not parse sinks state from {}, resetting it: {}",
+                    state_file.display(),
+                    e
+                );
+                HashMap::new()
+            })
+        } else {
+            HashMap::new()
+        };
+        entries.retain(|file, _| file.exists());
+
+        Ok(Self {
+            state_file,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub async fn outcomes(&self, file: &Path) -> SinkOutcomes {
+        self.entries
+            .lock()
+            .await
+            .get(file)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Store the sinks done before a new attempt
+    pub async fn record(&self, file: &Path, outcomes: SinkOutcomes) -> Result<(), Error> {
+        let mut entries = self.entries.lock().await;
+        entries.insert(file.to_path_buf(), outcomes);
+        self.persist(&mut entries).await
+    }
+
+    /// Forget a file once all sinks are done
+    pub async fn forget(&self, file: &Path) -> Result<(), Error> {
+        let mut entries = self.entries.lock().await;
+        if entries.remove(file).is_some() {
+            self.persist(&mut entries).await?;
+        }
+        Ok(())
+    }
+
+    async fn persist(&self, entries: &mut HashMap<PathBuf, SinkOutcomes>) -> Result<(), Error> {
+        // Files can be removed by the cleanup task
+        entries.retain(|file, _| file.exists());
+        debug!(
+            "writing sinks state with {} entries to {}",
+            entries.len(),
+            self.state_file.display()
+        );
+        let tmp = self.state_file.with_extension("json.tmp");
+        write(&tmp, serde_json::to_vec(entries)?).await?;
+        rename(&tmp, &self.state_file).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{error::RudderError, processing::failed::FailureStage};
+
+    #[tokio::test]
+    async fn it_persists_sink_outcomes() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("2018-08-24T15:55:01+00:00@root.log");
+        File::create(&file).unwrap();
+
+        let state = SinkState::new(dir.path()).unwrap();
+        assert!(state.outcomes(&file).await.is_empty());
+
+        let reason = FailureReason::new(
+            &file,
+            FailureStage::Output,
+            &RudderError::EmptyRunlog.into(),
+        );
+        let mut outcomes = SinkOutcomes::new();
+        outcomes.insert(Sink::Database, None);
+        outcomes.insert(Sink::Upstream, Some(reason));
+        state.record(&file, outcomes.clone()).await.unwrap();
+
+        let reloaded = SinkState::new(dir.path()).unwrap();
+        assert_eq!(reloaded.outcomes(&file).await, outcomes);
+        reloaded.forget(&file).await.unwrap();
+        assert!(reloaded.outcomes(&file).await.is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    future::Future,
+    hash::{Hash, Hasher},
+};
+
+use futures::future::join_all;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{error, span, Instrument, Level};
+
+use crate::metrics::ACTIVE_WORKERS;
+
+/// Files waiting for each worker
+const WORKER_QUEUE_SIZE: usize = 64;
+
+/// Pool of workers for a processing queue.
+///
+/// Items with the same key are always handled by the same worker,
+/// so they are processed in the order they were dispatched.
+pub struct Workers<T> {
+    senders: Vec<mpsc::Sender<T>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> Workers<T> {
+    /// Starts `count` workers (at least one), each one running `worker`
+    /// with its id and its own queue
+    pub fn new<F, Fut>(queue: &'static str, count: usize, worker: F) -> Self
+    where
+        F: Fn(usize, mpsc::Receiver<T>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let count = count.max(1);
+        let mut senders = Vec::with_capacity(count);
+        let mut handles = Vec::with_capacity(count);
+        for id in 0..count {
+            let (tx, rx) = mpsc::channel(WORKER_QUEUE_SIZE);
+            senders.push(tx);
+            handles.push(tokio::spawn(worker(id, rx).instrument(span!(
+                Level::INFO,
+                "worker",
+                queue,
+                id
+            ))));
+        }
+        Self { senders, handles }
+    }
+
+    fn index(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.senders.len() as u64) as usize
+    }
+
+    /// Waits while the worker queue is full
+    pub async fn dispatch(&self, key: &str, item: T) {
+        if self.senders[self.index(key)].send(item).await.is_err() {
+            // The file stays in place and will be picked up by the catchup
+            error!("worker for {} stopped, skipping", key);
+        }
+    }
+
+    /// Stop dispatching and wait for workers to finish
+    pub async fn join(self) {
+        drop(self.senders);
+        join_all(self.handles).await;
+    }
+}
+
+/// Counts a worker as active while kept alive
+pub struct Active(&'static str);
+
+impl Active {
+    pub fn new(queue: &'static str) -> Self {
+        ACTIVE_WORKERS.with_label_values(&[queue]).inc();
+        Self(queue)
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE_WORKERS.with_label_values(&[self.0]).dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_keeps_order_by_key() {
+        let processed = Arc::new(Mutex::new(vec![]));
+        let processed_workers = processed.clone();
+        let workers = Workers::new(
+            "tests",
+            4,
+            move |id, mut rx: mpsc::Receiver<(String, u32)>| {
+                let processed = processed_workers.clone();
+                async move {
+                    while let Some((key, n)) = rx.recv().await {
+                        let _active = Active::new("tests");
+                        processed.lock().unwrap().push((id, key, n));
+                    }
+                }
+            },
+        );
+        for n in 0..50 {
+            for key in ["node1", "node2", "node3"] {
+                workers.dispatch(key, (key.to_string(), n)).await;
+            }
+        }
+        workers.join().await;
+
+        let processed = processed.lock().unwrap();
+        assert_eq!(processed.len(), 150);
+        for key in ["node1", "node2", "node3"] {
+            let items: Vec<_> = processed.iter().filter(|(_, k, _)| k == key).collect();
+            // Always the same worker
+            assert!(items.iter().all(|(id, _, _)| *id == items[0].0));
+            // In order
+            assert!(items.windows(2).all(|w| w[0].2 < w[1].2));
+        }
+        assert_eq!(ACTIVE_WORKERS.with_label_values(&["tests"]).get(), 0);
+    }
+}
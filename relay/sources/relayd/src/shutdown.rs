@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use std::future::Future;
+
+use tokio::sync::watch;
+
+/// Sending side, triggered by the signal handler
+#[derive(Debug)]
+pub struct ShutdownTrigger(watch::Sender<bool>);
+
+impl ShutdownTrigger {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(false);
+        Self(tx)
+    }
+
+    pub fn subscribe(&self) -> Shutdown {
+        Shutdown(self.0.subscribe())
+    }
+
+    /// Notify all tasks, can be called several times
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+impl Default for ShutdownTrigger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Receiving side, given to every task that needs to stop cleanly
+#[derive(Debug, Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once shutdown has been requested
+    pub async fn requested(&mut self) {
+        while !*self.0.borrow_and_update() {
+            if self.0.changed().await.is_err() {
+                // Trigger dropped, nobody can ask for a shutdown anymore
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Run the future until it completes or shutdown is requested,
+    /// in which case it is dropped.
+    pub async fn cancel<F: Future>(mut self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            _ = self.requested() => None,
+            res = future => Some(res),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_notifies_shutdown() {
+        let trigger = ShutdownTrigger::new();
+        let mut shutdown = trigger.subscribe();
+        assert!(!shutdown.is_requested());
+
+        let task = tokio::spawn(trigger.subscribe().cancel(pending::<()>()));
+        assert_eq!(trigger.subscribe().cancel(async { 42 }).await, Some(42));
+
+        trigger.trigger();
+        shutdown.requested().await;
+        assert!(shutdown.is_requested());
+        assert_eq!(task.await.unwrap(), None);
+
+        // Subscribing after the trigger still sees it
+        let mut late = trigger.subscribe();
+        late.requested().await;
+    }
+}
@@ -15,7 +15,9 @@ use rudder_relayd::{
     configuration::cli::CliConfiguration,
     data::report::QueryableReport,
     init_logger,
-    output::database::schema::{reportsexecution::dsl::*, ruddersysevents::dsl::*},
+    output::database::schema::{
+        reportsexecution::dsl::*, ruddersysevents::dsl::*, runlogdigests::dsl::runlogdigests,
+    },
     start,
 };
 
@@ -79,6 +81,7 @@ fn it_reads_and_inserts_a_runlog() {
     let mut db = db_connection();
     diesel::delete(ruddersysevents).execute(&mut db).unwrap();
     diesel::delete(reportsexecution).execute(&mut db).unwrap();
+    diesel::delete(runlogdigests).execute(&mut db).unwrap();
 
     assert!(start_number(&mut db, 0).is_ok());
 
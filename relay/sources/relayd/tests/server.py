@@ -3,6 +3,7 @@
 from http.server import HTTPServer, BaseHTTPRequestHandler
 from ssl import SSLContext, PROTOCOL_TLS_SERVER
 import time
+import json
 from pprint import pprint
 import sys
 
@@ -18,6 +19,11 @@ class PolicyServer(BaseHTTPRequestHandler):
             self.send_header('Content-type', 'text/plain')
             self.end_headers()
             self.wfile.write(str.encode(nodeid))
+        elif self.path == '/rudder/relay-api/system/info':
+            self.send_response(200)
+            self.send_header('Content-type', 'application/json')
+            self.end_headers()
+            self.wfile.write(b'{"result": "success"}')
         elif self.path == '/stop':
             self.send_response(200)
             self.send_header('Content-type', 'text/plain')
@@ -39,6 +45,19 @@ class PolicyServer(BaseHTTPRequestHandler):
             # TODO also write received parameters
             f.write('OK')
             f.close()
+        elif self.path == '/webhook':
+            length = int(self.headers['Content-Length'])
+            body = json.loads(self.rfile.read(length))
+            f = open('target/tmp/api_test_webhook.json', 'w')
+            headers = {k.lower(): v for k, v in self.headers.items()}
+            json.dump({'headers': headers, 'body': body}, f)
+            f.close()
+            self.send_response(200)
+            self.end_headers()
+        elif self.path == '/webhook/refused':
+            self.send_error(400)
+        elif self.path == '/webhook/unavailable':
+            self.send_error(503)
         else:
             self.send_error(404)
 
@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+mod common;
+
+use std::{
+    fs::{self, copy, create_dir_all, remove_dir_all},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use common::{fake_server_start, fake_server_stop};
+use rudder_relayd::{
+    configuration::{cli::CliConfiguration, main::Configuration},
+    init_logger,
+    metrics::{UPSTREAM_ACTIVE, UPSTREAM_FAILOVERS},
+    output::upstream::Upstreams,
+    start,
+};
+
+const CONFIG_DIR: &str = "target/tmp/failover-config/";
+
+/// Same configuration as the other tests, with an unavailable primary upstream
+fn failover_config() -> CliConfiguration {
+    let _ = remove_dir_all(CONFIG_DIR);
+    create_dir_all(CONFIG_DIR).unwrap();
+    copy(
+        "tests/files/config/logging.conf",
+        Path::new(CONFIG_DIR).join("logging.conf"),
+    )
+    .unwrap();
+    let main = fs::read_to_string("tests/files/config/main.conf")
+        .unwrap()
+        .replace(
+            "directory = \"target/tmp/reporting/\"\noutput = \"database\"",
+            "directory = \"target/tmp/failover-reporting/\"\noutput = \"upstream\"",
+        )
+        .replace("host = \"rudder.example.com\"", "host = \"rudder.invalid\"")
+        .replace("host = \"rudder2.example.com\"", "host = \"localhost\"")
+        .replace(
+            "[output.upstream.health_check]\nfrequency = \"10s\"",
+            "[output.upstream.health_check]\nfrequency = \"1s\"",
+        );
+    fs::write(Path::new(CONFIG_DIR).join("main.conf"), main).unwrap();
+    CliConfiguration::new(CONFIG_DIR, false)
+}
+
+#[test]
+fn it_fails_over_to_next_upstream() {
+    let cfg: Configuration = r#"
+        [general]
+        node_id = "root"
+        https_port = 4443
+        peer_authentication = "cert_pinning"
+
+        [output.upstream]
+        host = "rudder.invalid"
+        password = "password"
+        server_certificate_file = "tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert"
+
+        [[output.upstream.failover]]
+        host = "localhost"
+        server_certificate_file = "tests/files/keys/37817c4d-fbf7-4850-a985-50021f4e8f41.cert"
+    "#
+    .parse()
+    .unwrap();
+    let upstreams = Upstreams::new(&cfg).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let primary = UPSTREAM_ACTIVE.with_label_values(&["https://rudder.invalid:4443/"]);
+    let failover = UPSTREAM_ACTIVE.with_label_values(&["https://localhost:4443/"]);
+    assert_eq!(primary.get(), 1);
+    assert_eq!(failover.get(), 0);
+
+    fake_server_start("37817c4d-fbf7-4850-a985-50021f4e8f41".to_string());
+
+    let response = runtime
+        .block_on(upstreams.send(|client, url| Ok(client.get(format!("{}uuid", url)))))
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(primary.get(), 0);
+    assert_eq!(failover.get(), 1);
+    assert_eq!(UPSTREAM_FAILOVERS.get(), 1);
+
+    // Primary still unavailable
+    runtime.block_on(upstreams.check(Duration::from_secs(1)));
+    assert_eq!(failover.get(), 1);
+
+    // The periodic health check of relayd switches to the failover
+    let cli_cfg = failover_config();
+    thread::spawn(move || {
+        start(cli_cfg, init_logger().unwrap()).unwrap();
+    });
+    assert!(common::start_api().is_ok());
+    let mut retry = 10;
+    while UPSTREAM_FAILOVERS.get() < 2 && retry > 0 {
+        thread::sleep(Duration::from_millis(200));
+        retry -= 1;
+    }
+    assert_eq!(UPSTREAM_FAILOVERS.get(), 2);
+    assert_eq!(failover.get(), 1);
+
+    fake_server_stop();
+}
@@ -0,0 +1,144 @@
+// SPDX-License-Identifier: GPL-3.0-or-later WITH GPL-3.0-linking-source-exception
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+mod common;
+
+use std::{
+    fs::{self, copy, create_dir_all, remove_dir_all},
+    path::Path,
+    thread, time,
+    time::Duration,
+};
+
+use common::{fake_server_start, fake_server_stop};
+use filetime::{set_file_times, FileTime};
+use rudder_relayd::{
+    configuration::{cli::CliConfiguration, main::Configuration},
+    data::RunLog,
+    error::RudderError,
+    init_logger,
+    output::webhook::{client, send_runlogs},
+    start,
+};
+
+const CONFIG_DIR: &str = "target/tmp/webhook-config/";
+const RECEIVED: &str = "target/tmp/api_test_webhook.json";
+
+/// Same configuration as the other tests, with reports sent to the webhook
+fn webhook_config() -> CliConfiguration {
+    let _ = remove_dir_all(CONFIG_DIR);
+    create_dir_all(CONFIG_DIR).unwrap();
+    copy(
+        "tests/files/config/logging.conf",
+        Path::new(CONFIG_DIR).join("logging.conf"),
+    )
+    .unwrap();
+    let main = fs::read_to_string("tests/files/config/main.conf")
+        .unwrap()
+        .replace(
+            "directory = \"target/tmp/reporting/\"\noutput = \"database\"",
+            "directory = \"target/tmp/webhook-reporting/\"\noutput = \"webhook\"",
+        );
+    fs::write(Path::new(CONFIG_DIR).join("main.conf"), main).unwrap();
+    CliConfiguration::new(CONFIG_DIR, false)
+}
+
+/// Waits for the webhook to receive a request
+fn received() -> Option<serde_json::Value> {
+    let mut retry = 20;
+    while retry > 0 {
+        thread::sleep(time::Duration::from_millis(200));
+        retry -= 1;
+
+        if let Ok(content) = fs::read_to_string(RECEIVED) {
+            return Some(serde_json::from_str(&content).unwrap());
+        }
+    }
+    None
+}
+
+#[test]
+fn it_sends_runlogs_to_webhook() {
+    let _ = fs::remove_file(RECEIVED);
+    let _ = remove_dir_all("target/tmp/webhook-reporting");
+    create_dir_all("target/tmp/webhook-reporting/incoming").unwrap();
+    let runlogs = [
+        "2017-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b",
+        "2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b",
+    ];
+    // Old enough to be picked up together by the catchup
+    let old_time = FileTime::from_unix_time(
+        (chrono::Utc::now() - chrono::Duration::minutes(10)).timestamp(),
+        0,
+    );
+    for runlog in runlogs {
+        let file = format!("target/tmp/webhook-reporting/incoming/{}.log", runlog);
+        copy(format!("tests/files/runlogs/{}.signed", runlog), &file).unwrap();
+        set_file_times(&file, old_time, old_time).unwrap();
+    }
+
+    fake_server_start("37817c4d-fbf7-4850-a985-50021f4e8f41".to_string());
+    let cli_cfg = webhook_config();
+    thread::spawn(move || {
+        start(cli_cfg, init_logger().unwrap()).unwrap();
+    });
+    assert!(common::start_api().is_ok());
+
+    // Both runlogs are sent with a single request
+    let received = received().unwrap();
+    assert_eq!(received["headers"]["authorization"], "Bearer TOKEN");
+    assert_eq!(received["headers"]["x-source"], "relay");
+    let body = received["body"].as_array().unwrap();
+    assert_eq!(body.len(), 2);
+    for (sent, name) in body.iter().zip(runlogs) {
+        let runlog = RunLog::new(format!("tests/files/runlogs/{}.log", name)).unwrap();
+        assert_eq!(
+            sent["run_info"]["node_id"],
+            "e745a140-40bc-4b86-b6dc-084488fc906b"
+        );
+        assert_eq!(sent["config_id"], runlog.config_id.unwrap().as_str());
+        assert_eq!(sent["log_type"], "Complete");
+        assert_eq!(
+            sent["reports"].as_array().unwrap().len(),
+            runlog.reports.len()
+        );
+    }
+    thread::sleep(time::Duration::from_millis(200));
+    for runlog in runlogs {
+        assert!(!Path::new(&format!(
+            "target/tmp/webhook-reporting/incoming/{}.log",
+            runlog
+        ))
+        .exists());
+    }
+
+    let mut cfg = Configuration::new("tests/files/config/")
+        .unwrap()
+        .output
+        .webhook;
+    let client = client(&cfg, Duration::from_secs(10)).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let runlog = RunLog::new(
+        "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+    )
+    .unwrap();
+
+    // Permanent error
+    cfg.url = "https://localhost:4443/webhook/refused".to_string();
+    let error = runtime
+        .block_on(send_runlogs(&client, &cfg, &[&runlog]))
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<RudderError>(),
+        Some(RudderError::WebhookRefused(400))
+    ));
+
+    // Transient error
+    cfg.url = "https://localhost:4443/webhook/unavailable".to_string();
+    let error = runtime
+        .block_on(send_runlogs(&client, &cfg, &[&runlog]))
+        .unwrap_err();
+    assert!(error.downcast_ref::<reqwest::Error>().is_some());
+
+    fake_server_stop();
+}
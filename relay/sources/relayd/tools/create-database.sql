@@ -4,6 +4,8 @@ create user rudderreports with encrypted password 'PASSWORD';
 \c rudder
 */
 
+/* Tables can also be created with "rudder-relayd db init", using an account allowed to create them */
+
 \i ../../../webapp/sources/rudder/rudder-core/src/main/resources/reportsSchema.sql
 
 grant usage on sequence serial to rudderreports;
@@ -13,10 +15,21 @@ grant insert on table ruddersysevents to rudderreports;
 grant select on table reportsexecution to rudderreports;
 grant insert on table reportsexecution to rudderreports;
 
-/* only for test databases */
+grant select on table runlogdigests to rudderreports;
+grant insert on table runlogdigests to rudderreports;
 
-grant delete on table ruddersysevents to rudderreports;
-grant truncate on table ruddersysevents to rudderreports;
+/* only needed with "output.database.purge.enabled = true" */
 
+grant delete on table ruddersysevents to rudderreports;
 grant delete on table reportsexecution to rudderreports;
+grant delete on table runlogdigests to rudderreports;
+
+/* with "output.database.purge.archive = true" */
+grant select on table archivedruddersysevents to rudderreports;
+grant insert on table archivedruddersysevents to rudderreports;
+
+/* only for test databases */
+
+grant truncate on table ruddersysevents to rudderreports;
 grant truncate on table reportsexecution to rudderreports;
+grant truncate on table runlogdigests to rudderreports;
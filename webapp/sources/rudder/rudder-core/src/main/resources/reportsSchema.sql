@@ -130,6 +130,21 @@ CREATE INDEX reportsexecution_uncomputedrun_idx on ReportsExecution (compliancec
 
 ALTER TABLE reportsexecution set (autovacuum_vacuum_scale_factor = 0.05);
 
+/*
+ * Runlogs received by relayd, used to detect duplicates (agent retries,
+ * runlogs going through several relays) without querying RudderSysEvents.
+ * Digest is the sha256 of the signed runlog content.
+ */
+CREATE TABLE RunlogDigests (
+  nodeId        text NOT NULL
+, date          timestamp with time zone NOT NULL
+, digest        text NOT NULL
+, insertionDate timestamp with time zone default now()
+, PRIMARY KEY(nodeId, date, digest)
+);
+
+CREATE INDEX runlogdigests_insertiondate_idx ON RunlogDigests (insertionDate);
+
 /*
  *************************************************************************************
  * The following tables store what Rudder expects from agent.